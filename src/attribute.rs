@@ -0,0 +1,146 @@
+use std::collections::BTreeMap;
+
+use nom::{
+    branch::alt,
+    bytes::complete::take_while1,
+    character::complete::digit1,
+    combinator::{map_opt, map_res},
+};
+use phf::phf_map;
+
+use crate::{
+    dwarf::uleb,
+    error::{AsmError, AsmErrorKind, IResult},
+    imm::Expr,
+    program::Program,
+    span::Span,
+};
+
+pub const TAG_STACK_ALIGN: u32 = 4;
+pub const TAG_ARCH: u32 = 5;
+pub const TAG_UNALIGNED_ACCESS: u32 = 6;
+
+/// Tags of the RISC-V attributes by name, also accepted with the
+/// `Tag_RISCV_` prefix.
+static TAGS: phf::Map<&'static str, u32> = phf_map! {
+    "stack_align" => TAG_STACK_ALIGN,
+    "arch" => TAG_ARCH,
+    "unaligned_access" => TAG_UNALIGNED_ACCESS,
+    "priv_spec" => 8,
+    "priv_spec_minor" => 10,
+    "priv_spec_revision" => 12,
+    "atomic_abi" => 14,
+    "x3_reg_usage" => 16,
+};
+
+/// Format version of the attributes section.
+const FORMAT: u8 = b'A';
+/// Attributes applying to the whole file.
+const TAG_FILE: u8 = 1;
+
+#[derive(Debug)]
+pub enum AttrArg {
+    Int(Expr),
+    Str(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttrValue {
+    Int(u64),
+    Str(String),
+}
+
+/// Values of odd tags are strings, the others integers.
+pub fn is_str(tag: u32) -> bool {
+    tag % 2 == 1
+}
+
+pub fn parse_tag(input: Span<'_>) -> IResult<'_, u32> {
+    alt((
+        map_opt(
+            take_while1(|c: char| c.is_ascii_alphanumeric() || c == '_'),
+            |s: Span<'_>| {
+                TAGS.get(s.strip_prefix("Tag_RISCV_").unwrap_or(*s))
+                    .copied()
+            },
+        ),
+        map_res(digit1, |s: Span<'_>| s.parse()),
+    ))(input)
+    .map_err(|e| e.map(|e: AsmError<'_>| e.with_kind(AsmErrorKind::InvalidAttribute)))
+}
+
+impl Program {
+    /// Contents of `.riscv.attributes`: the attributes given by the source
+    /// over the ones of the target, with `arch` describing it.
+    pub fn riscv_attributes(&self, arch: &str) -> Vec<u8> {
+        let mut attrs = BTreeMap::from([
+            (TAG_STACK_ALIGN, AttrValue::Int(16)),
+            (TAG_ARCH, AttrValue::Str(arch.to_string())),
+            (TAG_UNALIGNED_ACCESS, AttrValue::Int(0)),
+        ]);
+        attrs.extend(
+            self.attributes()
+                .iter()
+                .map(|(tag, value)| (*tag, value.clone())),
+        );
+
+        let mut file = vec![TAG_FILE, 0, 0, 0, 0];
+        for (tag, value) in attrs {
+            uleb(&mut file, tag as u64);
+            match value {
+                AttrValue::Int(value) => uleb(&mut file, value),
+                AttrValue::Str(value) => {
+                    file.extend_from_slice(value.as_bytes());
+                    file.push(0);
+                }
+            }
+        }
+        let len = file.len() as u32;
+        file[1..5].copy_from_slice(&len.to_le_bytes());
+
+        let vendor = b"riscv\0";
+        let mut out = vec![FORMAT];
+        out.extend((4 + vendor.len() as u32 + len).to_le_bytes());
+        out.extend(vendor);
+        out.extend(file);
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_tags() {
+        for (src, tag) in [
+            ("arch", TAG_ARCH),
+            ("Tag_RISCV_stack_align", TAG_STACK_ALIGN),
+            ("14", 14),
+        ] {
+            assert_eq!(parse_tag(Span::new(src, true)).unwrap().1, tag, "{src}");
+        }
+        assert!(parse_tag(Span::new("bogus", true)).is_err());
+    }
+
+    #[test]
+    fn attributes_section() {
+        let src = ".attribute stack_align, 8\n.attribute Tag_RISCV_arch, \"rv32imac\"";
+        let program = Program::parse(src).unwrap();
+        let mut expected = vec![b'A', 29, 0, 0, 0];
+        expected.extend(b"riscv\0");
+        expected.extend([TAG_FILE, 19, 0, 0, 0]);
+        expected.extend([4, 8, 5]);
+        expected.extend(b"rv32imac\0");
+        expected.extend([6, 0]);
+        assert_eq!(program.riscv_attributes("rv32i2p1"), expected);
+
+        // Values must have the type of the tag.
+        for src in [".attribute arch, 1", ".attribute stack_align, \"8\""] {
+            assert!(Program::parse(src).is_err(), "{src}");
+        }
+        let error = Program::parse(".attribute x, 1").unwrap_err();
+        assert!(matches!(error.kind, AsmErrorKind::InvalidAttribute));
+    }
+}
@@ -0,0 +1,481 @@
+use crate::{
+    dwarf::{sleb, uleb, Buf, DebugSection},
+    error::{AsmErrorKind, Error},
+    imm::{Expr, Value},
+    program::Program,
+    reg::Reg,
+    reloc::RelocKind,
+};
+
+/// Factor of the offsets of saved registers. Slots are at least a word of
+/// RV32, so the same factor serves RV64.
+const DATA_ALIGN: i64 = -4;
+
+const DW_CFA_ADVANCE_LOC: u8 = 0x40;
+const DW_CFA_OFFSET: u8 = 0x80;
+const DW_CFA_RESTORE: u8 = 0xc0;
+const DW_CFA_NOP: u8 = 0x00;
+const DW_CFA_ADVANCE_LOC1: u8 = 0x02;
+const DW_CFA_ADVANCE_LOC2: u8 = 0x03;
+const DW_CFA_ADVANCE_LOC4: u8 = 0x04;
+const DW_CFA_OFFSET_EXTENDED: u8 = 0x05;
+const DW_CFA_RESTORE_EXTENDED: u8 = 0x06;
+const DW_CFA_UNDEFINED: u8 = 0x07;
+const DW_CFA_SAME_VALUE: u8 = 0x08;
+const DW_CFA_REGISTER: u8 = 0x09;
+const DW_CFA_REMEMBER_STATE: u8 = 0x0a;
+const DW_CFA_RESTORE_STATE: u8 = 0x0b;
+const DW_CFA_DEF_CFA: u8 = 0x0c;
+const DW_CFA_DEF_CFA_REGISTER: u8 = 0x0d;
+const DW_CFA_DEF_CFA_OFFSET: u8 = 0x0e;
+const DW_CFA_OFFSET_EXTENDED_SF: u8 = 0x11;
+
+/// Encoding of the addresses in `.eh_frame`: 32-bit, relative to the field.
+const DW_EH_PE_PCREL_SDATA4: u8 = 0x1b;
+
+/// Call frame instruction of a `.cfi_*` directive. Registers are DWARF
+/// numbers, `x0` to `x31` being 0 to 31.
+#[derive(Debug)]
+pub enum CfiInsn {
+    DefCfa(u32, Expr),
+    DefCfaRegister(u32),
+    DefCfaOffset(Expr),
+    /// `.cfi_adjust_cfa_offset delta`, relative to the current offset.
+    AdjustCfaOffset(Expr),
+    /// `.cfi_offset reg, offset`, the register is saved at the offset from
+    /// the CFA.
+    Offset(u32, Expr),
+    /// `.cfi_rel_offset reg, offset`, the offset is from the CFA register.
+    RelOffset(u32, Expr),
+    Restore(u32),
+    Undefined(u32),
+    SameValue(u32),
+    /// `.cfi_register reg, other`, the register is saved in the other one.
+    Register(u32, u32),
+    RememberState,
+    RestoreState,
+    Escape(Vec<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CfiSections {
+    pub eh_frame: bool,
+    pub debug_frame: bool,
+}
+
+impl Default for CfiSections {
+    fn default() -> Self {
+        Self {
+            eh_frame: true,
+            debug_frame: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Frame {
+    pub section: usize,
+    pub start: u32,
+    pub end: u32,
+    /// The frame doesn't start with the CFA at `sp` (`.cfi_startproc
+    /// simple`).
+    pub simple: bool,
+    /// Encoded instructions along with the offset in the section from
+    /// which they apply.
+    pub insns: Vec<(u32, Vec<u8>)>,
+    pub line: usize,
+    /// Offset of the CFA from its register, for relative directives.
+    cfa_offset: i64,
+    /// Offsets saved by `.cfi_remember_state`.
+    saved: Vec<i64>,
+}
+
+#[derive(Debug, Default)]
+pub struct FrameInfo {
+    pub sections: CfiSections,
+    pub frames: Vec<Frame>,
+    pub(crate) open: Option<Frame>,
+}
+
+impl Frame {
+    pub fn new(section: usize, start: u32, simple: bool, line: usize) -> Self {
+        Self {
+            section,
+            start,
+            end: start,
+            simple,
+            insns: Vec::new(),
+            line,
+            cfa_offset: 0,
+            saved: Vec::new(),
+        }
+    }
+
+    /// Adds the instruction applying from `offset`. `eval` evaluates an
+    /// absolute expression, `error` reports one at the expression or the
+    /// directive.
+    pub fn push(
+        &mut self,
+        offset: u32,
+        insn: CfiInsn,
+        eval: impl Fn(&Expr) -> Result<i64, Error>,
+        error: impl Fn(Option<&Expr>, AsmErrorKind) -> Error,
+    ) -> Result<(), Error> {
+        // The CFA is above its register, saved registers are in slots.
+        let cfa_offset = |expr: &Expr, value: i64| match value {
+            0.. => Ok(value),
+            _ => Err(error(Some(expr), AsmErrorKind::ImmOutOfRange)),
+        };
+        let slot = |expr: &Expr, value: i64| match value % DATA_ALIGN {
+            0 => Ok(value),
+            _ => Err(error(
+                Some(expr),
+                AsmErrorKind::CfiOffset(-DATA_ALIGN as u32),
+            )),
+        };
+
+        let mut buf = Vec::new();
+        let reg_op = |buf: &mut Vec<u8>, op: u8, reg: u32| {
+            buf.push(op);
+            uleb(buf, reg as u64);
+        };
+
+        match insn {
+            CfiInsn::DefCfa(reg, cfa) => {
+                self.cfa_offset = cfa_offset(&cfa, eval(&cfa)?)?;
+                reg_op(&mut buf, DW_CFA_DEF_CFA, reg);
+                uleb(&mut buf, self.cfa_offset as u64);
+            }
+            CfiInsn::DefCfaRegister(reg) => {
+                reg_op(&mut buf, DW_CFA_DEF_CFA_REGISTER, reg)
+            }
+            CfiInsn::DefCfaOffset(cfa) => {
+                self.cfa_offset = cfa_offset(&cfa, eval(&cfa)?)?;
+                buf.push(DW_CFA_DEF_CFA_OFFSET);
+                uleb(&mut buf, self.cfa_offset as u64);
+            }
+            CfiInsn::AdjustCfaOffset(delta) => {
+                self.cfa_offset = cfa_offset(&delta, self.cfa_offset + eval(&delta)?)?;
+                buf.push(DW_CFA_DEF_CFA_OFFSET);
+                uleb(&mut buf, self.cfa_offset as u64);
+            }
+            CfiInsn::Offset(reg, offset) => {
+                save(&mut buf, reg, slot(&offset, eval(&offset)?)?)
+            }
+            CfiInsn::RelOffset(reg, offset) => {
+                let value = eval(&offset)? - self.cfa_offset;
+                save(&mut buf, reg, slot(&offset, value)?);
+            }
+            CfiInsn::Restore(reg) if reg < 64 => buf.push(DW_CFA_RESTORE | reg as u8),
+            CfiInsn::Restore(reg) => reg_op(&mut buf, DW_CFA_RESTORE_EXTENDED, reg),
+            CfiInsn::Undefined(reg) => reg_op(&mut buf, DW_CFA_UNDEFINED, reg),
+            CfiInsn::SameValue(reg) => reg_op(&mut buf, DW_CFA_SAME_VALUE, reg),
+            CfiInsn::Register(reg, other) => {
+                reg_op(&mut buf, DW_CFA_REGISTER, reg);
+                uleb(&mut buf, other as u64);
+            }
+            CfiInsn::RememberState => {
+                self.saved.push(self.cfa_offset);
+                buf.push(DW_CFA_REMEMBER_STATE);
+            }
+            CfiInsn::RestoreState => {
+                self.cfa_offset = self
+                    .saved
+                    .pop()
+                    .ok_or_else(|| error(None, AsmErrorKind::Misplaced))?;
+                buf.push(DW_CFA_RESTORE_STATE);
+            }
+            CfiInsn::Escape(bytes) => {
+                for byte in &bytes {
+                    buf.push(eval(byte)? as u8);
+                }
+            }
+        }
+
+        self.insns.push((offset, buf));
+
+        Ok(())
+    }
+}
+
+fn save(buf: &mut Vec<u8>, reg: u32, offset: i64) {
+    let factored = offset / DATA_ALIGN;
+    if factored < 0 {
+        buf.push(DW_CFA_OFFSET_EXTENDED_SF);
+        uleb(buf, reg as u64);
+        sleb(buf, factored);
+    } else {
+        if reg < 64 {
+            buf.push(DW_CFA_OFFSET | reg as u8);
+        } else {
+            buf.push(DW_CFA_OFFSET_EXTENDED);
+            uleb(buf, reg as u64);
+        }
+        uleb(buf, factored as u64);
+    }
+}
+
+/// Appends instructions moving the location from `from` to `to`. With
+/// `relaxed`, the delta is left to a pair of relocations, as the linker may
+/// shrink the code in between.
+fn advance(buf: &mut Buf, from: Value, to: Value, relaxed: bool) {
+    let delta = (to.offset - from.offset) as u32;
+    let field = if relaxed { 0 } else { delta };
+    let relocs = |buf: &mut Buf, set: RelocKind, sub: RelocKind| {
+        if relaxed {
+            buf.reloc(set, to);
+            buf.reloc(sub, from);
+        }
+    };
+
+    match delta {
+        0 => {}
+        1..0x40 => {
+            relocs(buf, RelocKind::Set6, RelocKind::Sub6);
+            buf.u8(DW_CFA_ADVANCE_LOC | field as u8);
+        }
+        0x40..0x100 => {
+            buf.u8(DW_CFA_ADVANCE_LOC1);
+            relocs(buf, RelocKind::Set(1), RelocKind::Sub(1));
+            buf.u8(field as u8);
+        }
+        0x100..0x10000 => {
+            buf.u8(DW_CFA_ADVANCE_LOC2);
+            relocs(buf, RelocKind::Set(2), RelocKind::Sub(2));
+            buf.u16(field as u16);
+        }
+        _ => {
+            buf.u8(DW_CFA_ADVANCE_LOC4);
+            relocs(buf, RelocKind::Set(4), RelocKind::Sub(4));
+            buf.u32(field);
+        }
+    }
+}
+
+/// Ends an entry started at `pos`, padded to the address size.
+fn end_entry(buf: &mut Buf, pos: usize) {
+    while !(buf.data.len() - pos).is_multiple_of(buf.addr_size as usize) {
+        buf.u8(DW_CFA_NOP);
+    }
+    buf.end(pos);
+}
+
+impl Program {
+    /// `.eh_frame` and `.debug_frame` sections describing the frames, as
+    /// asked for. Returns nothing without frames.
+    pub(crate) fn frame_sections(
+        &self,
+        addr_size: u8,
+        first: usize,
+        relocatable: bool,
+        sections: CfiSections,
+    ) -> Vec<DebugSection> {
+        let frames = &self.frame_info().frames;
+        if frames.is_empty() {
+            return Vec::new();
+        }
+
+        let mut out = Vec::new();
+        if sections.eh_frame {
+            out.push(DebugSection {
+                alloc: true,
+                ..self.frame_section(addr_size, first, relocatable, true)
+            });
+        }
+        if sections.debug_frame {
+            out.push(self.frame_section(
+                addr_size,
+                first + out.len(),
+                relocatable,
+                false,
+            ));
+        }
+
+        out
+    }
+
+    /// Common information entries (one for each kind of frame) followed by
+    /// the frame description entries, as `.eh_frame` or `.debug_frame` at
+    /// index `idx`.
+    fn frame_section(
+        &self,
+        addr_size: u8,
+        idx: usize,
+        relocatable: bool,
+        eh_frame: bool,
+    ) -> DebugSection {
+        let mut buf = Buf::new(addr_size, relocatable);
+        let mut cies = [None; 2];
+
+        for frame in &self.frame_info().frames {
+            let cie = *cies[frame.simple as usize].get_or_insert_with(|| {
+                let pos = buf.start();
+                buf.u32(if eh_frame { 0 } else { u32::MAX });
+                // Version and augmentation.
+                buf.u8(1);
+                buf.str(if eh_frame { "zR" } else { "" });
+                buf.uleb(1);
+                buf.sleb(DATA_ALIGN);
+                buf.uleb(Reg::RA.idx() as u64);
+                if eh_frame {
+                    buf.uleb(1);
+                    buf.u8(DW_EH_PE_PCREL_SDATA4);
+                }
+                // Procedures start with the CFA at `sp`.
+                if !frame.simple {
+                    buf.u8(DW_CFA_DEF_CFA);
+                    buf.uleb(2);
+                    buf.uleb(0);
+                }
+                end_entry(&mut buf, pos);
+                pos as u32
+            });
+
+            let pos = buf.start();
+            let start = Value::rel(frame.section, frame.start);
+            let end = Value::rel(frame.section, frame.end);
+            let relaxed = relocatable && self.sections()[frame.section].relaxed;
+            if eh_frame {
+                buf.u32(buf.data.len() as u32 - cie);
+                buf.reloc(RelocKind::Pcrel32, start);
+                buf.u32(0);
+                buf.diff(4, start, end, relaxed);
+                buf.uleb(0);
+            } else {
+                buf.sec_offset(idx, cie);
+                buf.addr(start, self.addr(start) as u64);
+                buf.diff(addr_size, start, end, relaxed);
+            }
+
+            let mut offset = start;
+            for (at, insn) in &frame.insns {
+                let at = Value::rel(frame.section, *at);
+                advance(&mut buf, offset, at, relaxed);
+                buf.data.extend(insn);
+                offset = at;
+            }
+            end_entry(&mut buf, pos);
+        }
+
+        buf.section(if eh_frame {
+            ".eh_frame"
+        } else {
+            ".debug_frame"
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// CIE of procedures, the CFA being at `sp`.
+    const CIE: [u8; 20] = [
+        0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, b'z', b'R', 0x00, 0x01,
+        0x7c, 0x01, 0x01, 0x1b, 0x0c, 0x02, 0x00,
+    ];
+
+    fn eh_frame(src: &str) -> Vec<u8> {
+        let program = Program::parse(src).unwrap();
+        let frames = CfiSections {
+            eh_frame: true,
+            debug_frame: false,
+        };
+        let sections = program.frame_sections(4, 1, true, frames);
+        assert_eq!(sections[0].name, ".eh_frame");
+
+        sections[0].data.clone()
+    }
+
+    #[test]
+    fn offsets() {
+        let src = "
+f:
+  .cfi_startproc
+  addi sp, sp, -16
+  .cfi_def_cfa_offset 16
+  .cfi_offset ra, -4
+  addi a0, a0, 1
+  .cfi_restore ra
+  addi sp, sp, 16
+  .cfi_def_cfa_offset 0
+  jalr zero, ra, 0
+  .cfi_endproc
+";
+        let fde = [
+            0x18, 0x00, 0x00, 0x00, 0x18, 0x00, 0x00, 0x00, // Length and CIE.
+            0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, // Start and size.
+            0x00, // Augmentation data.
+            0x44, 0x0e, 0x10, 0x81, 0x01, // 4: CFA sp+16, ra at CFA-4.
+            0x44, 0xc1, // 8: ra restored.
+            0x44, 0x0e, 0x00, // 12: CFA sp.
+            0x00, // Padding.
+        ];
+        assert_eq!(eh_frame(src), [&CIE[..], &fde].concat());
+    }
+
+    #[test]
+    fn states() {
+        let src = "
+f:
+  .cfi_startproc
+  addi sp, sp, -16
+  .cfi_adjust_cfa_offset 16
+  .cfi_rel_offset s0, 8
+  .cfi_remember_state
+  addi s0, sp, 16
+  .cfi_def_cfa s0, 0
+  .cfi_restore_state
+  jalr zero, ra, 0
+  .cfi_endproc
+";
+        let fde = [
+            0x18, 0x00, 0x00, 0x00, 0x18, 0x00, 0x00, 0x00, // Length and CIE.
+            0x00, 0x00, 0x00, 0x00, 0x0c, 0x00, 0x00, 0x00, // Start and size.
+            0x00, // Augmentation data.
+            0x44, 0x0e, 0x10, 0x88, 0x02,
+            0x0a, // 4: CFA sp+16, s0 at CFA-8, remembered.
+            0x44, 0x0c, 0x08, 0x00, 0x0b, // 8: CFA s0, restored.
+        ];
+        assert_eq!(eh_frame(src), [&CIE[..], &fde].concat());
+    }
+
+    #[test]
+    fn relaxed() {
+        let src = "
+.option relax
+  .cfi_startproc
+  addi sp, sp, -16
+  .cfi_def_cfa_offset 16
+  jalr zero, ra, 0
+  .cfi_endproc
+";
+        let program = Program::parse(src).unwrap();
+        let sections = program.frame_sections(4, 1, true, CfiSections::default());
+        let eh_frame = &sections[0];
+
+        let fde = [
+            0x10, 0x00, 0x00, 0x00, 0x18, 0x00, 0x00, 0x00, // Length and CIE.
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // Start and size.
+            0x00, // Augmentation data.
+            0x40, 0x0e, 0x10, // 4: CFA sp+16.
+        ];
+        assert_eq!(eh_frame.data, [&CIE[..], &fde].concat());
+
+        let relocs: Vec<_> = eh_frame
+            .relocs
+            .iter()
+            .map(|reloc| (reloc.offset, reloc.kind, reloc.target.value))
+            .collect();
+        assert_eq!(
+            relocs,
+            [
+                (28, RelocKind::Pcrel32, Value::rel(0, 0)),
+                (32, RelocKind::Add(4), Value::rel(0, 8)),
+                (32, RelocKind::Sub(4), Value::rel(0, 0)),
+                (37, RelocKind::Set6, Value::rel(0, 4)),
+                (37, RelocKind::Sub6, Value::rel(0, 0)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use crate::{
+    error::{AsmErrorKind, Error},
+    imm::{Expr, Target, Value},
+    program::{Program, Site},
+    reloc::{Reloc, RelocKind},
+};
+
+#[derive(Debug)]
+pub enum Data {
+    /// Integers of the given size in bytes, which may refer to labels.
+    Int(u32, Vec<Expr>),
+    Bytes(Vec<u8>),
+    /// `count` copies of a `size` bytes constant.
+    Fill {
+        count: u32,
+        size: u32,
+        value: i64,
+    },
+    /// Alignment padding of code, filled with `nop` (or `c.nop`). With
+    /// `relax`, it is for the worst case and marked for the linker to trim.
+    Nops {
+        size: u32,
+        rvc: bool,
+        relax: bool,
+    },
+}
+
+const NOP: u32 = 0x0000_0013;
+const C_NOP: u16 = 0x0001;
+
+impl Data {
+    pub fn size(&self) -> u64 {
+        match self {
+            Self::Int(size, values) => *size as u64 * values.len() as u64,
+            Self::Bytes(bytes) => bytes.len() as u64,
+            Self::Fill { count, size, .. } => *count as u64 * *size as u64,
+            Self::Nops { size, .. } => *size as u64,
+        }
+    }
+
+    /// Zero filled regions are the only data allowed in NoBits sections.
+    pub fn is_zero(&self) -> bool {
+        matches!(self, Self::Fill { value: 0, .. })
+    }
+
+    /// Appends the little-endian encoding of the data located at `site`.
+    /// With `relocs`, references to addresses are recorded as relocations.
+    pub fn emit(
+        &self,
+        program: &Program,
+        site: Site,
+        buf: &mut Vec<u8>,
+        mut relocs: Option<&mut Vec<Reloc>>,
+    ) -> Result<(), Error> {
+        match self {
+            Self::Int(size, values) => {
+                for (idx, expr) in values.iter().enumerate() {
+                    let here = Value {
+                        offset: site.here.offset + (idx as i64) * (*size as i64),
+                        ..site.here
+                    };
+                    let site = Site { here, ..site };
+                    if let Some(relocs) = relocs.as_deref_mut() {
+                        if let Some((lhs, rhs)) =
+                            expr.imm.relaxed_difference(program, site)
+                        {
+                            for (kind, value) in [
+                                (RelocKind::Add(*size as u8), lhs),
+                                (RelocKind::Sub(*size as u8), rhs),
+                            ] {
+                                relocs.push(Reloc {
+                                    offset: here.offset as u32,
+                                    kind,
+                                    target: Target { sym: None, value },
+                                });
+                            }
+                            buf.resize(buf.len() + *size as usize, 0);
+                            continue;
+                        }
+
+                        let target = expr.imm.target(program, site)?;
+                        if !target.is_abs() {
+                            let kind = match size {
+                                4 => RelocKind::Abs32,
+                                8 => RelocKind::Abs64,
+                                _ => {
+                                    return Err(program.error(
+                                        site.line,
+                                        &expr.offset,
+                                        AsmErrorKind::NotAbsolute,
+                                    ))
+                                }
+                            };
+                            relocs.push(Reloc {
+                                offset: here.offset as u32,
+                                kind,
+                                target,
+                            });
+                            buf.resize(buf.len() + *size as usize, 0);
+                            continue;
+                        }
+                    }
+
+                    let value = expr.imm.resolve(program, site)?;
+                    if !fits(value, *size) {
+                        return Err(program.error(
+                            site.line,
+                            &expr.offset,
+                            AsmErrorKind::ImmOutOfRange,
+                        ));
+                    }
+
+                    buf.extend_from_slice(&value.to_le_bytes()[..*size as usize]);
+                }
+            }
+            Self::Bytes(bytes) => buf.extend_from_slice(bytes),
+            Self::Fill { count, size, value } => {
+                let value = &value.to_le_bytes()[..*size as usize];
+                for _ in 0..*count {
+                    buf.extend_from_slice(value);
+                }
+            }
+            Self::Nops { size, rvc, relax } => {
+                if let (Some(relocs), true) = (relocs, *relax) {
+                    relocs.push(Reloc {
+                        offset: site.here.offset as u32,
+                        kind: RelocKind::Align,
+                        target: Target {
+                            sym: None,
+                            value: Value::abs(*size as i64),
+                        },
+                    });
+                }
+                push_nops(buf, site.here.offset, *size, *rvc)
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Appends `size` bytes of `nop`s (or `c.nop`s) to code at `offset`. Bytes
+/// before the first instruction boundary can't hold a nop and are zero.
+pub(crate) fn push_nops(buf: &mut Vec<u8>, mut offset: i64, size: u32, rvc: bool) {
+    let parcel = if rvc { 2 } else { 4 };
+    let end = offset + size as i64;
+
+    while offset < end && offset % parcel != 0 {
+        buf.push(0);
+        offset += 1;
+    }
+    if offset % 4 != 0 && end - offset >= 2 {
+        buf.extend(C_NOP.to_le_bytes());
+        offset += 2;
+    }
+    while end - offset >= 4 {
+        buf.extend(NOP.to_le_bytes());
+        offset += 4;
+    }
+    buf.resize(buf.len() + (end - offset) as usize, 0);
+}
+
+/// Checks that `value` fits into `size` bytes, either as signed or unsigned.
+pub fn fits(value: i64, size: u32) -> bool {
+    let bits = size * 8;
+    bits >= 64 || (-(1 << (bits - 1))..(1 << bits)).contains(&value)
+}
@@ -0,0 +1,740 @@
+use std::path::Path;
+
+use literify::literify;
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, take_while1, take_while_m_n},
+    character::complete::{char, digit1, none_of, one_of, space0, space1},
+    combinator::{cut, map, map_opt, map_res, opt, recognize, value, verify},
+    multi::{fold_many0, many0, separated_list1},
+    sequence::{delimited, pair, preceded, tuple},
+};
+use phf::phf_map;
+
+use crate::{
+    attribute::{is_str, parse_tag, AttrArg},
+    cfi::{CfiInsn, CfiSections},
+    dwarf::Loc,
+    error::{AsmError, AsmErrorKind, IResult},
+    imm::{parse_sym, Expr, Imm},
+    macros::{parse_name, Param, ParamKind},
+    option::AsmOption,
+    reg::Reg,
+    section::SectionFlags,
+    span::{Offset, Span},
+    symbol::{Binding, SymbolKind, Visibility},
+};
+
+#[derive(Debug)]
+pub enum Directive {
+    Section(String, SectionFlags),
+    Int(u32, Vec<Expr>),
+    /// Bytes of string literals, including NULs for `.string`.
+    Ascii(Vec<u8>),
+    Zero(Expr),
+    /// `.space count[, fill]`
+    Space(Expr, Option<Expr>),
+    /// `.fill repeat[, size[, value]]`
+    Fill(Expr, Option<Expr>, Option<Expr>),
+    /// `.balign align[, [fill][, max]]` and the power of two variants.
+    Align(AlignKind, Expr, Option<Expr>, Option<Expr>),
+    /// `.org offset[, fill]`, the offset being relative to the start of the
+    /// current section.
+    Org(Expr, Option<Expr>),
+    Include(String),
+    /// `.incbin "file"[, skip[, count]]`, emits the raw file contents.
+    Incbin(String, Option<Expr>, Option<Expr>),
+    Macro(String, Vec<Param>),
+    Endm,
+    Exitm,
+    Rept(Expr),
+    /// `.irp param, values` or `.irpc param, chars`, the body follows up
+    /// to `.endr`.
+    Irp(String, Vec<String>),
+    Endr,
+    /// `.if expr`, `.ifdef sym` or `.ifndef sym`.
+    If(Condition),
+    ElseIf(Condition),
+    Else,
+    Endif,
+    /// `.globl`, `.local` and `.weak`.
+    Bind(Binding, Vec<Offset>),
+    /// `.hidden`, `.internal` and `.protected`.
+    Visibility(Visibility, Vec<Offset>),
+    Type(Offset, SymbolKind),
+    Size(Offset, Expr),
+    /// `.set sym, expr` or `.equ sym, expr`, like `sym = expr`.
+    Set(Offset, Imm),
+    /// `.file "name"` names the source, `.file num ["dir"] "name"` enters
+    /// a file of the line table.
+    File(Option<u32>, String),
+    Loc(Loc),
+    CfiStartProc(bool),
+    CfiEndProc,
+    /// `.cfi_sections` naming `.eh_frame` and/or `.debug_frame`.
+    CfiSections(CfiSections),
+    /// Other `.cfi_*` directives, describing the frame from here on.
+    Cfi(CfiInsn),
+    Attribute(u32, AttrArg),
+    Option(AsmOption),
+}
+
+#[derive(Debug)]
+pub enum Condition {
+    Expr(Expr),
+    /// Symbol is defined (or not, when `false`).
+    Defined(Offset, bool),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum LocOption {
+    IsStmt(bool),
+    PrologueEnd,
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlignKind {
+    Pow2,
+    Bytes,
+}
+
+/// Parses a string literal with C-like escapes into bytes.
+pub fn parse_string(input: Span<'_>) -> IResult<'_, Vec<u8>> {
+    delimited(
+        char('"'),
+        fold_many0(
+            alt((
+                preceded(char('\\'), cut(parse_escape)),
+                map(none_of("\\\"\n"), |c| {
+                    let mut buf = [0; 4];
+                    c.encode_utf8(&mut buf).as_bytes().to_vec()
+                }),
+            )),
+            Vec::new,
+            |mut bytes, c| {
+                bytes.extend(c);
+                bytes
+            },
+        ),
+        cut(char('"')),
+    )(input)
+    .map_err(|e| e.map(|e: AsmError<'_>| e.with_kind(AsmErrorKind::InvalidString)))
+}
+
+fn parse_escape(input: Span<'_>) -> IResult<'_, Vec<u8>> {
+    let byte = |c: char| move |input| value(vec![c as u8], char(c))(input);
+    let radix = |radix: u32, len: usize| {
+        map_res(
+            take_while_m_n(1, len, move |c: char| c.is_digit(radix)),
+            move |s: Span<'_>| u32::from_str_radix(*s, radix).map(|c| vec![c as u8]),
+        )
+    };
+
+    alt((
+        value(vec![b'\n'], char('n')),
+        value(vec![b'\t'], char('t')),
+        value(vec![b'\r'], char('r')),
+        value(vec![0x07], char('a')),
+        value(vec![0x08], char('b')),
+        value(vec![0x0c], char('f')),
+        value(vec![0x0b], char('v')),
+        byte('\\'),
+        byte('"'),
+        byte('\''),
+        preceded(one_of("xX"), radix(16, 2)),
+        radix(8, 3),
+    ))(input)
+}
+
+fn parse_path(input: Span<'_>) -> IResult<'_, String> {
+    map_res(parse_string, String::from_utf8)(input)
+}
+
+fn parse_num(input: Span<'_>) -> IResult<'_, u32> {
+    map_res(digit1, |s: Span<'_>| s.parse())(input)
+}
+
+/// Register name or DWARF register number.
+fn parse_dwarf_reg(input: Span<'_>) -> IResult<'_, u32> {
+    alt((map(Reg::parse, |reg| reg.idx()), parse_num))(input)
+}
+
+fn sep(input: Span<'_>) -> IResult<'_, char> {
+    delimited(space0, char(','), space0)(input)
+}
+
+impl Directive {
+    pub fn parse(input: Span<'_>) -> IResult<'_, Self> {
+        let (input, kind) = preceded(char('.'), cut(DirectiveKind::parse))(input)?;
+        let (input, this) = cut(|input| kind.parse_args(input))(input)?;
+        let (input, _) = space0(input)?;
+
+        Ok((input, this))
+    }
+
+    fn section(name: &str) -> Self {
+        Self::Section(name.to_string(), SectionFlags::for_name(name))
+    }
+
+    fn parse_int(size: u32) -> impl Fn(Span<'_>) -> IResult<'_, Self> {
+        move |input| {
+            map(
+                preceded(space1, separated_list1(sep, Expr::parse)),
+                |values| Self::Int(size, values),
+            )(input)
+        }
+    }
+
+    fn parse_ascii(nul: bool) -> impl Fn(Span<'_>) -> IResult<'_, Self> {
+        move |input| {
+            map(
+                preceded(space1, separated_list1(sep, parse_string)),
+                |strs| {
+                    Self::Ascii(
+                        strs.into_iter()
+                            .flat_map(|mut s| {
+                                if nul {
+                                    s.push(0);
+                                }
+                                s
+                            })
+                            .collect(),
+                    )
+                },
+            )(input)
+        }
+    }
+
+    fn parse_zero(input: Span<'_>) -> IResult<'_, Self> {
+        map(preceded(space1, Expr::parse), Self::Zero)(input)
+    }
+
+    fn parse_space(input: Span<'_>) -> IResult<'_, Self> {
+        map(
+            pair(
+                preceded(space1, Expr::parse),
+                opt(preceded(sep, Expr::parse)),
+            ),
+            |(count, fill)| Self::Space(count, fill),
+        )(input)
+    }
+
+    fn parse_org(input: Span<'_>) -> IResult<'_, Self> {
+        map(
+            pair(
+                preceded(space1, Expr::parse),
+                opt(preceded(sep, Expr::parse)),
+            ),
+            |(offset, fill)| Self::Org(offset, fill),
+        )(input)
+    }
+
+    fn parse_fill(input: Span<'_>) -> IResult<'_, Self> {
+        map(
+            pair(
+                preceded(space1, Expr::parse),
+                opt(pair(
+                    preceded(sep, Expr::parse),
+                    opt(preceded(sep, Expr::parse)),
+                )),
+            ),
+            |(repeat, rest)| match rest {
+                Some((size, value)) => Self::Fill(repeat, Some(size), value),
+                None => Self::Fill(repeat, None, None),
+            },
+        )(input)
+    }
+
+    fn parse_align(kind: AlignKind) -> impl Fn(Span<'_>) -> IResult<'_, Self> {
+        move |input| {
+            map(
+                pair(
+                    preceded(space1, Expr::parse),
+                    opt(preceded(
+                        sep,
+                        pair(opt(Expr::parse), opt(preceded(sep, Expr::parse))),
+                    )),
+                ),
+                |(align, rest)| {
+                    let (fill, max) = rest.unwrap_or_default();
+                    Self::Align(kind, align, fill, max)
+                },
+            )(input)
+        }
+    }
+
+    fn parse_include(input: Span<'_>) -> IResult<'_, Self> {
+        map(preceded(space1, parse_path), Self::Include)(input)
+    }
+
+    fn parse_incbin(input: Span<'_>) -> IResult<'_, Self> {
+        map(
+            pair(
+                preceded(space1, parse_path),
+                opt(pair(
+                    preceded(sep, Expr::parse),
+                    opt(preceded(sep, Expr::parse)),
+                )),
+            ),
+            |(path, rest)| match rest {
+                Some((skip, count)) => Self::Incbin(path, Some(skip), count),
+                None => Self::Incbin(path, None, None),
+            },
+        )(input)
+    }
+
+    fn parse_macro(input: Span<'_>) -> IResult<'_, Self> {
+        map(
+            pair(
+                preceded(space1, parse_name),
+                verify(
+                    many0(preceded(alt((sep, map(space1, |_| ' '))), Param::parse)),
+                    |params: &Vec<Param>| {
+                        params
+                            .iter()
+                            .rev()
+                            .skip(1)
+                            .all(|param| param.kind != ParamKind::Vararg)
+                    },
+                ),
+            ),
+            |(name, params)| Self::Macro(name.to_string(), params),
+        )(input)
+    }
+
+    fn parse_irp(input: Span<'_>) -> IResult<'_, Self> {
+        map(
+            pair(
+                preceded(space1, parse_sym),
+                many0(preceded(
+                    alt((sep, map(space1, |_| ' '))),
+                    alt((
+                        recognize(parse_string),
+                        take_while1(|c: char| {
+                            !matches!(c, ',' | '#') && !c.is_whitespace()
+                        }),
+                    )),
+                )),
+            ),
+            |(name, values)| {
+                Self::Irp(
+                    name.to_string(),
+                    values.into_iter().map(|value| value.to_string()).collect(),
+                )
+            },
+        )(input)
+    }
+
+    fn parse_irpc(input: Span<'_>) -> IResult<'_, Self> {
+        map(
+            pair(
+                preceded(space1, parse_sym),
+                opt(preceded(
+                    sep,
+                    take_while1(|c: char| c != '#' && !c.is_whitespace()),
+                )),
+            ),
+            |(name, chars)| {
+                Self::Irp(
+                    name.to_string(),
+                    chars
+                        .map(|chars| chars.chars().map(String::from).collect())
+                        .unwrap_or_default(),
+                )
+            },
+        )(input)
+    }
+
+    fn parse_if(input: Span<'_>) -> IResult<'_, Condition> {
+        map(preceded(space1, Expr::parse), Condition::Expr)(input)
+    }
+
+    fn parse_ifdef(defined: bool) -> impl Fn(Span<'_>) -> IResult<'_, Self> {
+        move |input| {
+            map(preceded(space1, parse_sym), |sym| {
+                Self::If(Condition::Defined(sym.into(), defined))
+            })(input)
+        }
+    }
+
+    fn parse_syms(input: Span<'_>) -> IResult<'_, Vec<Offset>> {
+        preceded(
+            space1,
+            separated_list1(sep, map(parse_sym, |sym| sym.into())),
+        )(input)
+    }
+
+    fn parse_bind(binding: Binding) -> impl Fn(Span<'_>) -> IResult<'_, Self> {
+        move |input| map(Self::parse_syms, |syms| Self::Bind(binding, syms))(input)
+    }
+
+    fn parse_visibility(
+        visibility: Visibility,
+    ) -> impl Fn(Span<'_>) -> IResult<'_, Self> {
+        move |input| {
+            map(Self::parse_syms, |syms| Self::Visibility(visibility, syms))(input)
+        }
+    }
+
+    fn parse_type(input: Span<'_>) -> IResult<'_, Self> {
+        map(
+            pair(
+                preceded(space1, parse_sym),
+                preceded(sep, cut(SymbolKind::parse)),
+            ),
+            |(sym, kind)| Self::Type(sym.into(), kind),
+        )(input)
+    }
+
+    fn parse_size(input: Span<'_>) -> IResult<'_, Self> {
+        map(
+            pair(preceded(space1, parse_sym), preceded(sep, Expr::parse)),
+            |(sym, size)| Self::Size(sym.into(), size),
+        )(input)
+    }
+
+    fn parse_set(input: Span<'_>) -> IResult<'_, Self> {
+        map(
+            pair(preceded(space1, parse_sym), preceded(sep, cut(Imm::parse))),
+            |(sym, imm)| Self::Set(sym.into(), imm),
+        )(input)
+    }
+
+    fn parse_file(input: Span<'_>) -> IResult<'_, Self> {
+        let (input, num) = opt(preceded(space1, parse_num))(input)?;
+        let Some(num) = num else {
+            return map(preceded(space1, parse_path), |name| Self::File(None, name))(
+                input,
+            );
+        };
+
+        // The MD5 sum of DWARF 5 is of no use for the line table.
+        map(
+            tuple((
+                preceded(space1, parse_path),
+                opt(preceded(space1, parse_path)),
+                opt(tuple((
+                    space1,
+                    tag("md5"),
+                    space1,
+                    take_while1(|c: char| c.is_ascii_alphanumeric()),
+                ))),
+            )),
+            move |(dir, name, _)| {
+                let path = match name {
+                    Some(name) => Path::new(&dir).join(name).display().to_string(),
+                    None => dir,
+                };
+                Self::File(Some(num), path)
+            },
+        )(input)
+    }
+
+    fn parse_loc(input: Span<'_>) -> IResult<'_, Self> {
+        let (input, (file, line, col)) = tuple((
+            preceded(space1, parse_num),
+            preceded(space1, parse_num),
+            opt(preceded(space1, parse_num)),
+        ))(input)?;
+
+        let loc = Loc {
+            file,
+            line,
+            col: col.unwrap_or_default(),
+            is_stmt: true,
+            prologue_end: false,
+        };
+
+        // Options other than `is_stmt` and `prologue_end` are accepted but
+        // don't show in the line table.
+        fold_many0(
+            preceded(
+                space1,
+                alt((
+                    map(preceded(pair(tag("is_stmt"), space1), parse_num), |value| {
+                        LocOption::IsStmt(value != 0)
+                    }),
+                    value(LocOption::PrologueEnd, tag("prologue_end")),
+                    value(
+                        LocOption::Other,
+                        alt((tag("basic_block"), tag("epilogue_begin"))),
+                    ),
+                    value(
+                        LocOption::Other,
+                        tuple((
+                            alt((tag("isa"), tag("discriminator"), tag("view"))),
+                            space1,
+                            take_while1(|c: char| !c.is_whitespace() && c != '#'),
+                        )),
+                    ),
+                )),
+            ),
+            move || loc,
+            |mut loc, option| {
+                match option {
+                    LocOption::IsStmt(is_stmt) => loc.is_stmt = is_stmt,
+                    LocOption::PrologueEnd => loc.prologue_end = true,
+                    LocOption::Other => {}
+                }
+                loc
+            },
+        )(input)
+        .map(|(input, loc)| (input, Self::Loc(loc)))
+    }
+
+    fn parse_cfi_startproc(input: Span<'_>) -> IResult<'_, Self> {
+        map(opt(preceded(space1, tag("simple"))), |simple| {
+            Self::CfiStartProc(simple.is_some())
+        })(input)
+    }
+
+    fn parse_cfi_sections(input: Span<'_>) -> IResult<'_, Self> {
+        map(
+            preceded(
+                space1,
+                separated_list1(sep, alt((tag(".eh_frame"), tag(".debug_frame")))),
+            ),
+            |names| {
+                let has = |name: &str| names.iter().any(|s: &Span<'_>| **s == name);
+                Self::CfiSections(CfiSections {
+                    eh_frame: has(".eh_frame"),
+                    debug_frame: has(".debug_frame"),
+                })
+            },
+        )(input)
+    }
+
+    fn parse_cfi_reg(insn: fn(u32) -> CfiInsn) -> impl Fn(Span<'_>) -> IResult<'_, Self> {
+        move |input| {
+            map(preceded(space1, parse_dwarf_reg), |reg| {
+                Self::Cfi(insn(reg))
+            })(input)
+        }
+    }
+
+    fn parse_cfi_expr(
+        insn: fn(Expr) -> CfiInsn,
+    ) -> impl Fn(Span<'_>) -> IResult<'_, Self> {
+        move |input| {
+            map(preceded(space1, Expr::parse), |expr| Self::Cfi(insn(expr)))(input)
+        }
+    }
+
+    fn parse_cfi_reg_expr(
+        insn: fn(u32, Expr) -> CfiInsn,
+    ) -> impl Fn(Span<'_>) -> IResult<'_, Self> {
+        move |input| {
+            map(
+                pair(
+                    preceded(space1, parse_dwarf_reg),
+                    preceded(sep, Expr::parse),
+                ),
+                |(reg, expr)| Self::Cfi(insn(reg, expr)),
+            )(input)
+        }
+    }
+
+    fn parse_cfi_register(input: Span<'_>) -> IResult<'_, Self> {
+        map(
+            pair(
+                preceded(space1, parse_dwarf_reg),
+                preceded(sep, parse_dwarf_reg),
+            ),
+            |(reg, other)| Self::Cfi(CfiInsn::Register(reg, other)),
+        )(input)
+    }
+
+    fn parse_cfi_escape(input: Span<'_>) -> IResult<'_, Self> {
+        map(
+            preceded(space1, separated_list1(sep, Expr::parse)),
+            |bytes| Self::Cfi(CfiInsn::Escape(bytes)),
+        )(input)
+    }
+
+    fn parse_option(input: Span<'_>) -> IResult<'_, Self> {
+        map(preceded(space1, AsmOption::parse), Self::Option)(input)
+    }
+
+    fn parse_attribute(input: Span<'_>) -> IResult<'_, Self> {
+        let (input, tag) = delimited(space1, parse_tag, sep)(input)?;
+
+        if is_str(tag) {
+            map(parse_path, |value| {
+                Self::Attribute(tag, AttrArg::Str(value))
+            })(input)
+        } else {
+            map(Expr::parse, |value| {
+                Self::Attribute(tag, AttrArg::Int(value))
+            })(input)
+        }
+    }
+
+    fn parse_section(input: Span<'_>) -> IResult<'_, Self> {
+        let (input, name) = preceded(
+            space1,
+            take_while1(|c: char| c.is_ascii_alphanumeric() || matches!(c, '_' | '.')),
+        )(input)?;
+
+        let (input, flags) = opt(preceded(
+            delimited(space0, char(','), space0),
+            cut(|input| SectionFlags::parse(&name, input)),
+        ))(input)?;
+
+        Ok((
+            input,
+            Self::Section(
+                name.to_string(),
+                flags.unwrap_or(SectionFlags::for_name(&name)),
+            ),
+        ))
+    }
+}
+
+impl DirectiveKind {
+    fn parse(input: Span<'_>) -> IResult<'_, Self> {
+        map_opt(
+            take_while1(|c: char| c.is_ascii_alphanumeric() || c == '_'),
+            |s: Span<'_>| DIRECTIVE.get(*s).copied(),
+        )(input)
+        .map_err(|e| e.map(|e: AsmError<'_>| e.with_kind(AsmErrorKind::InvalidDirective)))
+    }
+}
+
+/// Generates `DirectiveKind` and the `DIRECTIVE` table. The name of a
+/// directive is the variant name, unless given explicitly, e.g.
+/// `r#if("if")` as `if` is a keyword.
+macro_rules! directive {
+    (@entries [$($entries:tt)*]) => {
+        directive!(@table $($entries)*);
+    };
+    (@entries [$($entries:tt)*] $name:ident($key:literal) => $parser:expr $(, $($rest:tt)*)?) => {
+        directive!(@entries [$($entries)* ($name, [$key], $parser)] $($($rest)*)?);
+    };
+    (@entries [$($entries:tt)*] $name:ident => $parser:expr $(, $($rest:tt)*)?) => {
+        directive!(@entries [$($entries)* ($name, [~($name)], $parser)] $($($rest)*)?);
+    };
+    (@table $(($name:ident, [$($key:tt)+], $parser:expr))+) => {
+        #[allow(non_camel_case_types)]
+        #[derive(Debug, Clone, Copy)]
+        enum DirectiveKind {
+            $(
+                $name,
+            )+
+        }
+
+        impl DirectiveKind {
+            fn parse_args<'i>(&self, input: Span<'i>) -> IResult<'i, Directive> {
+                match self {
+                    $(
+                        Self::$name => $parser(input),
+                    )+
+                }
+            }
+        }
+
+        literify! {
+            static DIRECTIVE: phf::Map<&'static str, DirectiveKind> = phf_map! {
+                $(
+                    $($key)+ => DirectiveKind::$name,
+                )+
+            };
+        }
+    };
+    ($($entries:tt)+) => {
+        directive!(@entries [] $($entries)+);
+    };
+}
+
+directive! {
+    text    => |input| Ok((input, Directive::section(".text"))),
+    data    => |input| Ok((input, Directive::section(".data"))),
+    rodata  => |input| Ok((input, Directive::section(".rodata"))),
+    bss     => |input| Ok((input, Directive::section(".bss"))),
+    section => Directive::parse_section,
+    byte    => Directive::parse_int(1),
+    half    => Directive::parse_int(2),
+    short   => Directive::parse_int(2),
+    word    => Directive::parse_int(4),
+    long    => Directive::parse_int(4),
+    dword   => Directive::parse_int(8),
+    quad    => Directive::parse_int(8),
+    ascii   => Directive::parse_ascii(false),
+    string  => Directive::parse_ascii(true),
+    asciz   => Directive::parse_ascii(true),
+    zero    => Directive::parse_zero,
+    space   => Directive::parse_space,
+    skip    => Directive::parse_space,
+    fill    => Directive::parse_fill,
+    align   => Directive::parse_align(AlignKind::Pow2),
+    p2align => Directive::parse_align(AlignKind::Pow2),
+    balign  => Directive::parse_align(AlignKind::Bytes),
+    org     => Directive::parse_org,
+    include => Directive::parse_include,
+    incbin  => Directive::parse_incbin,
+    r#macro("macro") => Directive::parse_macro,
+    endm    => |input| Ok((input, Directive::Endm)),
+    exitm   => |input| Ok((input, Directive::Exitm)),
+    rept    => |input| map(preceded(space1, Expr::parse), Directive::Rept)(input),
+    irp     => Directive::parse_irp,
+    irpc    => Directive::parse_irpc,
+    endr    => |input| Ok((input, Directive::Endr)),
+    r#if("if") => |input| map(Directive::parse_if, Directive::If)(input),
+    ifdef   => Directive::parse_ifdef(true),
+    ifndef  => Directive::parse_ifdef(false),
+    ifnotdef => Directive::parse_ifdef(false),
+    elseif  => |input| map(Directive::parse_if, Directive::ElseIf)(input),
+    r#else("else") => |input| Ok((input, Directive::Else)),
+    endif   => |input| Ok((input, Directive::Endif)),
+    globl   => Directive::parse_bind(Binding::Global),
+    global  => Directive::parse_bind(Binding::Global),
+    local   => Directive::parse_bind(Binding::Local),
+    weak    => Directive::parse_bind(Binding::Weak),
+    hidden  => Directive::parse_visibility(Visibility::Hidden),
+    internal => Directive::parse_visibility(Visibility::Internal),
+    protected => Directive::parse_visibility(Visibility::Protected),
+    r#type("type") => Directive::parse_type,
+    size    => Directive::parse_size,
+    set     => Directive::parse_set,
+    equ     => Directive::parse_set,
+    file    => Directive::parse_file,
+    loc     => Directive::parse_loc,
+    cfi_startproc => Directive::parse_cfi_startproc,
+    cfi_endproc => |input| Ok((input, Directive::CfiEndProc)),
+    cfi_sections => Directive::parse_cfi_sections,
+    cfi_def_cfa => Directive::parse_cfi_reg_expr(CfiInsn::DefCfa),
+    cfi_def_cfa_register => Directive::parse_cfi_reg(CfiInsn::DefCfaRegister),
+    cfi_def_cfa_offset => Directive::parse_cfi_expr(CfiInsn::DefCfaOffset),
+    cfi_adjust_cfa_offset => Directive::parse_cfi_expr(CfiInsn::AdjustCfaOffset),
+    cfi_offset => Directive::parse_cfi_reg_expr(CfiInsn::Offset),
+    cfi_rel_offset => Directive::parse_cfi_reg_expr(CfiInsn::RelOffset),
+    cfi_restore => Directive::parse_cfi_reg(CfiInsn::Restore),
+    cfi_undefined => Directive::parse_cfi_reg(CfiInsn::Undefined),
+    cfi_same_value => Directive::parse_cfi_reg(CfiInsn::SameValue),
+    cfi_register => Directive::parse_cfi_register,
+    cfi_remember_state => |input| Ok((input, Directive::Cfi(CfiInsn::RememberState))),
+    cfi_restore_state => |input| Ok((input, Directive::Cfi(CfiInsn::RestoreState))),
+    cfi_escape => Directive::parse_cfi_escape,
+    attribute => Directive::parse_attribute,
+    option => Directive::parse_option,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn string_escapes() {
+        let (rest, bytes) =
+            parse_string(Span::new(r#""a\n\t\\\"\x41\101\0é" x"#, true)).unwrap();
+        assert_eq!(*rest, " x");
+        assert_eq!(bytes, b"a\n\t\\\"AA\0\xc3\xa9");
+
+        for src in [r#""\q""#, r#""open"#, "\"a\nb\""] {
+            let Err(nom::Err::Failure(error)) = parse_string(Span::new(src, true)) else {
+                panic!("{src}");
+            };
+            assert!(matches!(error.kind, AsmErrorKind::InvalidString), "{src}");
+        }
+    }
+}
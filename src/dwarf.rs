@@ -0,0 +1,641 @@
+use std::{collections::BTreeMap, env};
+
+use crate::{
+    imm::{Target, Value},
+    program::Program,
+    reloc::{Reloc, RelocKind},
+};
+
+const DW_TAG_COMPILE_UNIT: u64 = 0x11;
+const DW_CHILDREN_NO: u8 = 0;
+
+const DW_AT_NAME: u64 = 0x03;
+const DW_AT_STMT_LIST: u64 = 0x10;
+const DW_AT_LOW_PC: u64 = 0x11;
+const DW_AT_HIGH_PC: u64 = 0x12;
+const DW_AT_LANGUAGE: u64 = 0x13;
+const DW_AT_COMP_DIR: u64 = 0x1b;
+const DW_AT_PRODUCER: u64 = 0x25;
+const DW_AT_RANGES: u64 = 0x55;
+
+const DW_FORM_ADDR: u64 = 0x01;
+const DW_FORM_DATA2: u64 = 0x05;
+const DW_FORM_DATA4: u64 = 0x06;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_SEC_OFFSET: u64 = 0x17;
+
+const DW_LANG_MIPS_ASSEMBLER: u16 = 0x8001;
+
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNS_SET_COLUMN: u8 = 5;
+const DW_LNS_NEGATE_STMT: u8 = 6;
+const DW_LNS_FIXED_ADVANCE_PC: u8 = 9;
+const DW_LNS_SET_PROLOGUE_END: u8 = 10;
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+
+/// Operand counts of the standard opcodes. Special opcodes aren't used, so
+/// `line_base` and `line_range` only need to be valid.
+const STANDARD_OPCODE_LENGTHS: [u8; 12] = [0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1];
+const LINE_BASE: i8 = -5;
+const LINE_RANGE: u8 = 14;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Loc {
+    /// Number of a file given by `.file`.
+    pub file: u32,
+    pub line: u32,
+    /// Column, 0 if unknown.
+    pub col: u32,
+    pub is_stmt: bool,
+    pub prologue_end: bool,
+}
+
+/// Row of the line table: the code from `offset` up to the next row comes
+/// from `loc`.
+#[derive(Debug, Clone, Copy)]
+pub struct LineRow {
+    pub section: usize,
+    pub offset: u32,
+    pub loc: Loc,
+}
+
+#[derive(Debug, Default)]
+pub struct LineInfo {
+    /// Name of the source file, from `.file "name"`.
+    pub name: Option<String>,
+    pub files: BTreeMap<u32, String>,
+    pub rows: Vec<LineRow>,
+    /// Position of the last `.loc`, given to the next instruction.
+    pub(crate) loc: Option<Loc>,
+}
+
+impl LineInfo {
+    /// The source comes with its own line information, which replaces the
+    /// lines of the assembly source.
+    pub fn has_locs(&self) -> bool {
+        !self.rows.is_empty()
+    }
+}
+
+pub(crate) struct DebugSection {
+    pub name: &'static str,
+    pub data: Vec<u8>,
+    pub relocs: Vec<Reloc>,
+    /// Loaded along with the code, like `.eh_frame`.
+    pub alloc: bool,
+}
+
+/// Writer of debug sections. In object files addresses and references
+/// to other debug sections are left to relocations.
+pub(crate) struct Buf {
+    pub data: Vec<u8>,
+    pub relocs: Vec<Reloc>,
+    pub addr_size: u8,
+    relocatable: bool,
+}
+
+impl Buf {
+    pub(crate) fn new(addr_size: u8, relocatable: bool) -> Self {
+        Self {
+            data: Vec::new(),
+            relocs: Vec::new(),
+            addr_size,
+            relocatable,
+        }
+    }
+
+    pub(crate) fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub(crate) fn u16(&mut self, value: u16) {
+        self.data.extend(value.to_le_bytes());
+    }
+
+    pub(crate) fn u32(&mut self, value: u32) {
+        self.data.extend(value.to_le_bytes());
+    }
+
+    pub(crate) fn uleb(&mut self, value: u64) {
+        uleb(&mut self.data, value);
+    }
+
+    pub(crate) fn sleb(&mut self, value: i64) {
+        sleb(&mut self.data, value);
+    }
+
+    pub(crate) fn str(&mut self, s: &str) {
+        self.data.extend_from_slice(s.as_bytes());
+        self.u8(0);
+    }
+
+    /// Address-sized number.
+    pub(crate) fn word(&mut self, value: u64) {
+        self.data
+            .extend(&value.to_le_bytes()[..self.addr_size as usize]);
+    }
+
+    /// Address of `value`, which is `addr` once laid out.
+    pub(crate) fn addr(&mut self, value: Value, addr: u64) {
+        if self.relocatable {
+            let kind = match self.addr_size {
+                4 => RelocKind::Abs32,
+                _ => RelocKind::Abs64,
+            };
+            self.reloc(kind, value);
+            self.word(0);
+        } else {
+            self.word(addr);
+        }
+    }
+
+    /// Difference `end - start` of locations in a section, of `size` bytes.
+    /// It is left to a pair of relocations if `relaxed`, the linker being
+    /// free to relax the code in between.
+    pub(crate) fn diff(&mut self, size: u8, start: Value, end: Value, relaxed: bool) {
+        let mut value = end.offset - start.offset;
+        if relaxed {
+            self.reloc(RelocKind::Add(size), end);
+            self.reloc(RelocKind::Sub(size), start);
+            value = 0;
+        }
+        self.data.extend(&value.to_le_bytes()[..size as usize]);
+    }
+
+    /// Offset into another debug section.
+    pub(crate) fn sec_offset(&mut self, section: usize, offset: u32) {
+        if self.relocatable {
+            self.reloc(RelocKind::Abs32, Value::rel(section, offset));
+            self.u32(0);
+        } else {
+            self.u32(offset);
+        }
+    }
+
+    pub(crate) fn reloc(&mut self, kind: RelocKind, value: Value) {
+        self.relocs.push(Reloc {
+            offset: self.data.len() as u32,
+            kind,
+            target: Target { sym: None, value },
+        });
+    }
+
+    /// Length field, filled by `end` with the length of what follows.
+    pub(crate) fn start(&mut self) -> usize {
+        let pos = self.data.len();
+        self.u32(0);
+        pos
+    }
+
+    pub(crate) fn end(&mut self, pos: usize) {
+        let len = (self.data.len() - pos - 4) as u32;
+        self.data[pos..pos + 4].copy_from_slice(&len.to_le_bytes());
+    }
+
+    pub(crate) fn section(self, name: &'static str) -> DebugSection {
+        DebugSection {
+            name,
+            data: self.data,
+            relocs: self.relocs,
+            alloc: false,
+        }
+    }
+}
+
+fn set_address(line: &mut Buf, value: Value, addr: u64) {
+    line.u8(0);
+    line.uleb(line.addr_size as u64 + 1);
+    line.u8(DW_LNE_SET_ADDRESS);
+    line.addr(value, addr);
+}
+
+pub(crate) fn uleb(buf: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf.push(byte);
+            break;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+pub(crate) fn sleb(buf: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        let done = (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0);
+        if done {
+            buf.push(byte);
+            break;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+impl Program {
+    /// DWARF 4 line table of the code along with a compile unit and the
+    /// address ranges covered by it. Returns nothing without code. The
+    /// sections are numbered from `first` in the output, for references
+    /// between them.
+    pub(crate) fn debug_sections(
+        &self,
+        addr_size: u8,
+        first: usize,
+        relocatable: bool,
+    ) -> Vec<DebugSection> {
+        let (files, mut rows) = self.line_table();
+        if rows.is_empty() {
+            return Vec::new();
+        }
+        rows.sort_by_key(|row| (row.section, row.offset));
+
+        let mut ranges: Vec<usize> = rows.iter().map(|row| row.section).collect();
+        ranges.dedup();
+        let start = |section: usize| {
+            (Value::rel(section, 0), self.sections()[section].addr as u64)
+        };
+        let end = |section: usize| {
+            let size = self.sections()[section].size();
+            (
+                Value::rel(section, size),
+                self.sections()[section].addr as u64 + size as u64,
+            )
+        };
+
+        let (line_idx, abbrev_idx, info_idx, ranges_idx) =
+            (first, first + 1, first + 2, first + 4);
+        let buf = || Buf::new(addr_size, relocatable);
+        let relaxed = |section: usize| relocatable && self.sections()[section].relaxed;
+
+        let mut line = buf();
+        let unit = line.start();
+        line.u16(4);
+        let header = line.start();
+        // Minimum instruction length, maximum operations per instruction and
+        // the default of `is_stmt`.
+        line.data.extend([1, 1, 1]);
+        line.u8(LINE_BASE as u8);
+        line.u8(LINE_RANGE);
+        line.u8(STANDARD_OPCODE_LENGTHS.len() as u8 + 1);
+        line.data.extend(STANDARD_OPCODE_LENGTHS);
+        // Paths are complete or relative to the compilation directory.
+        line.u8(0);
+        for file in &files {
+            line.str(file);
+            // Directory, time and size.
+            line.data.extend([0, 0, 0]);
+        }
+        line.u8(0);
+        line.end(header);
+
+        for group in rows.chunk_by(|a, b| a.section == b.section) {
+            let section = group[0].section;
+            let relaxed = relaxed(section);
+            let (value, addr) = start(section);
+            set_address(&mut line, value, addr);
+
+            let mut state = Loc {
+                file: 1,
+                line: 1,
+                col: 0,
+                is_stmt: true,
+                prologue_end: false,
+            };
+            let mut offset = 0;
+            for row in group {
+                let loc = row.loc;
+                if loc.file != state.file {
+                    line.u8(DW_LNS_SET_FILE);
+                    line.uleb(loc.file as u64);
+                }
+                if loc.col != state.col {
+                    line.u8(DW_LNS_SET_COLUMN);
+                    line.uleb(loc.col as u64);
+                }
+                if loc.is_stmt != state.is_stmt {
+                    line.u8(DW_LNS_NEGATE_STMT);
+                }
+                if loc.line != state.line {
+                    line.u8(DW_LNS_ADVANCE_LINE);
+                    line.sleb(loc.line as i64 - state.line as i64);
+                }
+                if row.offset != offset {
+                    self.advance_pc(&mut line, section, offset, row.offset, relaxed);
+                }
+                if loc.prologue_end {
+                    line.u8(DW_LNS_SET_PROLOGUE_END);
+                }
+                line.u8(DW_LNS_COPY);
+
+                state = loc;
+                offset = row.offset;
+            }
+
+            let size = self.sections()[section].size();
+            if size != offset {
+                self.advance_pc(&mut line, section, offset, size, relaxed);
+            }
+            line.data.extend([0, 1, DW_LNE_END_SEQUENCE]);
+        }
+        line.end(unit);
+
+        let name = self.line_info().name.clone().or_else(|| {
+            self.sources()
+                .iter()
+                .rfind(|source| source.parent.is_none())
+                .map(|source| source.name.clone())
+        });
+        let comp_dir = env::current_dir().ok().map(|dir| dir.display().to_string());
+
+        let mut attrs = vec![(DW_AT_PRODUCER, DW_FORM_STRING)];
+        attrs.extend(name.is_some().then_some((DW_AT_NAME, DW_FORM_STRING)));
+        attrs.extend(
+            comp_dir
+                .is_some()
+                .then_some((DW_AT_COMP_DIR, DW_FORM_STRING)),
+        );
+        attrs.extend([
+            (DW_AT_LANGUAGE, DW_FORM_DATA2),
+            (DW_AT_STMT_LIST, DW_FORM_SEC_OFFSET),
+            (DW_AT_LOW_PC, DW_FORM_ADDR),
+        ]);
+        // One range is given by the bounds of the unit, more need a range
+        // list based at 0.
+        if ranges.len() == 1 {
+            attrs.push((DW_AT_HIGH_PC, DW_FORM_DATA4));
+        } else {
+            attrs.push((DW_AT_RANGES, DW_FORM_SEC_OFFSET));
+        }
+
+        let mut abbrev = buf();
+        abbrev.uleb(1);
+        abbrev.uleb(DW_TAG_COMPILE_UNIT);
+        abbrev.u8(DW_CHILDREN_NO);
+        for &(attr, form) in &attrs {
+            abbrev.uleb(attr);
+            abbrev.uleb(form);
+        }
+        abbrev.data.extend([0, 0, 0]);
+
+        let mut info = buf();
+        let unit = info.start();
+        info.u16(4);
+        info.sec_offset(abbrev_idx, 0);
+        info.u8(addr_size);
+        info.uleb(1);
+        info.str(concat!(
+            env!("CARGO_PKG_NAME"),
+            " ",
+            env!("CARGO_PKG_VERSION")
+        ));
+        if let Some(name) = &name {
+            info.str(name);
+        }
+        if let Some(comp_dir) = &comp_dir {
+            info.str(comp_dir);
+        }
+        info.u16(DW_LANG_MIPS_ASSEMBLER);
+        info.sec_offset(line_idx, 0);
+        if let [section] = ranges[..] {
+            let (value, addr) = start(section);
+            info.addr(value, addr);
+            info.diff(4, value, end(section).0, relaxed(section));
+        } else {
+            info.word(0);
+            info.sec_offset(ranges_idx, 0);
+        }
+        info.end(unit);
+
+        let mut aranges = buf();
+        let unit = aranges.start();
+        aranges.u16(2);
+        aranges.sec_offset(info_idx, 0);
+        aranges.u8(addr_size);
+        aranges.u8(0);
+        // Tuples are aligned to their size.
+        while aranges.data.len() % (2 * addr_size as usize) != 0 {
+            aranges.u8(0);
+        }
+        for &section in &ranges {
+            let (value, addr) = start(section);
+            aranges.addr(value, addr);
+            aranges.diff(addr_size, value, end(section).0, relaxed(section));
+        }
+        aranges.word(0);
+        aranges.word(0);
+        aranges.end(unit);
+
+        let mut sections = vec![
+            line.section(".debug_line"),
+            abbrev.section(".debug_abbrev"),
+            info.section(".debug_info"),
+            aranges.section(".debug_aranges"),
+        ];
+        if ranges.len() > 1 {
+            let mut list = buf();
+            for &section in &ranges {
+                let (value, addr) = start(section);
+                list.addr(value, addr);
+                let (value, addr) = end(section);
+                list.addr(value, addr);
+            }
+            list.word(0);
+            list.word(0);
+            sections.push(list.section(".debug_ranges"));
+        }
+
+        sections
+    }
+
+    /// Advances the address of the line table from `from` to `to` in the
+    /// section. Relaxed code needs a fixed size operand for relocations,
+    /// or the address set again if it is too far.
+    fn advance_pc(
+        &self,
+        line: &mut Buf,
+        section: usize,
+        from: u32,
+        to: u32,
+        relaxed: bool,
+    ) {
+        let (from, to) = (Value::rel(section, from), Value::rel(section, to));
+        let delta = to.offset - from.offset;
+        if !relaxed {
+            line.u8(DW_LNS_ADVANCE_PC);
+            line.uleb(delta as u64);
+        } else if delta <= u16::MAX as i64 {
+            line.u8(DW_LNS_FIXED_ADVANCE_PC);
+            line.diff(2, from, to, relaxed);
+        } else {
+            set_address(line, to, self.addr(to) as u64);
+        }
+    }
+
+    /// Files and rows of the line table: the ones given by `.loc`, otherwise
+    /// the lines of the assembly source emitting code. Files are numbered
+    /// from 1.
+    fn line_table(&self) -> (Vec<String>, Vec<LineRow>) {
+        let info = self.line_info();
+        let code = |section: usize| {
+            let flags = self.sections()[section].flags;
+            flags.alloc && flags.exec
+        };
+
+        if info.has_locs() {
+            let numbers: Vec<u32> = info.files.keys().copied().collect();
+            let rows = info
+                .rows
+                .iter()
+                .filter(|row| code(row.section))
+                .map(|row| LineRow {
+                    loc: Loc {
+                        file: numbers.partition_point(|&num| num < row.loc.file) as u32
+                            + 1,
+                        ..row.loc
+                    },
+                    ..*row
+                })
+                .collect();
+
+            return (info.files.values().cloned().collect(), rows);
+        }
+
+        let mut files: Vec<String> = Vec::new();
+        let mut rows: Vec<LineRow> = Vec::new();
+        for (idx, section) in self.sections().iter().enumerate() {
+            if !code(idx) {
+                continue;
+            }
+
+            for entry in section.items.iter().filter(|entry| entry.item.size() > 0) {
+                let span = self.span(entry.line);
+                let file = match files.iter().position(|file| *file == span.file) {
+                    Some(file) => file,
+                    None => {
+                        files.push(span.file);
+                        files.len() - 1
+                    }
+                };
+                let loc = Loc {
+                    file: file as u32 + 1,
+                    line: span.number as u32,
+                    col: span.col as u32,
+                    is_stmt: true,
+                    prologue_end: false,
+                };
+
+                if !rows
+                    .last()
+                    .is_some_and(|row| row.section == idx && row.loc == loc)
+                {
+                    rows.push(LineRow {
+                        section: idx,
+                        offset: entry.offset,
+                        loc,
+                    });
+                }
+            }
+        }
+
+        (files, rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line_program(src: &str) -> Vec<u8> {
+        let program = Program::parse(src).unwrap();
+        let sections = program.debug_sections(4, 1, false);
+        let line = &sections[0];
+        assert_eq!(line.name, ".debug_line");
+
+        let header_len = u32::from_le_bytes(line.data[6..10].try_into().unwrap());
+        line.data[10 + header_len as usize..].to_vec()
+    }
+
+    #[test]
+    fn loc_rows() {
+        let src = "
+.file 1 \"a.c\"
+.loc 1 3 0
+  addi a0, a0, 1
+  addi a0, a0, 1
+.loc 1 5 2
+  addi a0, a0, 2
+";
+        assert_eq!(
+            line_program(src),
+            [
+                0x00, 0x05, 0x02, 0x00, 0x00, 0x00, 0x00, // Address 0.
+                0x03, 0x02, 0x01, // Line 3.
+                0x05, 0x02, 0x03, 0x02, 0x02, 0x08, 0x01, // Column 2, line 5 at 8.
+                0x02, 0x04, 0x00, 0x01, 0x01, // End at 12.
+            ]
+        );
+    }
+
+    #[test]
+    fn source_rows() {
+        let src = "  addi a0, a0, 1\n\n  addi a0, a0, 2\n";
+        assert_eq!(
+            line_program(src),
+            [
+                0x00, 0x05, 0x02, 0x00, 0x00, 0x00, 0x00, // Address 0.
+                0x05, 0x03, 0x01, // Column 3, line 1.
+                0x03, 0x02, 0x02, 0x04, 0x01, // Line 3 at 4.
+                0x02, 0x04, 0x00, 0x01, 0x01, // End at 8.
+            ]
+        );
+    }
+
+    #[test]
+    fn relaxed_rows() {
+        let src = ".option relax\n  addi a0, a0, 1\n  addi a0, a0, 2\n";
+        let program = Program::parse(src).unwrap();
+        let sections = program.debug_sections(4, 1, true);
+        let line = &sections[0];
+
+        let header_len = u32::from_le_bytes(line.data[6..10].try_into().unwrap());
+        let start = 10 + header_len as usize;
+        assert_eq!(
+            line.data[start..],
+            [
+                0x00, 0x05, 0x02, 0x00, 0x00, 0x00, 0x00, // Address of the code.
+                0x05, 0x03, 0x03, 0x01, 0x01, // Column 3, line 2.
+                0x03, 0x01, 0x09, 0x00, 0x00, 0x01, // Line 3 at 4.
+                0x09, 0x00, 0x00, 0x00, 0x01, 0x01, // End at 8.
+            ]
+        );
+
+        // Advances are differences of the locations of the rows.
+        let relocs: Vec<_> = line
+            .relocs
+            .iter()
+            .map(|reloc| {
+                (
+                    reloc.offset as usize - start,
+                    reloc.kind,
+                    reloc.target.value,
+                )
+            })
+            .collect();
+        assert_eq!(
+            relocs,
+            [
+                (3, RelocKind::Abs32, Value::rel(0, 0)),
+                (15, RelocKind::Add(2), Value::rel(0, 4)),
+                (15, RelocKind::Sub(2), Value::rel(0, 0)),
+                (19, RelocKind::Add(2), Value::rel(0, 8)),
+                (19, RelocKind::Sub(2), Value::rel(0, 4)),
+            ]
+        );
+    }
+}
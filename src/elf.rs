@@ -0,0 +1,1415 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    mem,
+};
+
+use crate::{
+    cfi::CfiSections,
+    dwarf::DebugSection,
+    error::{AsmErrorKind, Error},
+    imm::Value,
+    link::{Linked, ObjReloc, ObjSection, ObjSymbol, Object, SymDef},
+    option::isa_string,
+    program::{self, Program},
+    reloc::{Reloc, RelocKind},
+    section::{Section, SectionFlags},
+    symbol::{Binding, Symbol, SymbolKind, Visibility},
+};
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ElfConfig {
+    pub class: ElfClass,
+    pub float_abi: FloatAbi,
+    /// Add line information of the assembly source. Line information
+    /// given by `.loc` is always added.
+    pub debug: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ElfClass {
+    #[default]
+    Elf32,
+    Elf64,
+}
+
+/// Floating-point calling convention, recorded in `e_flags`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FloatAbi {
+    #[default]
+    Soft,
+    Single,
+    Double,
+    Quad,
+}
+
+impl ElfConfig {
+    fn flags(&self, rvc: bool) -> u32 {
+        let float_abi = match self.float_abi {
+            FloatAbi::Soft => 0x0,
+            FloatAbi::Single => 0x2,
+            FloatAbi::Double => 0x4,
+            FloatAbi::Quad => 0x6,
+        };
+
+        float_abi | rvc as u32
+    }
+
+    /// ISA string of the target: the extensions of the code along with the
+    /// ones implied by the floating-point ABI.
+    fn arch(&self, exts: &BTreeSet<String>) -> String {
+        let float: &[&str] = match self.float_abi {
+            FloatAbi::Soft => &[],
+            FloatAbi::Single => &["f", "zicsr"],
+            FloatAbi::Double => &["f", "d", "zicsr"],
+            FloatAbi::Quad => &["f", "d", "q", "zicsr"],
+        };
+        let mut exts = exts.clone();
+        exts.extend(float.iter().map(|ext| ext.to_string()));
+
+        let xlen = match self.class {
+            ElfClass::Elf32 => 32,
+            ElfClass::Elf64 => 64,
+        };
+        isa_string(xlen, &exts)
+    }
+}
+
+const ET_REL: u16 = 1;
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 243;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+const SHT_REL: u32 = 9;
+const SHT_INIT_ARRAY: u32 = 14;
+const SHT_FINI_ARRAY: u32 = 15;
+const SHT_PREINIT_ARRAY: u32 = 16;
+const SHT_RISCV_ATTRIBUTES: u32 = 0x7000_0003;
+
+const SHF_WRITE: u64 = 0x1;
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 0x1;
+const PF_W: u32 = 0x2;
+const PF_R: u32 = 0x4;
+
+const PAGE: u64 = program::PAGE as u64;
+
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xfff1;
+const SHN_COMMON: u16 = 0xfff2;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STB_WEAK: u8 = 2;
+
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+
+type Named<'p> = Vec<(&'p str, &'p Symbol)>;
+
+#[derive(Debug, Default)]
+struct OutSection {
+    name: String,
+    kind: u32,
+    flags: u64,
+    addr: u64,
+    data: Vec<u8>,
+    /// Size of NoBits sections, which have no data.
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+}
+
+#[derive(Debug, Default)]
+struct ElfSym {
+    name: u32,
+    value: u64,
+    size: u64,
+    info: u8,
+    other: u8,
+    shndx: u16,
+}
+
+/// String table, starting with the empty string.
+#[derive(Debug)]
+struct StrTab(Vec<u8>);
+
+impl StrTab {
+    fn new() -> Self {
+        Self(vec![0])
+    }
+
+    fn add(&mut self, s: &str) -> u32 {
+        let idx = self.0.len() as u32;
+        self.0.extend_from_slice(s.as_bytes());
+        self.0.push(0);
+        idx
+    }
+}
+
+/// Symbol table along with its string table and the indices of the named
+/// symbols.
+struct SymTab {
+    syms: Vec<ElfSym>,
+    strtab: StrTab,
+    index: HashMap<String, u32>,
+}
+
+impl SymTab {
+    fn new() -> Self {
+        Self {
+            syms: vec![ElfSym::default()],
+            strtab: StrTab::new(),
+            index: HashMap::new(),
+        }
+    }
+
+    fn add(&mut self, name: &str, sym: ElfSym) -> u32 {
+        let idx = self.syms.len() as u32;
+        self.index.insert(name.to_string(), idx);
+        self.syms.push(ElfSym {
+            name: self.strtab.add(name),
+            ..sym
+        });
+        idx
+    }
+
+    /// `.symtab` and `.strtab` sections, the latter at index `strtab`.
+    fn sections(
+        self,
+        class: ElfClass,
+        strtab: u32,
+        first_global: u32,
+    ) -> [OutSection; 2] {
+        let mut symbols = Writer::new(class);
+        for sym in &self.syms {
+            symbols.sym(sym);
+        }
+
+        [
+            OutSection {
+                name: ".symtab".into(),
+                kind: SHT_SYMTAB,
+                size: symbols.buf.len() as u64,
+                data: symbols.buf,
+                link: strtab,
+                info: first_global,
+                align: class.word_size(),
+                entsize: class.sym_size(),
+                ..Default::default()
+            },
+            OutSection {
+                name: ".strtab".into(),
+                kind: SHT_STRTAB,
+                size: self.strtab.0.len() as u64,
+                data: self.strtab.0,
+                align: 1,
+                ..Default::default()
+            },
+        ]
+    }
+}
+
+/// Little-endian writer of fields whose size depends on the class.
+struct Writer {
+    class: ElfClass,
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn new(class: ElfClass) -> Self {
+        Self {
+            class,
+            buf: Vec::new(),
+        }
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.buf.extend(value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.buf.extend(value.to_le_bytes());
+    }
+
+    /// Address or offset, 4 or 8 bytes.
+    fn word(&mut self, value: u64) {
+        match self.class {
+            ElfClass::Elf32 => self.u32(value as u32),
+            ElfClass::Elf64 => self.buf.extend(value.to_le_bytes()),
+        }
+    }
+
+    fn align(&mut self, align: u64) {
+        let len = (self.buf.len() as u64).next_multiple_of(align.max(1));
+        self.buf.resize(len as usize, 0);
+    }
+
+    fn sym(&mut self, sym: &ElfSym) {
+        self.u32(sym.name);
+        match self.class {
+            ElfClass::Elf32 => {
+                self.word(sym.value);
+                self.word(sym.size);
+                self.u8(sym.info);
+                self.u8(sym.other);
+                self.u16(sym.shndx);
+            }
+            ElfClass::Elf64 => {
+                self.u8(sym.info);
+                self.u8(sym.other);
+                self.u16(sym.shndx);
+                self.word(sym.value);
+                self.word(sym.size);
+            }
+        }
+    }
+
+    fn phdr(&mut self, flags: u32, offset: u64, addr: u64, filesz: u64, memsz: u64) {
+        self.u32(PT_LOAD);
+        if self.class == ElfClass::Elf64 {
+            self.u32(flags);
+        }
+        self.word(offset);
+        self.word(addr);
+        self.word(addr);
+        self.word(filesz);
+        self.word(memsz);
+        if self.class == ElfClass::Elf32 {
+            self.u32(flags);
+        }
+        self.word(PAGE);
+    }
+
+    fn rela(&mut self, offset: u64, sym: u32, kind: u32, addend: i64) {
+        self.word(offset);
+        match self.class {
+            ElfClass::Elf32 => {
+                self.u32(sym << 8 | kind);
+                self.u32(addend as u32);
+            }
+            ElfClass::Elf64 => {
+                self.word((sym as u64) << 32 | kind as u64);
+                self.word(addend as u64);
+            }
+        }
+    }
+}
+
+impl ElfClass {
+    fn word_size(&self) -> u64 {
+        match self {
+            Self::Elf32 => 4,
+            Self::Elf64 => 8,
+        }
+    }
+
+    fn sym_size(&self) -> u64 {
+        match self {
+            Self::Elf32 => 16,
+            Self::Elf64 => 24,
+        }
+    }
+
+    fn rela_size(&self) -> u64 {
+        3 * self.word_size()
+    }
+
+    fn ehdr_size(&self) -> u16 {
+        match self {
+            Self::Elf32 => 52,
+            Self::Elf64 => 64,
+        }
+    }
+
+    fn phdr_size(&self) -> u16 {
+        match self {
+            Self::Elf32 => 32,
+            Self::Elf64 => 56,
+        }
+    }
+
+    fn shdr_size(&self) -> u16 {
+        match self {
+            Self::Elf32 => 40,
+            Self::Elf64 => 64,
+        }
+    }
+}
+
+fn section_flags(flags: SectionFlags) -> u64 {
+    let mut bits = 0;
+    if flags.alloc {
+        bits |= SHF_ALLOC;
+    }
+    if flags.write {
+        bits |= SHF_WRITE;
+    }
+    if flags.exec {
+        bits |= SHF_EXECINSTR;
+    }
+
+    bits
+}
+
+fn sym_info(binding: Binding, kind: SymbolKind) -> u8 {
+    let binding = match binding {
+        Binding::Local => STB_LOCAL,
+        Binding::Global => STB_GLOBAL,
+        Binding::Weak => STB_WEAK,
+    };
+    let kind = match kind {
+        SymbolKind::NoType => STT_NOTYPE,
+        SymbolKind::Object => STT_OBJECT,
+        SymbolKind::Func => STT_FUNC,
+    };
+
+    binding << 4 | kind
+}
+
+fn sym_other(visibility: Visibility) -> u8 {
+    match visibility {
+        Visibility::Default => 0,
+        Visibility::Internal => 1,
+        Visibility::Hidden => 2,
+        Visibility::Protected => 3,
+    }
+}
+
+/// Index of the section header of a value, sections of the program follow
+/// the null section.
+fn shndx(value: Option<Value>) -> u16 {
+    match value {
+        Some(Value {
+            section: Some(section),
+            ..
+        }) => section as u16 + 1,
+        Some(_) => SHN_ABS,
+        None => SHN_UNDEF,
+    }
+}
+
+impl Program {
+    pub fn elf_object(&self, config: &ElfConfig) -> Result<Vec<u8>, Error> {
+        let class = config.class;
+        let (mut sections, mut relocs): (Vec<OutSection>, Vec<Vec<Reloc>>) = self
+            .generate_relocatable()?
+            .into_iter()
+            .map(|(section, data, relocs)| (out_section(section, data), relocs))
+            .unzip();
+        let frames = self.frame_info().sections;
+        for mut debug in self.debug_info(config, sections.len(), true, frames) {
+            relocs.push(mem::take(&mut debug.relocs));
+            sections.push(debug_section(class, debug));
+        }
+        sections.push(self.attributes_section(config));
+        relocs.push(Vec::new());
+
+        // Symbols: the null one, sections, named locals, then the rest.
+        let mut symtab = SymTab::new();
+        for idx in 0..sections.len() {
+            symtab.syms.push(ElfSym {
+                info: STT_SECTION,
+                shndx: idx as u16 + 1,
+                ..Default::default()
+            });
+        }
+
+        let (locals, globals) = self.named_symbols();
+        for (name, sym) in &locals {
+            symtab.add(
+                name,
+                elf_sym(sym, sym.value.map_or(0, |value| value.offset)),
+            );
+        }
+
+        // `%pcrel_lo` relocations refer to the `auipc` by a local symbol.
+        let mut pcrel_hi = HashMap::new();
+        for reloc in relocs.iter().flatten() {
+            if is_pcrel_lo(reloc.kind) && !pcrel_hi.contains_key(&reloc.target.value) {
+                let name = format!(".Lpcrel_hi{}", pcrel_hi.len());
+                let idx = symtab.add(
+                    &name,
+                    ElfSym {
+                        value: reloc.target.value.offset as u64,
+                        shndx: shndx(Some(reloc.target.value)),
+                        ..Default::default()
+                    },
+                );
+                pcrel_hi.insert(reloc.target.value, idx);
+            }
+        }
+
+        // The linker moves the code it relaxes along with the symbols in it,
+        // not offsets from sections, so references to the code are by local
+        // symbols.
+        let mut relaxed = HashMap::new();
+        for reloc in relocs.iter().flatten() {
+            let value = reloc.target.value;
+            let in_relaxed = value
+                .section
+                .and_then(|section| self.sections().get(section))
+                .is_some_and(|section| section.relaxed);
+            if in_relaxed
+                && reloc.target.sym.is_none()
+                && !is_pcrel_lo(reloc.kind)
+                && !relaxed.contains_key(&value)
+            {
+                let name = format!(".L{}", relaxed.len());
+                let idx = symtab.add(
+                    &name,
+                    ElfSym {
+                        value: value.offset as u64,
+                        shndx: shndx(Some(value)),
+                        ..Default::default()
+                    },
+                );
+                relaxed.insert(value, idx);
+            }
+        }
+
+        let first_global = symtab.syms.len() as u32;
+        for (name, sym) in &globals {
+            symtab.add(
+                name,
+                elf_sym(sym, sym.value.map_or(0, |value| value.offset)),
+            );
+        }
+
+        // Undefined symbols which are only referenced are external.
+        for reloc in relocs.iter().flatten() {
+            if let Some(name) = &reloc.target.sym {
+                if !symtab.index.contains_key(name) {
+                    symtab.add(
+                        name,
+                        ElfSym {
+                            info: sym_info(Binding::Global, SymbolKind::NoType),
+                            ..Default::default()
+                        },
+                    );
+                }
+            }
+        }
+
+        let symtab_idx = sections.len()
+            + relocs.iter().filter(|relocs| !relocs.is_empty()).count()
+            + 1;
+        for (idx, relocs) in relocs.iter().enumerate() {
+            if relocs.is_empty() {
+                continue;
+            }
+
+            let mut rela = Writer::new(class);
+            for reloc in relocs {
+                let target = &reloc.target;
+                let (sym, addend) = match (&target.sym, target.value.section) {
+                    _ if is_pcrel_lo(reloc.kind) => (pcrel_hi[&target.value], 0),
+                    (None, _) if relaxed.contains_key(&target.value) => {
+                        (relaxed[&target.value], 0)
+                    }
+                    (Some(name), _) => (symtab.index[name], target.value.offset),
+                    (None, section) => (
+                        section.map_or(0, |section| section as u32 + 1),
+                        target.value.offset,
+                    ),
+                };
+
+                rela.rela(reloc.offset as u64, sym, reloc.kind.elf_type(), addend);
+            }
+
+            sections.push(OutSection {
+                name: format!(".rela{}", sections[idx].name),
+                kind: SHT_RELA,
+                flags: SHF_INFO_LINK,
+                size: rela.buf.len() as u64,
+                data: rela.buf,
+                link: symtab_idx as u32,
+                info: idx as u32 + 1,
+                align: class.word_size(),
+                entsize: class.rela_size(),
+                ..Default::default()
+            });
+        }
+
+        sections.extend(symtab.sections(class, symtab_idx as u32 + 1, first_global));
+
+        let header = Header {
+            kind: ET_REL,
+            flags: config.flags(self.rvc()),
+            entry: 0,
+        };
+
+        Ok(write(class, header, sections, &[]))
+    }
+
+    /// Generates a statically linked executable. Sections are loaded at
+    /// their addresses, all references are resolved. The entry point is
+    /// the symbol `entry`, `_start` or the start of the code.
+    pub fn elf_executable(
+        &self,
+        config: &ElfConfig,
+        entry: Option<&str>,
+    ) -> Result<Vec<u8>, Error> {
+        let class = config.class;
+        let program_sections = self.sections();
+        let mut sections: Vec<OutSection> = self
+            .generate()?
+            .into_iter()
+            .map(|(section, data)| OutSection {
+                addr: if section.flags.alloc {
+                    section.addr as u64
+                } else {
+                    0
+                },
+                ..out_section(section, data)
+            })
+            .collect();
+        // `.eh_frame` would have to be loaded, debuggers read `.debug_frame`
+        // as well.
+        let frames = CfiSections {
+            eh_frame: false,
+            debug_frame: true,
+        };
+        for debug in self.debug_info(config, sections.len(), false, frames) {
+            sections.push(debug_section(class, debug));
+        }
+        sections.push(self.attributes_section(config));
+
+        let segments = segments(
+            program_sections
+                .iter()
+                .map(|section| (section.flags, section.addr, section.size())),
+        );
+
+        let mut symtab = SymTab::new();
+        let (locals, globals) = self.named_symbols();
+        for (name, sym) in &locals {
+            if let Some(value) = sym.value {
+                symtab.add(name, elf_sym(sym, self.addr(value)));
+            }
+        }
+        let first_global = symtab.syms.len() as u32;
+        for (name, sym) in &globals {
+            if let Some(value) = sym.value {
+                symtab.add(name, elf_sym(sym, self.addr(value)));
+            }
+        }
+        let strtab_idx = sections.len() as u32 + 2;
+        sections.extend(symtab.sections(class, strtab_idx, first_global));
+
+        let entry = match entry.or(self.symbol("_start").map(|_| "_start")) {
+            Some(name) => self
+                .symbol(name)
+                .and_then(|sym| sym.value)
+                .map(|value| self.addr(value))
+                .ok_or_else(|| AsmErrorKind::UnknownEntry(name.to_string()))?,
+            None => program_sections
+                .iter()
+                .find(|section| section.flags.alloc && section.flags.exec)
+                .map_or(0, |section| section.addr as i64),
+        };
+
+        let header = Header {
+            kind: ET_EXEC,
+            flags: config.flags(self.rvc()),
+            entry: entry as u64,
+        };
+
+        Ok(write(class, header, sections, &segments))
+    }
+
+    /// Line information, if asked for or given by the source, and the
+    /// frame descriptions in `frames`, numbered from `first` in the output.
+    fn debug_info(
+        &self,
+        config: &ElfConfig,
+        first: usize,
+        relocatable: bool,
+        frames: CfiSections,
+    ) -> Vec<DebugSection> {
+        let addr_size = config.class.word_size() as u8;
+        let mut sections = Vec::new();
+        if config.debug || self.line_info().has_locs() {
+            sections = self.debug_sections(addr_size, first, relocatable);
+        }
+        sections.extend(self.frame_sections(
+            addr_size,
+            first + sections.len(),
+            relocatable,
+            frames,
+        ));
+
+        sections
+    }
+
+    fn attributes_section(&self, config: &ElfConfig) -> OutSection {
+        let data = self.riscv_attributes(&config.arch(self.arch()));
+
+        OutSection {
+            name: ".riscv.attributes".into(),
+            kind: SHT_RISCV_ATTRIBUTES,
+            size: data.len() as u64,
+            data,
+            align: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Symbols written to the symbol table, locals first, in the order of
+    /// their definition. Assembler temporaries (`.L*`) are left out.
+    fn named_symbols(&self) -> (Named<'_>, Named<'_>) {
+        let mut named: Vec<(&str, &Symbol)> = self
+            .symbols()
+            .filter(|(name, sym)| {
+                sym.binding != Binding::Local || !name.starts_with(".L")
+            })
+            .collect();
+        named.sort_by_key(|(name, sym)| (sym.binding != Binding::Local, sym.line, *name));
+
+        named
+            .into_iter()
+            .partition(|(_, sym)| sym.binding == Binding::Local)
+    }
+}
+
+fn out_section(section: &Section, data: Vec<u8>) -> OutSection {
+    OutSection {
+        name: section.name.clone(),
+        kind: if section.flags.nobits {
+            SHT_NOBITS
+        } else {
+            SHT_PROGBITS
+        },
+        flags: section_flags(section.flags),
+        data,
+        size: section.size() as u64,
+        align: section.align as u64,
+        ..Default::default()
+    }
+}
+
+fn debug_section(class: ElfClass, section: DebugSection) -> OutSection {
+    OutSection {
+        name: section.name.into(),
+        kind: SHT_PROGBITS,
+        flags: if section.alloc { SHF_ALLOC } else { 0 },
+        size: section.data.len() as u64,
+        data: section.data,
+        align: if section.alloc { class.word_size() } else { 1 },
+        ..Default::default()
+    }
+}
+
+fn elf_sym(sym: &Symbol, value: i64) -> ElfSym {
+    ElfSym {
+        name: 0,
+        value: value as u64,
+        size: sym.size.unwrap_or_default(),
+        info: sym_info(sym.binding, sym.kind),
+        other: sym_other(sym.visibility),
+        shndx: shndx(sym.value),
+    }
+}
+
+fn is_pcrel_lo(kind: RelocKind) -> bool {
+    matches!(kind, RelocKind::PcrelLo12I | RelocKind::PcrelLo12S)
+}
+
+/// Fields of the file header which depend on the kind of output.
+struct Header {
+    kind: u16,
+    flags: u32,
+    entry: u64,
+}
+
+/// Loadable segment, made of consecutive sections with the same
+/// permissions.
+struct Segment {
+    flags: u32,
+    /// Indices of the sections in the output.
+    sections: Vec<usize>,
+}
+
+/// Loadable segments of sections given by their flags, address and size.
+/// Sections share a segment if they have the same permissions and only
+/// alignment padding between them. Data can't follow NoBits sections.
+fn segments(sections: impl Iterator<Item = (SectionFlags, u32, u32)>) -> Vec<Segment> {
+    let mut order: Vec<(usize, SectionFlags, u64, u64)> = sections
+        .enumerate()
+        .filter(|(_, (flags, _, size))| flags.alloc && *size > 0)
+        .map(|(idx, (flags, addr, size))| (idx, flags, addr as u64, size as u64))
+        .collect();
+    order.sort_by_key(|&(_, _, addr, _)| addr);
+
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut end = 0;
+    let mut nobits = false;
+    for (idx, section, addr, size) in order {
+        let mut flags = PF_R;
+        if section.write {
+            flags |= PF_W;
+        }
+        if section.exec {
+            flags |= PF_X;
+        }
+
+        match segments.last_mut() {
+            Some(segment)
+                if segment.flags == flags
+                    && (end..end + PAGE).contains(&addr)
+                    && (section.nobits || !nobits) =>
+            {
+                segment.sections.push(idx)
+            }
+            _ => segments.push(Segment {
+                flags,
+                sections: vec![idx],
+            }),
+        }
+        end = addr + size;
+        nobits = section.nobits;
+    }
+
+    segments
+}
+
+/// Lays out the file: header, program headers, contents of the sections
+/// (loaded ones at offsets congruent to their addresses modulo the page
+/// size), the section name table and the section headers.
+fn write(
+    class: ElfClass,
+    header: Header,
+    mut sections: Vec<OutSection>,
+    segments: &[Segment],
+) -> Vec<u8> {
+    let mut shstrtab = StrTab::new();
+    let names: Vec<u32> = sections
+        .iter()
+        .map(|section| shstrtab.add(&section.name))
+        .collect();
+    let shstrndx = sections.len() as u16 + 1;
+    let name = shstrtab.add(".shstrtab");
+    sections.push(OutSection {
+        name: ".shstrtab".into(),
+        kind: SHT_STRTAB,
+        size: shstrtab.0.len() as u64,
+        data: shstrtab.0,
+        align: 1,
+        ..Default::default()
+    });
+
+    let phoff = class.ehdr_size() as u64;
+    let mut out = Writer::new(class);
+    out.buf.resize(
+        (phoff + segments.len() as u64 * class.phdr_size() as u64) as usize,
+        0,
+    );
+
+    let mut offsets = vec![None; sections.len()];
+    let mut phdrs = Writer::new(class);
+    for segment in segments {
+        let first = &sections[segment.sections[0]];
+        let pos = out.buf.len() as u64;
+        let offset = pos + (first.addr % PAGE + PAGE - pos % PAGE) % PAGE;
+
+        let (mut filesz, mut memsz) = (0, 0);
+        for &idx in &segment.sections {
+            let section = &sections[idx];
+            let start = section.addr - first.addr;
+            offsets[idx] = Some(offset + start);
+            if section.kind != SHT_NOBITS {
+                out.buf.resize((offset + start) as usize, 0);
+                out.buf.extend_from_slice(&section.data);
+                filesz = start + section.size;
+            }
+            memsz = start + section.size;
+        }
+
+        phdrs.phdr(segment.flags, offset, first.addr, filesz, memsz);
+    }
+
+    let offsets: Vec<u64> = sections
+        .iter()
+        .zip(offsets)
+        .map(|(section, offset)| {
+            offset.unwrap_or_else(|| {
+                out.align(section.align);
+                let offset = out.buf.len() as u64;
+                out.buf.extend_from_slice(&section.data);
+                offset
+            })
+        })
+        .collect();
+
+    out.align(class.word_size());
+    let shoff = out.buf.len() as u64;
+    out.buf
+        .resize(out.buf.len() + class.shdr_size() as usize, 0);
+    for ((section, offset), name) in sections
+        .iter()
+        .zip(offsets)
+        .zip(names.into_iter().chain([name]))
+    {
+        out.u32(name);
+        out.u32(section.kind);
+        out.word(section.flags);
+        out.word(section.addr);
+        out.word(offset);
+        out.word(section.size);
+        out.u32(section.link);
+        out.u32(section.info);
+        out.word(section.align);
+        out.word(section.entsize);
+    }
+
+    let mut ehdr = Writer::new(class);
+    ehdr.buf.extend(b"\x7fELF");
+    ehdr.u8(match class {
+        ElfClass::Elf32 => 1,
+        ElfClass::Elf64 => 2,
+    });
+    // Little-endian, version 1, System V ABI.
+    ehdr.buf.extend([1, 1, 0]);
+    ehdr.buf.resize(16, 0);
+    ehdr.u16(header.kind);
+    ehdr.u16(EM_RISCV);
+    ehdr.u32(1);
+    ehdr.word(header.entry);
+    ehdr.word(if segments.is_empty() { 0 } else { phoff });
+    ehdr.word(shoff);
+    ehdr.u32(header.flags);
+    ehdr.u16(class.ehdr_size());
+    ehdr.u16(if segments.is_empty() {
+        0
+    } else {
+        class.phdr_size()
+    });
+    ehdr.u16(segments.len() as u16);
+    ehdr.u16(class.shdr_size());
+    ehdr.u16(sections.len() as u16 + 1);
+    ehdr.u16(shstrndx);
+    ehdr.buf.extend(phdrs.buf);
+
+    out.buf[..ehdr.buf.len()].copy_from_slice(&ehdr.buf);
+    out.buf
+}
+
+impl Linked {
+    pub fn elf_executable(
+        &self,
+        config: &ElfConfig,
+        entry: Option<&str>,
+    ) -> Result<Vec<u8>, Error> {
+        let class = config.class;
+        let mut sections: Vec<OutSection> = self
+            .sections
+            .iter()
+            .map(|section| OutSection {
+                name: section.name.clone(),
+                kind: if section.flags.nobits {
+                    SHT_NOBITS
+                } else {
+                    SHT_PROGBITS
+                },
+                flags: section_flags(section.flags),
+                addr: section.addr as u64,
+                data: section.data.clone(),
+                size: section.size as u64,
+                align: section.align as u64,
+                ..Default::default()
+            })
+            .collect();
+
+        let segments = segments(
+            self.sections
+                .iter()
+                .map(|section| (section.flags, section.addr, section.size)),
+        );
+
+        let mut symtab = SymTab::new();
+        let mut first_global = None;
+        for sym in &self.symbols {
+            if sym.binding != Binding::Local && first_global.is_none() {
+                first_global = Some(symtab.syms.len() as u32);
+            }
+            symtab.add(
+                &sym.name,
+                ElfSym {
+                    name: 0,
+                    value: sym.value,
+                    size: sym.size,
+                    info: sym_info(sym.binding, sym.kind),
+                    other: 0,
+                    shndx: sym.section.map_or(SHN_ABS, |section| section as u16 + 1),
+                },
+            );
+        }
+        let first_global = first_global.unwrap_or(symtab.syms.len() as u32);
+        let strtab_idx = sections.len() as u32 + 2;
+        sections.extend(symtab.sections(class, strtab_idx, first_global));
+
+        let entry = match entry.or(self.symbol("_start").map(|_| "_start")) {
+            Some(name) => self
+                .symbol(name)
+                .map(|sym| sym.value)
+                .ok_or_else(|| AsmErrorKind::UnknownEntry(name.to_string()))?,
+            None => self
+                .sections
+                .iter()
+                .find(|section| section.flags.exec)
+                .map_or(0, |section| section.addr as u64),
+        };
+
+        let header = Header {
+            kind: ET_EXEC,
+            flags: config.flags(self.rvc),
+            entry,
+        };
+
+        Ok(write(class, header, sections, &segments))
+    }
+}
+
+/// Reads a relocatable object file for the linker. Allocated sections are
+/// kept along with their relocations, the others are dropped.
+pub fn read_object(name: &str, data: &[u8]) -> Result<Object, Error> {
+    let object = match data.get(..6) {
+        Some([0x7f, b'E', b'L', b'F', 1, 1]) => Some(ElfClass::Elf32),
+        Some([0x7f, b'E', b'L', b'F', 2, 1]) => Some(ElfClass::Elf64),
+        _ => None,
+    }
+    .and_then(|class| Reader { class, data }.object(name));
+
+    object.ok_or_else(|| AsmErrorKind::InvalidObject(name.into()).into())
+}
+
+/// Little-endian reader of fields whose size depends on the class, `None`
+/// past the end of the data.
+struct Reader<'d> {
+    class: ElfClass,
+    data: &'d [u8],
+}
+
+struct SectionHeader {
+    name: u32,
+    kind: u32,
+    flags: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+}
+
+impl Reader<'_> {
+    fn bytes(&self, pos: u64, len: u64) -> Option<&[u8]> {
+        self.data.get(
+            usize::try_from(pos).ok()?..usize::try_from(pos.checked_add(len)?).ok()?,
+        )
+    }
+
+    fn u8(&self, pos: u64) -> Option<u8> {
+        Some(self.bytes(pos, 1)?[0])
+    }
+
+    fn u16(&self, pos: u64) -> Option<u16> {
+        Some(u16::from_le_bytes(self.bytes(pos, 2)?.try_into().ok()?))
+    }
+
+    fn u32(&self, pos: u64) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(pos, 4)?.try_into().ok()?))
+    }
+
+    fn u64(&self, pos: u64) -> Option<u64> {
+        Some(u64::from_le_bytes(self.bytes(pos, 8)?.try_into().ok()?))
+    }
+
+    /// Address or offset, 4 or 8 bytes.
+    fn word(&self, pos: u64) -> Option<u64> {
+        match self.class {
+            ElfClass::Elf32 => self.u32(pos).map(u64::from),
+            ElfClass::Elf64 => self.u64(pos),
+        }
+    }
+
+    fn str(&self, pos: u64) -> Option<String> {
+        let rest = self.data.get(usize::try_from(pos).ok()?..)?;
+        let len = rest.iter().position(|&b| b == 0)?;
+        String::from_utf8(rest[..len].to_vec()).ok()
+    }
+
+    fn section_header(&self, pos: u64) -> Option<SectionHeader> {
+        let w = self.class.word_size();
+        Some(SectionHeader {
+            name: self.u32(pos)?,
+            kind: self.u32(pos + 4)?,
+            flags: self.word(pos + 8)?,
+            offset: self.word(pos + 8 + 2 * w)?,
+            size: self.word(pos + 8 + 3 * w)?,
+            link: self.u32(pos + 8 + 4 * w)?,
+            info: self.u32(pos + 12 + 4 * w)?,
+            align: self.word(pos + 16 + 4 * w)?,
+        })
+    }
+
+    fn object(&self, name: &str) -> Option<Object> {
+        let elf64 = self.class == ElfClass::Elf64;
+        let (kind, machine) = (self.u16(16)?, self.u16(18)?);
+        if kind != ET_REL || machine != EM_RISCV {
+            return None;
+        }
+        let (flags, shoff, shentsize, shnum) = if elf64 {
+            (
+                self.u32(0x30)?,
+                self.u64(0x28)?,
+                self.u16(0x3a)?,
+                self.u16(0x3c)?,
+            )
+        } else {
+            (
+                self.u32(0x24)?,
+                self.u32(0x20)? as u64,
+                self.u16(0x2e)?,
+                self.u16(0x30)?,
+            )
+        };
+        let shstrndx = self.u16(if elf64 { 0x3e } else { 0x32 })?;
+
+        let headers = (0..shnum as u64)
+            .map(|idx| self.section_header(shoff + idx * shentsize as u64))
+            .collect::<Option<Vec<_>>>()?;
+        let shstrtab = headers.get(shstrndx as usize)?.offset;
+
+        // Allocated sections by index of their header.
+        let mut kept = vec![None; headers.len()];
+        let mut sections = Vec::new();
+        for (idx, header) in headers.iter().enumerate() {
+            let is_data = matches!(
+                header.kind,
+                SHT_PROGBITS
+                    | SHT_NOBITS
+                    | SHT_INIT_ARRAY
+                    | SHT_FINI_ARRAY
+                    | SHT_PREINIT_ARRAY
+            );
+            if !is_data || header.flags & SHF_ALLOC == 0 {
+                continue;
+            }
+
+            let nobits = header.kind == SHT_NOBITS;
+            kept[idx] = Some(sections.len());
+            sections.push(ObjSection {
+                name: self.str(shstrtab + header.name as u64)?,
+                flags: SectionFlags {
+                    alloc: true,
+                    write: header.flags & SHF_WRITE != 0,
+                    exec: header.flags & SHF_EXECINSTR != 0,
+                    nobits,
+                },
+                align: u32::try_from(header.align.max(1)).ok()?,
+                data: if nobits {
+                    Vec::new()
+                } else {
+                    self.bytes(header.offset, header.size)?.to_vec()
+                },
+                size: u32::try_from(header.size).ok()?,
+                relocs: Vec::new(),
+            });
+        }
+
+        let mut symbols = vec![ObjSymbol::null()];
+        if let Some(symtab) = headers.iter().find(|header| header.kind == SHT_SYMTAB) {
+            let strtab = headers.get(symtab.link as usize)?.offset;
+            let size = self.class.sym_size();
+            for idx in 1..symtab.size / size {
+                let pos = symtab.offset + idx * size;
+                let (value, size, info, shndx) = if elf64 {
+                    (
+                        self.u64(pos + 8)?,
+                        self.u64(pos + 16)?,
+                        self.u8(pos + 4)?,
+                        self.u16(pos + 6)?,
+                    )
+                } else {
+                    (
+                        self.u32(pos + 4)? as u64,
+                        self.u32(pos + 8)? as u64,
+                        self.u8(pos + 12)?,
+                        self.u16(pos + 14)?,
+                    )
+                };
+
+                symbols.push(ObjSymbol {
+                    name: self.str(strtab + self.u32(pos)? as u64)?,
+                    binding: match info >> 4 {
+                        STB_LOCAL => Binding::Local,
+                        STB_WEAK => Binding::Weak,
+                        _ => Binding::Global,
+                    },
+                    kind: match info & 0xf {
+                        STT_FUNC => SymbolKind::Func,
+                        STT_OBJECT => SymbolKind::Object,
+                        _ => SymbolKind::NoType,
+                    },
+                    size,
+                    def: match shndx {
+                        SHN_UNDEF => SymDef::Undefined,
+                        SHN_ABS => SymDef::Abs(value),
+                        SHN_COMMON => return None,
+                        shndx => match kept.get(shndx as usize)? {
+                            Some(section) => SymDef::Section(*section, value),
+                            None => SymDef::Undefined,
+                        },
+                    },
+                });
+            }
+        }
+
+        for header in &headers {
+            if header.kind == SHT_REL {
+                return None;
+            }
+            if header.kind != SHT_RELA {
+                continue;
+            }
+            let Some(section) = *kept.get(header.info as usize)? else {
+                continue;
+            };
+
+            let size = self.class.rela_size();
+            for idx in 0..header.size / size {
+                let pos = header.offset + idx * size;
+                let (offset, info, addend) = (
+                    self.word(pos)?,
+                    self.word(pos + self.class.word_size())?,
+                    self.word(pos + 2 * self.class.word_size())?,
+                );
+                let (sym, kind, addend) = if elf64 {
+                    (info >> 32, info as u32, addend as i64)
+                } else {
+                    (info >> 8, info as u32 & 0xff, addend as u32 as i32 as i64)
+                };
+                if sym as usize >= symbols.len() {
+                    return None;
+                }
+
+                sections[section].relocs.push(ObjReloc {
+                    offset: u32::try_from(offset).ok()?,
+                    kind,
+                    sym: sym as usize,
+                    addend,
+                });
+            }
+        }
+
+        Some(Object {
+            name: name.to_string(),
+            sections,
+            symbols,
+            rvc: flags & 1 != 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::program::Config;
+
+    #[test]
+    fn relocations() {
+        let src = "
+  call ext
+1:
+  auipc a0, %pcrel_hi(ext)
+  addi a0, a0, %pcrel_lo(1b)
+  lui a1, %hi(ext + 8)
+  addi a1, a1, %lo(ext + 8)
+  jal ra, ext
+.data
+  .word ext + 4
+";
+        let program = Program::parse(src).unwrap();
+        let elf = program.elf_object(&ElfConfig::default()).unwrap();
+        let object = read_object("t.o", &elf).unwrap();
+
+        let relocs = |name: &str| -> Vec<(u32, u32, &str, i64)> {
+            let section = object.sections.iter().find(|s| s.name == name).unwrap();
+            section
+                .relocs
+                .iter()
+                .map(|reloc| {
+                    let sym = object.symbols[reloc.sym].name.as_str();
+                    (reloc.offset, reloc.kind, sym, reloc.addend)
+                })
+                .collect()
+        };
+        assert_eq!(
+            relocs(".text"),
+            [
+                (0x00, 18, "ext", 0),
+                (0x08, 23, "ext", 0),
+                (0x0c, 24, ".Lpcrel_hi0", 0),
+                (0x10, 26, "ext", 8),
+                (0x14, 27, "ext", 8),
+                (0x18, 17, "ext", 0),
+            ]
+        );
+        assert_eq!(relocs(".data"), [(0, 1, "ext", 4)]);
+
+        let text = &object.sections[0];
+        assert_eq!(text.name, ".text");
+        assert_eq!(
+            text.data,
+            [
+                0x97, 0x00, 0x00, 0x00, 0xe7, 0x80, 0x00, 0x00, 0x17, 0x05, 0x00, 0x00,
+                0x13, 0x05, 0x05, 0x00, 0xb7, 0x05, 0x00, 0x00, 0x93, 0x85, 0x05, 0x00,
+                0xef, 0x00, 0x00, 0x00,
+            ]
+        );
+    }
+
+    #[test]
+    fn local_branches_resolved() {
+        let src = "
+start:
+  beq a0, a1, start
+  jal ra, start
+";
+        let program = Program::parse(src).unwrap();
+        let elf = program.elf_object(&ElfConfig::default()).unwrap();
+        let object = read_object("t.o", &elf).unwrap();
+
+        assert!(object.sections[0].relocs.is_empty());
+        assert_eq!(
+            object.sections[0].data,
+            [0x63, 0x00, 0xb5, 0x00, 0xef, 0xf0, 0xdf, 0xff]
+        );
+    }
+
+    #[test]
+    fn relaxed() {
+        let src = "
+.option relax
+start:
+  call ext
+  .balign 16
+end:
+  jalr zero, ra, 0
+.data
+  .word end - start
+";
+        let program = Program::parse(src).unwrap();
+        let elf = program.elf_object(&ElfConfig::default()).unwrap();
+        let object = read_object("t.o", &elf).unwrap();
+
+        let relocs = |idx: usize| -> Vec<(u32, u32, SymDef, i64)> {
+            object.sections[idx]
+                .relocs
+                .iter()
+                .map(|reloc| {
+                    let def = object.symbols[reloc.sym].def;
+                    (reloc.offset, reloc.kind, def, reloc.addend)
+                })
+                .collect()
+        };
+        // Padding for the worst case, trimmed by the linker.
+        assert_eq!(
+            relocs(0),
+            [
+                (0, 18, SymDef::Undefined, 0),
+                (0, 51, SymDef::Undefined, 0),
+                (8, 43, SymDef::Undefined, 12),
+            ]
+        );
+        assert_eq!(object.sections[0].size, 24);
+        // The difference is left to the linker, by symbols moving with the
+        // code.
+        assert_eq!(
+            relocs(1),
+            [
+                (0, 35, SymDef::Section(0, 20), 0),
+                (0, 39, SymDef::Section(0, 0), 0),
+            ]
+        );
+        assert_eq!(object.sections[1].data, [0; 4]);
+    }
+
+    #[test]
+    fn arch() {
+        let src = ".option arch, +zbb, +m\n  addi a0, a0, 1";
+        let program = Program::parse(src).unwrap();
+        let config = ElfConfig::default();
+        assert_eq!(config.arch(program.arch()), "rv32i2p1_m2p0_zbb1p0");
+
+        let config = ElfConfig {
+            class: ElfClass::Elf64,
+            float_abi: FloatAbi::Double,
+            ..config
+        };
+        let program = Program::parse(".option rvc").unwrap();
+        assert_eq!(
+            config.arch(program.arch()),
+            "rv64i2p1_f2p2_d2p2_c2p0_zicsr2p0"
+        );
+    }
+
+    #[test]
+    fn segment_flags() {
+        let src = "
+  addi a0, a0, 1
+.section .rodata
+  .word 1
+.data
+  .word 2
+.bss
+  .zero 4
+";
+        let config = Config {
+            base: 0x1_0000,
+            paged: true,
+            ..Default::default()
+        };
+        let program = Program::parse_with_config(src, config).unwrap();
+        let elf = program.elf_executable(&ElfConfig::default(), None).unwrap();
+
+        let u32_at = |at: usize| u32::from_le_bytes(elf[at..at + 4].try_into().unwrap());
+        let phoff = u32_at(0x1c) as usize;
+        let phnum = u16::from_le_bytes([elf[0x2c], elf[0x2d]]) as usize;
+        // Address, sizes in the file and memory, and flags of each segment.
+        let segments: Vec<_> = (0..phnum)
+            .map(|idx| phoff + idx * 32)
+            .map(|at| {
+                (
+                    u32_at(at + 8),
+                    u32_at(at + 16),
+                    u32_at(at + 20),
+                    u32_at(at + 24),
+                )
+            })
+            .collect();
+        assert_eq!(
+            segments,
+            [
+                (0x1_0000, 4, 4, PF_R | PF_X),
+                (0x1_1000, 4, 4, PF_R),
+                (0x1_2000, 4, 8, PF_R | PF_W),
+            ]
+        );
+    }
+}
@@ -1,3 +1,8 @@
+use std::{
+    fmt::{self, Display},
+    io,
+};
+
 use nom::error::{ErrorKind, FromExternalError, ParseError};
 use nom::IResult as NomResult;
 
@@ -18,32 +23,77 @@ impl<'i> AsmError<'i> {
             kind,
         }
     }
+}
 
-    pub fn to_str(&self, input: &str) -> String {
-        let line_num = self.span.line();
-        let column_num = self.span.col();
-
-        let line_begin = input[..self.span.byte_offset()]
-            .rfind('\n')
-            .map(|pos| pos + 1)
-            .unwrap_or(0);
-        let line = input[line_begin..]
-            .lines()
-            .next()
-            .unwrap_or(&input[line_begin..])
-            .trim_end();
+#[derive(Debug, Clone)]
+pub struct Location {
+    pub file: String,
+    pub line: usize,
+    pub col: usize,
+    pub text: String,
+}
 
+impl Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self {
+            file,
+            line,
+            col,
+            text,
+        } = self;
         let caret = '^';
-        let kind = &self.kind;
 
-        format!(
-            "{kind} at line {line_num}:\n\
-             {line}\n\
-             {caret:>column_num$}\n"
+        write!(
+            f,
+            "at {file}:{line}:\n\
+             {text}\n\
+             {caret:>col$}\n"
         )
     }
 }
 
+#[derive(Debug)]
+pub struct Error {
+    pub kind: AsmErrorKind,
+    pub loc: Option<Location>,
+    /// Related locations, e.g. the previous definition of a duplicate
+    /// symbol or the line including the file.
+    pub notes: Vec<(&'static str, Location)>,
+}
+
+impl Error {
+    pub fn with_note(mut self, note: &'static str, loc: Location) -> Self {
+        self.notes.push((note, loc));
+        self
+    }
+}
+
+impl From<AsmErrorKind> for Error {
+    fn from(kind: AsmErrorKind) -> Self {
+        Self {
+            kind,
+            loc: None,
+            notes: Vec::new(),
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.loc {
+            Some(loc) => write!(f, "{} {loc}", self.kind)?,
+            None => writeln!(f, "{}", self.kind)?,
+        }
+        for (note, loc) in &self.notes {
+            write!(f, "{note} {loc}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {}
+
 #[derive(Debug, thiserror::Error)]
 pub enum AsmErrorKind {
     #[error("Nom '{}'", ErrorKind::description(.0))]
@@ -56,10 +106,90 @@ pub enum AsmErrorKind {
     InvalidReg,
     #[error("Invalid Imm")]
     InvalidImm,
+    #[error("Invalid Expr")]
+    InvalidExpr,
+    #[error("Expr is not absolute")]
+    NotAbsolute,
+    #[error("Imm out of range")]
+    ImmOutOfRange,
+    #[error("Invalid String")]
+    InvalidString,
+    #[error("Invalid Directive")]
+    InvalidDirective,
+    #[error("Invalid Section flags")]
+    InvalidSectionFlags,
+    #[error("Invalid Symbol type")]
+    InvalidSymbolType,
+    #[error("Invalid Attribute")]
+    InvalidAttribute,
+    #[error("Invalid Option")]
+    InvalidOption,
     #[error("Invalid Pseudo instr")]
     InvalidPseudo,
     #[error("Unknown Sym")]
     UnknownSym,
+    #[error("Unknown Local label")]
+    UnknownLocal,
+    #[error("Duplicate Sym")]
+    DuplicateSym,
+    #[error("Sym defined in terms of itself")]
+    CircularSym,
+    #[error("Sym shadows Reg name")]
+    SymIsReg,
+    #[error("Sym shadows OpCode name")]
+    SymIsOpCode,
+    #[error("No %pcrel_hi at label")]
+    NoPcrelHi,
+    #[error("Data in NoBits section")]
+    NoBitsData,
+    #[error("Org moves location counter backwards")]
+    OrgBackwards,
+    #[error("Address space overflowed, ending at {0:#x}")]
+    AddressOverflow(u64),
+    #[error("File number {0} not given by '.file'")]
+    UnknownFile(u32),
+    #[error("CFI offset not a multiple of {0}")]
+    CfiOffset(u32),
+    #[error("Unknown entry Sym '{0}'")]
+    UnknownEntry(String),
+    #[error("Invalid object file '{0}'")]
+    InvalidObject(Box<str>),
+    #[error("Undefined Sym '{}' referenced in '{}'", .0 .0, .0 .1)]
+    UndefinedSym(Box<(String, String)>),
+    #[error("Sym '{}' defined in both '{}' and '{}'", .0 .0, .0 .1, .0 .2)]
+    MultipleDefinition(Box<(String, String, String)>),
+    #[error("Unsupported relocation {0} in '{1}'")]
+    UnsupportedReloc(u32, Box<str>),
+    #[error("Relocation out of range at {0}")]
+    RelocOverflow(Box<str>),
+    #[error("Region '{0}' overflowed by {1} bytes")]
+    RegionOverflow(Box<str>, u32),
+    #[error("Invalid word width {0}")]
+    InvalidWordWidth(u32),
+    #[error("Image does not fit {0} words")]
+    DepthExceeded(u32),
+    #[error("File not found '{0}'")]
+    FileNotFound(String),
+    #[error("Cannot read '{0}': {1}")]
+    Io(String, io::Error),
+    #[error("Include cycle")]
+    IncludeCycle,
+    #[error("Duplicate Macro")]
+    DuplicateMacro,
+    #[error("Missing Macro arg '{0}'")]
+    MissingMacroArg(String),
+    #[error("Too many Macro args")]
+    TooManyMacroArgs,
+    #[error("Macro nesting too deep")]
+    MacroDepth,
+    #[error("Macro expansion too large")]
+    ExpansionSize,
+    #[error("Block without '.{0}'")]
+    Unterminated(&'static str),
+    #[error("Directive outside of its block")]
+    Misplaced,
+    #[error("Invalid define name '{0}'")]
+    InvalidDefine(String),
 }
 
 impl<'i> ParseError<Span<'i>> for AsmError<'i> {
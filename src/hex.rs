@@ -0,0 +1,197 @@
+use std::fmt::Write;
+
+use crate::{error::Error, image::Image};
+
+/// Data bytes per record.
+const RECORD_LEN: usize = 16;
+
+/// Intel HEX record types.
+const IHEX_DATA: u8 = 0x00;
+const IHEX_EOF: u8 = 0x01;
+const IHEX_EXT_LINEAR: u8 = 0x04;
+const IHEX_START_LINEAR: u8 = 0x05;
+
+impl Image {
+    /// Intel HEX text. Addresses above 64 KiB are set by extended linear
+    /// address records, the entry point by a start linear address record.
+    pub fn to_ihex(&self) -> Result<String, Error> {
+        let mut out = String::new();
+        let mut upper = 0;
+
+        for chunk in &self.chunks {
+            chunk.end()?;
+            let mut addr = chunk.addr;
+            let mut data = &chunk.data[..];
+
+            while !data.is_empty() {
+                if addr >> 16 != upper {
+                    upper = addr >> 16;
+                    ihex_record(
+                        &mut out,
+                        0,
+                        IHEX_EXT_LINEAR,
+                        &(upper as u16).to_be_bytes(),
+                    );
+                }
+
+                // Records don't cross 64 KiB boundaries.
+                let len = RECORD_LEN
+                    .min(data.len())
+                    .min(0x1_0000 - (addr & 0xffff) as usize);
+                ihex_record(&mut out, addr as u16, IHEX_DATA, &data[..len]);
+
+                addr += len as u32;
+                data = &data[len..];
+            }
+        }
+
+        if let Some(entry) = self.entry {
+            ihex_record(&mut out, 0, IHEX_START_LINEAR, &entry.to_be_bytes());
+        }
+        ihex_record(&mut out, 0, IHEX_EOF, &[]);
+
+        Ok(out)
+    }
+
+    /// Motorola S-record text. The address size (S1/S2/S3 and the
+    /// terminator S9/S8/S7) is the smallest one fitting all addresses.
+    pub fn to_srec(&self) -> Result<String, Error> {
+        let max = self
+            .end()?
+            .unwrap_or_default()
+            .saturating_sub(1)
+            .max(self.entry.unwrap_or_default());
+        let (data_kind, end_kind, addr_len) = match max {
+            0..=0xffff => (1, 9, 2),
+            0x1_0000..=0xff_ffff => (2, 8, 3),
+            _ => (3, 7, 4),
+        };
+
+        let mut out = String::new();
+        srec_record(&mut out, 0, 0, 2, &[]);
+
+        let mut count = 0u32;
+        for chunk in &self.chunks {
+            for (idx, data) in chunk.data.chunks(RECORD_LEN).enumerate() {
+                let addr = chunk.addr + (idx * RECORD_LEN) as u32;
+                srec_record(&mut out, data_kind, addr, addr_len, data);
+                count += 1;
+            }
+        }
+
+        match count {
+            0..=0xffff => srec_record(&mut out, 5, count, 2, &[]),
+            0x1_0000..=0xff_ffff => srec_record(&mut out, 6, count, 3, &[]),
+            _ => {}
+        }
+        srec_record(
+            &mut out,
+            end_kind,
+            self.entry.unwrap_or_default(),
+            addr_len,
+            &[],
+        );
+
+        Ok(out)
+    }
+}
+
+fn ihex_record(out: &mut String, addr: u16, kind: u8, data: &[u8]) {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend(addr.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend(data);
+
+    let sum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    bytes.push(sum.wrapping_neg());
+
+    out.push(':');
+    push_hex(out, &bytes);
+}
+
+fn srec_record(out: &mut String, kind: u8, addr: u32, addr_len: usize, data: &[u8]) {
+    let mut bytes = vec![(addr_len + data.len() + 1) as u8];
+    bytes.extend(&addr.to_be_bytes()[4 - addr_len..]);
+    bytes.extend(data);
+
+    let sum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    bytes.push(!sum);
+
+    write!(out, "S{kind}").unwrap();
+    push_hex(out, &bytes);
+}
+
+fn push_hex(out: &mut String, bytes: &[u8]) {
+    for byte in bytes {
+        write!(out, "{byte:02X}").unwrap();
+    }
+    out.push('\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::program::{Config, Program};
+
+    const SRC: &str = "
+_start:
+  addi a0, a1, -1
+  jal ra, _start
+.data
+  .word 0x12345678
+";
+
+    fn image(base: u32) -> crate::image::Image {
+        let config = Config {
+            base,
+            ..Default::default()
+        };
+        Program::parse_with_config(SRC, config)
+            .unwrap()
+            .image()
+            .unwrap()
+    }
+
+    #[test]
+    fn ihex() {
+        assert_eq!(
+            image(0).to_ihex().unwrap(),
+            ":080000001385F5FFEFF0DFFFAF\n\
+             :0400080078563412E0\n\
+             :0400000500000000F7\n\
+             :00000001FF\n"
+        );
+    }
+
+    #[test]
+    fn ihex_extended_address() {
+        assert_eq!(
+            image(0x1fff8).to_ihex().unwrap(),
+            ":020000040001F9\n\
+             :08FFF8001385F5FFEFF0DFFFB8\n\
+             :020000040002F8\n\
+             :0400000078563412E8\n\
+             :040000050001FFF8FF\n\
+             :00000001FF\n"
+        );
+    }
+
+    #[test]
+    fn srec() {
+        assert_eq!(
+            image(0).to_srec().unwrap(),
+            "S0030000FC\n\
+             S10B00001385F5FFEFF0DFFFAB\n\
+             S107000878563412DC\n\
+             S5030002FA\n\
+             S9030000FC\n"
+        );
+        assert_eq!(
+            image(0x1fff8).to_srec().unwrap(),
+            "S0030000FC\n\
+             S20C01FFF81385F5FFEFF0DFFFB2\n\
+             S20802000078563412E1\n\
+             S5030002FA\n\
+             S80401FFF803\n"
+        );
+    }
+}
@@ -0,0 +1,130 @@
+use crate::{
+    error::{AsmErrorKind, Error},
+    program::Program,
+};
+
+/// Memory contents of a program: the bytes of every loaded section at its
+/// address, in little-endian order.
+#[derive(Debug, Clone, Default)]
+pub struct Image {
+    /// Chunks in the order of their addresses.
+    pub chunks: Vec<Chunk>,
+    /// Address of `_start`, if defined.
+    pub entry: Option<u32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Chunk {
+    pub addr: u32,
+    pub data: Vec<u8>,
+}
+
+impl Chunk {
+    pub fn end(&self) -> Result<u32, Error> {
+        u32::try_from(self.data.len())
+            .ok()
+            .and_then(|len| self.addr.checked_add(len))
+            .ok_or_else(|| {
+                let end = self.addr as u64 + self.data.len() as u64;
+                AsmErrorKind::AddressOverflow(end).into()
+            })
+    }
+}
+
+impl Image {
+    pub fn start(&self) -> Option<u32> {
+        self.chunks.first().map(|chunk| chunk.addr)
+    }
+
+    /// Address past the last byte with contents.
+    pub fn end(&self) -> Result<Option<u32>, Error> {
+        self.chunks
+            .iter()
+            .try_fold(None, |end, chunk| Ok(end.max(Some(chunk.end()?))))
+    }
+
+    /// Flat image from the lowest address up to the end, gaps between chunks
+    /// filled with `fill`.
+    pub fn flat(&self, fill: u8) -> Result<Vec<u8>, Error> {
+        let (Some(start), Some(end)) = (self.start(), self.end()?) else {
+            return Ok(Vec::new());
+        };
+
+        let mut buf = vec![fill; (end - start) as usize];
+        for chunk in &self.chunks {
+            let offset = (chunk.addr - start) as usize;
+            buf[offset..offset + chunk.data.len()].copy_from_slice(&chunk.data);
+        }
+
+        Ok(buf)
+    }
+}
+
+impl Program {
+    /// Image of the allocated sections. NoBits sections are left out, they
+    /// are zeroed at run time.
+    pub fn image(&self) -> Result<Image, Error> {
+        let mut chunks: Vec<Chunk> = self
+            .generate()?
+            .into_iter()
+            .filter(|(section, data)| section.flags.alloc && !data.is_empty())
+            .map(|(section, data)| Chunk {
+                addr: section.addr,
+                data,
+            })
+            .collect();
+        chunks.sort_by_key(|chunk| chunk.addr);
+
+        let entry = self
+            .symbol("_start")
+            .and_then(|sym| sym.value)
+            .map(|value| self.addr(value) as u32);
+
+        Ok(Image { chunks, entry })
+    }
+
+    /// Flat binary starting at the lowest loaded address, as written to
+    /// flash or loaded by a simulator.
+    pub fn flat_binary(&self, fill: u8) -> Result<Vec<u8>, Error> {
+        self.image()?.flat(fill)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::program::Config;
+
+    #[test]
+    fn flat_binary() {
+        let src = "
+_start:
+  addi a0, a0, 1
+.data
+  .balign 8
+  .word 0x12345678
+.bss
+  .zero 16
+";
+        let config = Config {
+            base: 0x100,
+            ..Default::default()
+        };
+        let program = Program::parse_with_config(src, config).unwrap();
+
+        let image = program.image().unwrap();
+        assert_eq!(image.start(), Some(0x100));
+        assert_eq!(image.end().unwrap(), Some(0x10c));
+        assert_eq!(image.entry, Some(0x100));
+        // Little-endian words, the gap filled and `.bss` left out.
+        assert_eq!(
+            program.flat_binary(0xff).unwrap(),
+            [0x13, 0x05, 0x15, 0x00, 0xff, 0xff, 0xff, 0xff, 0x78, 0x56, 0x34, 0x12]
+        );
+        assert!(Program::parse("")
+            .unwrap()
+            .flat_binary(0)
+            .unwrap()
+            .is_empty());
+    }
+}
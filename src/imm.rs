@@ -3,95 +3,482 @@ use std::fmt::Debug;
 use nom::{
     branch::alt,
     bytes::complete::{tag, take_while1, take_while_m_n},
-    character::complete::{digit1, hex_digit1, oct_digit1},
-    combinator::{map, map_res, peek},
-    sequence::preceded,
+    character::complete::{char, digit1, hex_digit1, oct_digit1, satisfy, space0},
+    combinator::{consumed, map, map_res, not, peek, recognize, value},
+    multi::fold_many0,
+    sequence::{delimited, pair, preceded, terminated},
 };
 
 use crate::{
-    error::{AsmError, AsmErrorKind, IResult},
-    program::Program,
+    error::{AsmError, AsmErrorKind, Error, IResult},
+    program::{Program, Site},
     span::{Offset, Span},
 };
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub enum Imm {
-    Val(i32),
+    Val(i64),
     Sym(Offset),
+    Local(LocalRef),
+    /// Location counter `.`, resolved to the address of the site using it.
+    Here,
+    Neg(Offset, Box<Imm>),
+    Bin(BinOp, Offset, Box<Imm>, Box<Imm>),
+    /// `%hi(expr)` and alike, selecting a part of an address.
+    Mod(Modifier, Offset, Box<Imm>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Modifier {
+    /// `%hi`, upper 20 bits for `lui`, rounded for the sign of `%lo`.
+    Hi,
+    /// `%lo`, lower 12 bits, sign-extended.
+    Lo,
+    /// `%pcrel_hi`, upper 20 bits of the offset from the `auipc`.
+    PcrelHi,
+    /// `%pcrel_lo(label)`, lower 12 bits of the offset computed by the
+    /// `auipc` at `label`.
+    PcrelLo,
+    /// Same as `%pcrel_hi`, for the `auipc` of `call`.
+    Call,
+}
+
+/// Upper part of a value, which is added to the sign-extended lower part.
+pub fn hi(value: i64) -> i64 {
+    value.wrapping_add(0x800) >> 12
+}
+
+pub fn lo(value: i64) -> i64 {
+    value.wrapping_sub(hi(value) << 12)
+}
+
+/// Target of a relocation: a value which may be relative to an undefined
+/// symbol.
+#[derive(Debug, Clone)]
+pub struct Target {
+    pub sym: Option<String>,
+    pub value: Value,
+}
+
+impl Target {
+    pub fn is_abs(&self) -> bool {
+        self.sym.is_none() && self.value.is_abs()
+    }
+}
+
+/// Value of an expression: either absolute, or an offset into a section
+/// which only becomes an address once sections are laid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Value {
+    pub section: Option<usize>,
+    pub offset: i64,
+}
+
+impl Value {
+    pub fn abs(offset: i64) -> Self {
+        Self {
+            section: None,
+            offset,
+        }
+    }
+
+    pub fn rel(section: usize, offset: u32) -> Self {
+        Self {
+            section: Some(section),
+            offset: offset as i64,
+        }
+    }
+
+    pub fn is_abs(&self) -> bool {
+        self.section.is_none()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl BinOp {
+    fn parse_add(input: Span<'_>) -> IResult<'_, (Self, Offset)> {
+        map(
+            consumed(alt((
+                value(Self::Add, char::<Span<'_>, _>('+')),
+                value(Self::Sub, char('-')),
+            ))),
+            |(op_span, op)| (op, op_span.into()),
+        )(input)
+    }
+
+    fn parse_cmp(input: Span<'_>) -> IResult<'_, (Self, Offset)> {
+        map(
+            consumed(alt((
+                value(Self::Eq, tag::<_, Span<'_>, _>("==")),
+                value(Self::Ne, alt((tag("!="), tag("<>")))),
+                value(Self::Le, tag("<=")),
+                value(Self::Ge, tag(">=")),
+                value(Self::Lt, tag("<")),
+                value(Self::Gt, tag(">")),
+            ))),
+            |(op_span, op)| (op, op_span.into()),
+        )(input)
+    }
+
+    /// Applies the operator, returning `None` if the result cannot be
+    /// expressed relative to a single section.
+    fn apply(&self, lhs: Value, rhs: Value) -> Option<Value> {
+        let cmp = |ord: fn(&i64, &i64) -> bool| {
+            // Values in different sections can't be compared before layout.
+            // True is -1 (all bits set), as GNU as does.
+            (lhs.section == rhs.section)
+                .then(|| Value::abs(-(ord(&lhs.offset, &rhs.offset) as i64)))
+        };
+
+        match (self, lhs.section, rhs.section) {
+            (Self::Eq, ..) => cmp(i64::eq),
+            (Self::Ne, ..) => cmp(i64::ne),
+            (Self::Lt, ..) => cmp(i64::lt),
+            (Self::Le, ..) => cmp(i64::le),
+            (Self::Gt, ..) => cmp(i64::gt),
+            (Self::Ge, ..) => cmp(i64::ge),
+            (Self::Add, Some(_), Some(_)) => None,
+            (Self::Add, section, None) | (Self::Add, None, section) => Some(Value {
+                section,
+                offset: lhs.offset.wrapping_add(rhs.offset),
+            }),
+            (Self::Sub, section, None) => Some(Value {
+                section,
+                offset: lhs.offset.wrapping_sub(rhs.offset),
+            }),
+            (Self::Sub, Some(lhs_sec), Some(rhs_sec)) if lhs_sec == rhs_sec => {
+                Some(Value::abs(lhs.offset.wrapping_sub(rhs.offset)))
+            }
+            (Self::Sub, _, Some(_)) => None,
+        }
+    }
+}
+
+/// Expression along with its location, for reporting errors about the
+/// value it evaluates to.
+#[derive(Debug, Clone)]
+pub struct Expr {
+    pub imm: Imm,
+    pub offset: Offset,
+}
+
+impl Expr {
+    pub fn parse(input: Span<'_>) -> IResult<'_, Self> {
+        map(consumed(Imm::parse), |(span, imm)| Self {
+            imm,
+            offset: span.into(),
+        })(input)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dir {
+    Backward,
+    Forward,
+}
+
+/// Reference to a numeric local label. The label is looked up relative to
+/// the position of the reference itself, so the same number can be defined
+/// many times.
+#[derive(Debug, Clone, Copy)]
+pub struct LocalRef {
+    pub num: u32,
+    pub dir: Dir,
+    pub offset: Offset,
 }
 
 impl From<i32> for Imm {
     fn from(val: i32) -> Self {
-        Self::Val(val)
+        Self::Val(val as i64)
     }
 }
 
-pub fn parse_sym(input: Span<'_>) -> IResult<Span<'_>> {
+pub fn parse_sym(input: Span<'_>) -> IResult<'_, Span<'_>> {
     preceded(
         peek(take_while_m_n(
             1,
             1,
             |c| matches!(c, 'a'..='z' | 'A'..='Z' | '_'),
         )),
-        take_while1(|c| matches!(c, '0'..='9' | 'a'..='z' | 'A'..='Z' | '_')),
+        take_while1(is_sym_char),
     )(input)
 }
 
+pub fn is_sym_char(c: char) -> bool {
+    matches!(c, '0'..='9' | 'a'..='z' | 'A'..='Z' | '_')
+}
+
 impl Imm {
-    pub fn parse(input: Span<'_>) -> IResult<Self> {
-        alt((Self::parse_sym, Self::parse_imm))(input)
+    /// Parses an expression. Comparisons bind looser than `+` and `-`.
+    pub fn parse(input: Span<'_>) -> IResult<'_, Self> {
+        let (input, lhs) = Self::parse_sum(input)?;
+
+        fold_many0(
+            pair(delimited(space0, BinOp::parse_cmp, space0), Self::parse_sum),
+            move || lhs.clone(),
+            |lhs, ((op, offset), rhs)| Self::Bin(op, offset, lhs.into(), rhs.into()),
+        )(input)
+    }
+
+    fn parse_sum(input: Span<'_>) -> IResult<'_, Self> {
+        let (input, lhs) = Self::parse_unary(input)?;
+
+        fold_many0(
+            pair(
+                delimited(space0, BinOp::parse_add, space0),
+                Self::parse_unary,
+            ),
+            move || lhs.clone(),
+            |lhs, ((op, offset), rhs)| Self::Bin(op, offset, lhs.into(), rhs.into()),
+        )(input)
+    }
+
+    fn parse_unary(input: Span<'_>) -> IResult<'_, Self> {
+        alt((
+            map(
+                pair(terminated(recognize(char('-')), space0), Self::parse_unary),
+                |(op, imm)| Self::Neg(op.into(), imm.into()),
+            ),
+            Self::parse_atom,
+        ))(input)
+    }
+
+    fn parse_atom(input: Span<'_>) -> IResult<'_, Self> {
+        alt((
+            Self::parse_mod,
+            Self::parse_sym,
+            Self::parse_local,
+            Self::parse_here,
+            Self::parse_imm,
+        ))(input)
+    }
+
+    fn parse_mod(input: Span<'_>) -> IResult<'_, Self> {
+        map(
+            consumed(pair(
+                preceded(
+                    char('%'),
+                    alt((
+                        value(Modifier::PcrelHi, tag("pcrel_hi")),
+                        value(Modifier::PcrelLo, tag("pcrel_lo")),
+                        value(Modifier::Hi, tag("hi")),
+                        value(Modifier::Lo, tag("lo")),
+                    )),
+                ),
+                delimited(
+                    pair(char('('), space0),
+                    Self::parse,
+                    pair(space0, char(')')),
+                ),
+            )),
+            |(span, (modifier, imm))| Self::Mod(modifier, span.into(), imm.into()),
+        )(input)
+    }
+
+    fn parse_here(input: Span<'_>) -> IResult<'_, Self> {
+        value(Self::Here, terminated(char('.'), not(satisfy(is_sym_char))))(input)
     }
 
-    pub fn parse_imm(input: Span<'_>) -> IResult<Self> {
+    pub fn parse_imm(input: Span<'_>) -> IResult<'_, Self> {
         map(
             alt((
                 Self::parse_decimal,
                 Self::parse_hex,
                 Self::parse_bin,
                 Self::parse_octal,
+                Self::parse_zero,
             )),
             Self::Val,
         )(input)
         .map_err(|e| e.map(|e: AsmError<'_>| e.with_kind(AsmErrorKind::InvalidImm)))
     }
 
-    pub fn parse_sym(input: Span<'_>) -> IResult<Self> {
+    pub fn parse_sym(input: Span<'_>) -> IResult<'_, Self> {
         map(parse_sym, |sym| Self::Sym(sym.into()))(input)
     }
 
-    fn parse_decimal(input: Span<'_>) -> IResult<i32> {
+    pub fn parse_local(input: Span<'_>) -> IResult<'_, Self> {
+        let (rest, (num, dir)) = terminated(
+            pair(
+                map_res(digit1, |s: Span<'_>| s.parse::<u32>()),
+                alt((
+                    value(Dir::Backward, char('b')),
+                    value(Dir::Forward, char('f')),
+                )),
+            ),
+            not(satisfy(is_sym_char)),
+        )(input)?;
+
+        let offset = Offset {
+            offset: input.byte_offset(),
+            len: input.len() - rest.len(),
+        };
+
+        Ok((rest, Self::Local(LocalRef { num, dir, offset })))
+    }
+
+    fn parse_decimal(input: Span<'_>) -> IResult<'_, i64> {
         map_res(
             preceded(
                 peek(take_while_m_n(1, 1, |c| matches!(c, '1'..='9'))),
                 digit1,
             ),
-            |s: Span<'_>| s.parse::<i32>(),
+            |s: Span<'_>| s.parse::<i64>(),
         )(input)
     }
 
-    fn parse_hex(input: Span<'_>) -> IResult<i32> {
+    fn parse_hex(input: Span<'_>) -> IResult<'_, i64> {
         map_res(preceded(tag("0x"), hex_digit1), |s: Span<'_>| {
-            i32::from_str_radix(*s, 16)
+            u64::from_str_radix(*s, 16).map(|val| val as i64)
         })(input)
     }
 
-    fn parse_bin(input: Span<'_>) -> IResult<i32> {
+    fn parse_bin(input: Span<'_>) -> IResult<'_, i64> {
         map_res(preceded(tag("0b"), oct_digit1), |s: Span<'_>| {
-            i32::from_str_radix(*s, 2)
+            u64::from_str_radix(*s, 2).map(|val| val as i64)
         })(input)
     }
 
-    fn parse_octal(input: Span<'_>) -> IResult<i32> {
+    fn parse_octal(input: Span<'_>) -> IResult<'_, i64> {
         map_res(
             preceded(tag("0"), take_while1(|c| matches!(c, '0'..='7'))),
-            |s: Span<'_>| i32::from_str_radix(*s, 8),
+            |s: Span<'_>| u64::from_str_radix(*s, 8).map(|val| val as i64),
         )(input)
     }
 
-    pub fn resolve<'s>(&self, program: &Program<'s>) -> Result<i32, AsmError<'s>> {
+    fn parse_zero(input: Span<'_>) -> IResult<'_, i64> {
+        value(0, terminated(char('0'), not(satisfy(is_sym_char))))(input)
+    }
+
+    pub fn eval(&self, program: &Program, site: Site) -> Result<Value, Error> {
+        match self {
+            Self::Val(val) => Ok(Value::abs(*val)),
+            Self::Sym(sym) => program.resolve(site, sym),
+            Self::Local(local) => program.resolve_local(site, local),
+            Self::Here => Ok(site.here),
+            Self::Neg(offset, imm) => {
+                let value = imm.eval(program, site)?;
+                if !value.is_abs() {
+                    return Err(program.error(
+                        site.line,
+                        offset,
+                        AsmErrorKind::InvalidExpr,
+                    ));
+                }
+
+                Ok(Value::abs(value.offset.wrapping_neg()))
+            }
+            Self::Bin(op, offset, lhs, rhs) => op
+                .apply(lhs.eval(program, site)?, rhs.eval(program, site)?)
+                .ok_or_else(|| {
+                    program.error(site.line, offset, AsmErrorKind::InvalidExpr)
+                }),
+            Self::Mod(modifier, offset, imm) => {
+                let value = match modifier {
+                    Modifier::Hi | Modifier::Lo => imm.resolve(program, site)?,
+                    Modifier::PcrelHi | Modifier::Call => {
+                        imm.resolve(program, site)? - program.addr(site.here)
+                    }
+                    Modifier::PcrelLo => {
+                        let label = imm.eval(program, site)?;
+                        let (hi, line) = program.pcrel_hi(label, site, offset)?;
+                        let (_, _, target) = hi.imm().and_then(Self::modifier).unwrap();
+
+                        target.resolve(program, Site { here: label, line })?
+                            - program.addr(label)
+                    }
+                };
+
+                Ok(Value::abs(match modifier {
+                    Modifier::Hi | Modifier::PcrelHi | Modifier::Call => hi(value),
+                    Modifier::Lo | Modifier::PcrelLo => lo(value),
+                }))
+            }
+        }
+    }
+
+    pub fn modifier(&self) -> Option<(Modifier, Offset, &Self)> {
         match self {
-            Self::Val(val) => Ok(*val),
-            Self::Sym(sym) => program.resolve(sym),
+            Self::Mod(modifier, offset, imm) => Some((*modifier, *offset, imm)),
+            _ => None,
         }
     }
+
+    /// Evaluates the expression, allowing a reference to an undefined
+    /// symbol plus or minus a constant, like relocations in object files
+    /// do.
+    pub fn target(&self, program: &Program, site: Site) -> Result<Target, Error> {
+        match self {
+            Self::Sym(sym) => {
+                let name = program.text(site.line, sym);
+                match program.symbol(name).and_then(|sym| sym.value) {
+                    Some(_) => Ok(Target {
+                        sym: None,
+                        value: program.resolve(site, sym)?,
+                    }),
+                    None => Ok(Target {
+                        sym: Some(name.to_string()),
+                        value: Value::abs(0),
+                    }),
+                }
+            }
+            Self::Bin(op @ (BinOp::Add | BinOp::Sub), offset, lhs, rhs) => {
+                let (lhs, rhs) = (lhs.target(program, site)?, rhs.target(program, site)?);
+                let error =
+                    || program.error(site.line, offset, AsmErrorKind::InvalidExpr);
+
+                let sym = match (lhs.sym, rhs.sym) {
+                    (None, None) => None,
+                    (Some(sym), None) if rhs.value.is_abs() => Some(sym),
+                    (None, Some(sym)) if *op == BinOp::Add && lhs.value.is_abs() => {
+                        Some(sym)
+                    }
+                    _ => return Err(error()),
+                };
+                let value = op.apply(lhs.value, rhs.value).ok_or_else(error)?;
+
+                Ok(Target { sym, value })
+            }
+            _ => Ok(Target {
+                sym: None,
+                value: self.eval(program, site)?,
+            }),
+        }
+    }
+
+    /// Operands of a difference of locations in the same section, like
+    /// `end - start`, if the linker may relax code of the section and so
+    /// change it.
+    pub fn relaxed_difference(
+        &self,
+        program: &Program,
+        site: Site,
+    ) -> Option<(Value, Value)> {
+        let Self::Bin(BinOp::Sub, _, lhs, rhs) = self else {
+            return None;
+        };
+        let (lhs, rhs) = (lhs.eval(program, site).ok()?, rhs.eval(program, site).ok()?);
+        let section = lhs
+            .section
+            .filter(|&section| rhs.section == Some(section))?;
+
+        program.sections()[section].relaxed.then_some((lhs, rhs))
+    }
+
+    /// Evaluates the expression to a number, turning section offsets into
+    /// addresses.
+    pub fn resolve(&self, program: &Program, site: Site) -> Result<i64, Error> {
+        Ok(program.addr(self.eval(program, site)?))
+    }
 }
@@ -2,17 +2,21 @@ use nom::{
     branch::alt,
     character::complete::{char, space0, space1},
     combinator::{cut, map},
-    sequence::{delimited, terminated},
+    sequence::{delimited, pair, terminated},
+};
+use std::{
+    fmt::{self, Debug, Display},
+    ops::Range,
 };
-use std::fmt::{self, Debug, Display};
 
 use crate::{
-    error::{AsmError, IResult},
-    imm::Imm,
+    error::{AsmErrorKind, Error, IResult},
+    imm::{BinOp, Expr, Imm, Modifier, Target},
     op_code::OpCode,
-    program::Program,
+    program::{Program, Site},
     pseudo::Pseudo,
     reg::Reg,
+    reloc::{Fixup, RelocKind},
     span::Span,
 };
 
@@ -30,17 +34,35 @@ macro_rules! parse_ops {
 pub struct Instr {
     pub op_code: OpCode,
     pub operands: Operands,
+    /// Relocations of the instruction are marked relaxable and references
+    /// within the section are relocated too, set by `.option relax`.
+    pub relax: bool,
+    /// `call` goes through the PLT, set by `.option pic`.
+    pub pic: bool,
 }
 
 impl Instr {
-    pub fn parse(input: Span<'_>) -> IResult<Self> {
-        let (input, this) = alt((Self::parse_pseudo, Self::parse_instr))(input)?;
+    fn new(op_code: OpCode, operands: Operands) -> Self {
+        Self {
+            op_code,
+            operands,
+            relax: false,
+            pic: false,
+        }
+    }
+
+    /// Parses an instruction, pseudo instructions may expand to several.
+    pub fn parse(input: Span<'_>) -> IResult<'_, Vec<Self>> {
+        let (input, this) = alt((
+            Self::parse_pseudo,
+            map(Self::parse_instr, |instr| vec![instr]),
+        ))(input)?;
         let (input, _) = space0(input)?;
 
         Ok((input, this))
     }
 
-    fn parse_pseudo(input: Span<'_>) -> IResult<Self> {
+    fn parse_pseudo(input: Span<'_>) -> IResult<'_, Vec<Self>> {
         let (input, pseudo) = terminated(Pseudo::parse, space1)(input)?;
 
         let op_code = pseudo.op_code();
@@ -48,19 +70,67 @@ impl Instr {
             Pseudo::mv => {
                 assert_eq!(op_code.kind(), OpKind::I);
 
-                map(Self::parse_pseudo_rd_rs, |(rd, rs)| Self {
-                    op_code,
-                    operands: Operands::I(InstrI {
-                        rd,
-                        rs,
-                        imm: 0.into(),
-                    }),
+                map(Self::parse_pseudo_rd_rs, |(rd, rs)| {
+                    vec![Self::new(
+                        op_code,
+                        Operands::I(InstrI {
+                            rd,
+                            rs,
+                            imm: 0.into(),
+                        }),
+                    )]
                 })(input)
             }
+            Pseudo::j => map(Imm::parse, |imm| {
+                vec![Self::new(
+                    op_code,
+                    Operands::J(InstrJ { rd: Reg::ZERO, imm }),
+                )]
+            })(input),
+            Pseudo::call => map(Expr::parse, |expr| {
+                Self::pcrel_pair(Reg::RA, Modifier::Call, expr, OpCode::jalr, Reg::RA)
+            })(input),
+            Pseudo::la => map(
+                pair(terminated(Reg::parse, sep), Expr::parse),
+                |(rd, expr)| {
+                    Self::pcrel_pair(rd, Modifier::PcrelHi, expr, OpCode::addi, rd)
+                },
+            )(input),
         })(input)
     }
 
-    fn parse_pseudo_rd_rs(input: Span<'_>) -> IResult<(Reg, Reg)> {
+    /// `auipc` followed by an I-type instruction, which add up to the pc
+    /// relative address `imm`.
+    fn pcrel_pair(
+        hi_rd: Reg,
+        modifier: Modifier,
+        Expr { imm, offset }: Expr,
+        lo_op_code: OpCode,
+        lo_rd: Reg,
+    ) -> Vec<Self> {
+        // The low part refers to the `auipc`, right before it.
+        let auipc = Imm::Bin(BinOp::Sub, offset, Imm::Here.into(), Imm::from(4).into());
+
+        vec![
+            Self::new(
+                OpCode::auipc,
+                Operands::U(InstrU {
+                    rd: hi_rd,
+                    imm: Imm::Mod(modifier, offset, imm.into()),
+                }),
+            ),
+            Self::new(
+                lo_op_code,
+                Operands::I(InstrI {
+                    rd: lo_rd,
+                    rs: hi_rd,
+                    imm: Imm::Mod(Modifier::PcrelLo, offset, auipc.into()),
+                }),
+            ),
+        ]
+    }
+
+    fn parse_pseudo_rd_rs(input: Span<'_>) -> IResult<'_, (Reg, Reg)> {
         parse_ops! {
             input as
             rd => Reg::parse,
@@ -70,27 +140,137 @@ impl Instr {
         Ok((input, (rd, rs)))
     }
 
-    fn parse_instr(input: Span<'_>) -> IResult<Self> {
+    fn parse_instr(input: Span<'_>) -> IResult<'_, Self> {
         let (input, op_code) = terminated(OpCode::parse, space1)(input)?;
         let (input, operands) = cut(|input| op_code.kind().parse(input))(input)?;
 
-        Ok((input, Self { op_code, operands }))
+        Ok((input, Self::new(op_code, operands)))
     }
 
-    pub fn code<'s>(
-        &self,
-        program: &Program<'s>,
-        addr: u32,
-    ) -> Result<u32, AsmError<'s>> {
-        let op_code = self.op_code.mask();
-        let operands = self.operands.mask(program, addr)?;
+    pub fn code(&self, program: &Program, site: Site) -> Result<u32, Error> {
+        let imm = self.operands.imm(program, site)?;
+        if self.op_code.is_shift() && !(0..32).contains(&imm) {
+            return Err(program.line_error(site.line, AsmErrorKind::ImmOutOfRange));
+        }
+
+        Ok(self.encode(imm))
+    }
 
-        Ok(op_code | operands)
+    pub fn encode(&self, imm: i64) -> u32 {
+        self.op_code.mask() | self.operands.encode(imm)
+    }
+
+    pub fn imm(&self) -> Option<&Imm> {
+        match &self.operands {
+            Operands::R(_) => None,
+            Operands::I(instr) => Some(&instr.imm),
+            Operands::S(instr) => Some(&instr.imm),
+            Operands::B(instr) => Some(&instr.imm),
+            Operands::U(instr) => Some(&instr.imm),
+            Operands::J(instr) => Some(&instr.imm),
+        }
+    }
+
+    /// Decides how the immediate is filled in an object file, where
+    /// references to other sections and undefined symbols are left to the
+    /// linker.
+    pub fn fixup(&self, program: &Program, site: Site) -> Result<Fixup, Error> {
+        let Some(imm) = self.imm() else {
+            return Ok(Fixup::Resolved);
+        };
+
+        let pcrel = |kind| {
+            let imm = imm.modifier().map_or(imm, |(_, _, imm)| imm);
+            let target = imm.target(program, site)?;
+            let is_local =
+                target.sym.is_none() && target.value.section == site.here.section;
+
+            Ok(if target.is_abs() || (is_local && !self.relax) {
+                Fixup::Resolved
+            } else {
+                Fixup::Reloc(kind, target)
+            })
+        };
+
+        match (&self.operands, imm.modifier()) {
+            (Operands::B(_), None) => pcrel(RelocKind::Branch),
+            (Operands::J(_), None) => pcrel(RelocKind::Jal),
+            (Operands::U(_), Some((Modifier::PcrelHi, ..))) => {
+                pcrel(RelocKind::PcrelHi20)
+            }
+            (Operands::U(_), Some((Modifier::Call, ..))) => pcrel(if self.pic {
+                RelocKind::CallPlt
+            } else {
+                RelocKind::Call
+            }),
+            (Operands::U(_), Some((Modifier::Hi, _, imm))) => {
+                abs(program, site, imm, RelocKind::Hi20)
+            }
+            (Operands::I(_), Some((Modifier::Lo, _, imm))) => {
+                abs(program, site, imm, RelocKind::Lo12I)
+            }
+            (Operands::S(_), Some((Modifier::Lo, _, imm))) => {
+                abs(program, site, imm, RelocKind::Lo12S)
+            }
+            (
+                Operands::I(_) | Operands::S(_),
+                Some((Modifier::PcrelLo, offset, label)),
+            ) => {
+                let label = label.eval(program, site)?;
+                let (hi, line) = program.pcrel_hi(label, site, &offset)?;
+                let hi_site = Site { here: label, line };
+
+                let kind = match self.operands {
+                    Operands::S(_) => RelocKind::PcrelLo12S,
+                    _ => RelocKind::PcrelLo12I,
+                };
+                Ok(match hi.fixup(program, hi_site)? {
+                    Fixup::Resolved => Fixup::Resolved,
+                    // `call` is relocated as a whole.
+                    Fixup::Reloc(RelocKind::Call | RelocKind::CallPlt, _) => {
+                        Fixup::Covered
+                    }
+                    _ => Fixup::Reloc(
+                        kind,
+                        Target {
+                            sym: None,
+                            value: label,
+                        },
+                    ),
+                })
+            }
+            _ => Ok(Fixup::Resolved),
+        }
     }
 }
 
+/// Fixup of an absolute reference, which is relocated unless it is a
+/// constant.
+fn abs(
+    program: &Program,
+    site: Site,
+    imm: &Imm,
+    kind: RelocKind,
+) -> Result<Fixup, Error> {
+    let target = imm.target(program, site)?;
+
+    Ok(if target.is_abs() {
+        Fixup::Resolved
+    } else {
+        Fixup::Reloc(kind, target)
+    })
+}
+
+fn sep(input: Span<'_>) -> IResult<'_, char> {
+    delimited(space0, char(','), space0)(input)
+}
+
 pub trait Mask {
-    fn mask<'s>(&self, program: &Program<'s>, addr: u32) -> Result<u32, AsmError<'s>>;
+    /// Value of the immediate operand at the site, as it is encoded (e.g.
+    /// branch targets are relative to the instruction).
+    fn imm(&self, program: &Program, site: Site) -> Result<i64, Error>;
+
+    fn encode(&self, imm: i64) -> u32;
 }
 
 macro_rules! op_kind {
@@ -103,7 +283,7 @@ macro_rules! op_kind {
         }
 
         impl $name {
-            pub fn parse(input: Span<'_>) -> IResult<Self> {
+            pub fn parse(input: Span<'_>) -> IResult<'_, Self> {
                 parse_ops!(
                     input as
                     $(
@@ -157,10 +337,18 @@ macro_rules! ops {
         }
 
         impl Mask for Operands {
-            fn mask<'s>(&self, program: &Program<'s>, addr: u32) -> Result<u32, AsmError<'s>> {
+            fn imm(&self, program: &Program, site: Site) -> Result<i64, Error> {
+                match self {
+                    $(
+                        Self::$kind(instr) => instr.imm(program, site),
+                    )+
+                }
+            }
+
+            fn encode(&self, imm: i64) -> u32 {
                 match self {
                     $(
-                        Self::$kind(instr) => instr.mask(program, addr),
+                        Self::$kind(instr) => instr.encode(imm),
                     )+
                 }
             }
@@ -192,6 +380,14 @@ ops! {
         rs1: Reg => Reg::parse,
         rs2: Reg => Reg::parse,
         imm: Imm => Imm::parse
+    ),
+    U => (InstrU with
+        rd: Reg => Reg::parse,
+        imm: Imm => Imm::parse
+    ),
+    J => (InstrJ with
+        rd: Reg => Reg::parse,
+        imm: Imm => Imm::parse
     )
 }
 
@@ -211,43 +407,212 @@ pub fn bit(imm: u32, idx: u32) -> u32 {
     slice(imm, idx, idx)
 }
 
+const fn signed(bits: u32) -> Range<i64> {
+    -(1 << (bits - 1))..1 << (bits - 1)
+}
+
+fn check(
+    program: &Program,
+    site: Site,
+    imm: i64,
+    range: Range<i64>,
+    align: i64,
+) -> Result<i64, Error> {
+    if !range.contains(&imm) || imm % align != 0 {
+        return Err(program.line_error(site.line, AsmErrorKind::ImmOutOfRange));
+    }
+
+    Ok(imm)
+}
+
 impl Mask for InstrR {
-    fn mask<'s>(&self, _: &Program<'s>, _: u32) -> Result<u32, AsmError<'s>> {
-        Ok((self.rs1.idx() << 20) | (self.rs2.idx() << 15) | (self.rd.idx() << 7))
+    fn imm(&self, _: &Program, _: Site) -> Result<i64, Error> {
+        Ok(0)
+    }
+
+    fn encode(&self, _: i64) -> u32 {
+        (self.rs2.idx() << 20) | (self.rs1.idx() << 15) | (self.rd.idx() << 7)
     }
 }
 
 impl Mask for InstrI {
-    fn mask<'s>(&self, program: &Program<'s>, _: u32) -> Result<u32, AsmError<'s>> {
-        let imm = (self.imm.resolve(program)? as u32) & 0xfff;
+    fn imm(&self, program: &Program, site: Site) -> Result<i64, Error> {
+        check(
+            program,
+            site,
+            self.imm.resolve(program, site)?,
+            signed(12),
+            1,
+        )
+    }
+
+    fn encode(&self, imm: i64) -> u32 {
+        let imm = (imm as u32) & 0xfff;
         let rs = self.rs.idx();
         let rd = self.rd.idx();
 
-        Ok((imm << 20) | (rs << 15) | (rd << 7))
+        (imm << 20) | (rs << 15) | (rd << 7)
     }
 }
 
 impl Mask for InstrS {
-    fn mask<'s>(&self, program: &Program<'s>, _: u32) -> Result<u32, AsmError<'s>> {
-        let imm = (self.imm.resolve(program)? as u32) & 0xfff;
+    fn imm(&self, program: &Program, site: Site) -> Result<i64, Error> {
+        check(
+            program,
+            site,
+            self.imm.resolve(program, site)?,
+            signed(12),
+            1,
+        )
+    }
 
-        Ok((slice(imm, 5, 11) << 25)
+    fn encode(&self, imm: i64) -> u32 {
+        let imm = (imm as u32) & 0xfff;
+
+        (slice(imm, 5, 11) << 25)
             | (self.rs2.idx() << 20)
             | (self.rs1.idx() << 15)
-            | (slice(imm, 0, 4) << 7))
+            | (slice(imm, 0, 4) << 7)
     }
 }
 
 impl Mask for InstrB {
-    fn mask<'s>(&self, program: &Program<'s>, addr: u32) -> Result<u32, AsmError<'s>> {
-        let imm = self.imm.resolve(program)? - (addr as i32);
+    fn imm(&self, program: &Program, site: Site) -> Result<i64, Error> {
+        let offset = self.imm.resolve(program, site)? - program.addr(site.here);
+        check(program, site, offset, signed(13), 2)
+    }
+
+    fn encode(&self, imm: i64) -> u32 {
         let imm = (imm as u32) & 0x1fff;
 
-        Ok((bit(imm, 12) << 31)
+        (bit(imm, 12) << 31)
             | (slice(imm, 5, 10) << 25)
             | (self.rs2.idx() << 20)
             | (self.rs1.idx() << 15)
             | (slice(imm, 1, 4) << 8)
-            | (bit(imm, 11) << 7))
+            | (bit(imm, 11) << 7)
+    }
+}
+
+impl Mask for InstrU {
+    fn imm(&self, program: &Program, site: Site) -> Result<i64, Error> {
+        let imm = self.imm.resolve(program, site)?;
+        if self.imm.modifier().is_some() {
+            // Upper parts of addresses wrap around the 32-bit address space.
+            return Ok(imm & 0xfffff);
+        }
+
+        check(program, site, imm, -(1 << 19)..1 << 20, 1)
+    }
+
+    fn encode(&self, imm: i64) -> u32 {
+        ((imm as u32) & 0xfffff) << 12 | (self.rd.idx() << 7)
+    }
+}
+
+impl Mask for InstrJ {
+    fn imm(&self, program: &Program, site: Site) -> Result<i64, Error> {
+        let offset = self.imm.resolve(program, site)? - program.addr(site.here);
+        check(program, site, offset, signed(21), 2)
+    }
+
+    fn encode(&self, imm: i64) -> u32 {
+        let imm = (imm as u32) & 0x1f_ffff;
+
+        (bit(imm, 20) << 31)
+            | (slice(imm, 1, 10) << 21)
+            | (bit(imm, 11) << 20)
+            | (slice(imm, 12, 19) << 12)
+            | (self.rd.idx() << 7)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{error::AsmErrorKind, program::Program};
+
+    fn words(src: &str) -> Vec<u32> {
+        let program = Program::parse(src).unwrap();
+        let sections = program.generate().unwrap();
+        sections[0]
+            .1
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect()
+    }
+
+    fn error(src: &str) -> AsmErrorKind {
+        Program::parse(src)
+            .and_then(|program| program.generate().map(drop))
+            .unwrap_err()
+            .kind
+    }
+
+    #[test]
+    fn encode() {
+        let src = "
+  add a0, a1, a2
+  sub t0, t1, t2
+  addi a0, a1, -1
+  addi a0, a1, 2047
+  slli a0, a0, 3
+  srai a0, a1, 31
+  jalr ra, t0, 0
+  lui a0, 0x12345
+  auipc a0, 0xfffff
+start:
+  beq a0, a1, start
+  bne a0, a1, end
+  jal ra, start
+  jal zero, end
+end:
+";
+        assert_eq!(
+            words(src),
+            [
+                0x00c5_8533,
+                0x4073_02b3,
+                0xfff5_8513,
+                0x7ff5_8513,
+                0x0035_1513,
+                0x41f5_d513,
+                0x0002_80e7,
+                0x1234_5537,
+                0xffff_f517,
+                0x00b5_0063,
+                0x00b5_1663,
+                0xff9f_f0ef,
+                0x0040_006f,
+            ]
+        );
+    }
+
+    #[test]
+    fn encode_modifiers() {
+        let src = "
+  lui a0, %hi(0x80000800)
+  addi a0, a0, %lo(0x80000800)
+  la a1, start
+start:
+";
+        assert_eq!(
+            words(src),
+            [0x8000_1537, 0x8005_0513, 0x0000_0597, 0x0085_8593]
+        );
+    }
+
+    #[test]
+    fn out_of_range() {
+        for src in [
+            "  addi a0, a0, 2048",
+            "  addi a0, a0, -2049",
+            "  slli a0, a0, 32",
+            "  lui a0, 0x100000",
+            "  beq a0, a1, 4096",
+            "  beq a0, a1, x\n.byte 0\nx:",
+            "  jal ra, x\n.zero 0x100000\nx:",
+        ] {
+            assert!(matches!(error(src), AsmErrorKind::ImmOutOfRange), "{src}");
+        }
     }
 }
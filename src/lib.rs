@@ -1,8 +1,27 @@
+pub mod attribute;
+pub mod cfi;
+pub mod data;
+pub mod directive;
+pub mod dwarf;
+pub mod elf;
 pub mod error;
+pub mod hex;
+pub mod image;
 pub mod imm;
 pub mod instr;
+pub mod link;
+pub mod listing;
+pub mod macros;
+pub mod map;
+pub mod mem;
 pub mod op_code;
+pub mod option;
 pub mod program;
 pub mod pseudo;
 pub mod reg;
+pub mod reloc;
+pub mod section;
+pub mod source;
+pub mod source_map;
 pub mod span;
+pub mod symbol;
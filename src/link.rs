@@ -0,0 +1,1096 @@
+use std::collections::HashMap;
+
+use crate::{
+    data::push_nops,
+    error::{AsmErrorKind, Error},
+    image::{Chunk, Image},
+    imm::{hi, lo, Value},
+    instr::{bit, slice},
+    program::{dump_section, Config, Program},
+    section::SectionFlags,
+    symbol::{Binding, SymbolKind},
+};
+
+const R_RISCV_NONE: u32 = 0;
+const R_RISCV_32: u32 = 1;
+const R_RISCV_64: u32 = 2;
+const R_RISCV_BRANCH: u32 = 16;
+const R_RISCV_JAL: u32 = 17;
+const R_RISCV_CALL: u32 = 18;
+const R_RISCV_CALL_PLT: u32 = 19;
+const R_RISCV_PCREL_HI20: u32 = 23;
+const R_RISCV_PCREL_LO12_I: u32 = 24;
+const R_RISCV_PCREL_LO12_S: u32 = 25;
+const R_RISCV_HI20: u32 = 26;
+const R_RISCV_LO12_I: u32 = 27;
+const R_RISCV_LO12_S: u32 = 28;
+const R_RISCV_ADD8: u32 = 33;
+const R_RISCV_ADD16: u32 = 34;
+const R_RISCV_ADD32: u32 = 35;
+const R_RISCV_ADD64: u32 = 36;
+const R_RISCV_SUB8: u32 = 37;
+const R_RISCV_SUB16: u32 = 38;
+const R_RISCV_SUB32: u32 = 39;
+const R_RISCV_SUB64: u32 = 40;
+const R_RISCV_ALIGN: u32 = 43;
+const R_RISCV_RVC_BRANCH: u32 = 44;
+const R_RISCV_RVC_JUMP: u32 = 45;
+const R_RISCV_RELAX: u32 = 51;
+const R_RISCV_SUB6: u32 = 52;
+const R_RISCV_SET6: u32 = 53;
+const R_RISCV_SET8: u32 = 54;
+const R_RISCV_SET16: u32 = 55;
+const R_RISCV_SET32: u32 = 56;
+const R_RISCV_32_PCREL: u32 = 57;
+const R_RISCV_PLT32: u32 = 59;
+
+#[derive(Debug)]
+pub struct Object {
+    pub name: String,
+    pub sections: Vec<ObjSection>,
+    /// Symbols referred to by index from relocations, the first one being
+    /// the null symbol.
+    pub symbols: Vec<ObjSymbol>,
+    pub rvc: bool,
+}
+
+#[derive(Debug)]
+pub struct ObjSection {
+    pub name: String,
+    pub flags: SectionFlags,
+    pub align: u32,
+    pub data: Vec<u8>,
+    pub size: u32,
+    pub relocs: Vec<ObjReloc>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ObjSymbol {
+    pub name: String,
+    pub binding: Binding,
+    pub kind: SymbolKind,
+    pub size: u64,
+    pub def: SymDef,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymDef {
+    Undefined,
+    Abs(u64),
+    Section(usize, u64),
+}
+
+#[derive(Debug, Clone)]
+pub struct ObjReloc {
+    pub offset: u32,
+    /// `R_RISCV_*` number.
+    pub kind: u32,
+    pub sym: usize,
+    pub addend: i64,
+}
+
+impl ObjSymbol {
+    pub fn null() -> Self {
+        Self {
+            name: String::new(),
+            binding: Binding::Local,
+            kind: SymbolKind::NoType,
+            size: 0,
+            def: SymDef::Undefined,
+        }
+    }
+}
+
+impl Object {
+    /// Object of an assembled program, as it would be written to an object
+    /// file.
+    pub fn from_program(program: &Program, name: &str) -> Result<Self, Error> {
+        let relocatable = program.generate_relocatable()?;
+
+        // The null symbol, one per section, then the named ones.
+        let mut symbols = vec![ObjSymbol::null()];
+        symbols.extend((0..relocatable.len()).map(|idx| ObjSymbol {
+            def: SymDef::Section(idx, 0),
+            ..ObjSymbol::null()
+        }));
+
+        let mut named: Vec<_> = program.symbols().collect();
+        named.sort_by_key(|(name, sym)| (sym.line, *name));
+        let mut index = HashMap::new();
+        for (name, sym) in named {
+            index.insert(name.to_string(), symbols.len());
+            symbols.push(ObjSymbol {
+                name: name.to_string(),
+                binding: sym.binding,
+                kind: sym.kind,
+                size: sym.size.unwrap_or_default(),
+                def: match sym.value {
+                    None => SymDef::Undefined,
+                    Some(Value {
+                        section: Some(section),
+                        offset,
+                    }) => SymDef::Section(section, offset as u64),
+                    Some(value) => SymDef::Abs(value.offset as u64),
+                },
+            });
+        }
+
+        let sections = relocatable
+            .into_iter()
+            .map(|(section, data, relocs)| ObjSection {
+                name: section.name.clone(),
+                flags: section.flags,
+                align: section.align,
+                data,
+                size: section.size(),
+                relocs: relocs
+                    .into_iter()
+                    .map(|reloc| {
+                        let target = &reloc.target;
+                        let sym = match (&target.sym, target.value.section) {
+                            // Undefined symbols which are only referenced are
+                            // external.
+                            (Some(name), _) => {
+                                *index.entry(name.clone()).or_insert_with(|| {
+                                    symbols.push(ObjSymbol {
+                                        name: name.clone(),
+                                        binding: Binding::Global,
+                                        ..ObjSymbol::null()
+                                    });
+                                    symbols.len() - 1
+                                })
+                            }
+                            (None, Some(section)) => section + 1,
+                            (None, None) => 0,
+                        };
+
+                        ObjReloc {
+                            offset: reloc.offset,
+                            kind: reloc.kind.elf_type(),
+                            sym,
+                            addend: target.value.offset,
+                        }
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        Ok(Self {
+            name: name.to_string(),
+            sections,
+            symbols,
+            rvc: program.rvc(),
+        })
+    }
+
+    /// Deletes the alignment padding marked by `R_RISCV_ALIGN` which isn't
+    /// needed at the offset the padding ends up at. The padding is written
+    /// for the worst case when the linker may relax code.
+    fn delete_align_nops(&mut self) -> Result<(), Error> {
+        // Padding kept and deleted after it in each section, in the order
+        // of offsets.
+        let mut cuts: Vec<Vec<(u32, u32, u32)>> = Vec::new();
+        for section in &self.sections {
+            let mut aligns: Vec<(u32, u32)> = section
+                .relocs
+                .iter()
+                .filter(|reloc| reloc.kind == R_RISCV_ALIGN)
+                .map(|reloc| (reloc.offset, reloc.addend as u32))
+                .collect();
+            aligns.sort_unstable();
+
+            let mut deleted = 0;
+            let mut section_cuts = Vec::new();
+            for (offset, nops) in aligns {
+                // The padding is for the worst case, at least 2 bytes less
+                // than the alignment.
+                let align = (nops + 1).next_power_of_two();
+                let at = offset - deleted;
+                let keep = at.next_multiple_of(align) - at;
+                if keep > nops {
+                    return Err(
+                        AsmErrorKind::InvalidObject(self.name.as_str().into()).into()
+                    );
+                }
+                section_cuts.push((offset, keep, nops - keep));
+                deleted += nops - keep;
+            }
+            cuts.push(section_cuts);
+        }
+
+        let shift = |section: usize, offset: u64| -> u64 {
+            cuts[section]
+                .iter()
+                .map(|&(pad, keep, len)| {
+                    let (start, len) = ((pad + keep) as u64, len as u64);
+                    if offset >= start + len {
+                        len
+                    } else {
+                        offset.saturating_sub(start)
+                    }
+                })
+                .sum()
+        };
+
+        // References into the sections move with their targets.
+        for section in &mut self.sections {
+            for reloc in &mut section.relocs {
+                if let SymDef::Section(target, value) = self.symbols[reloc.sym].def {
+                    let at = (value as i64 + reloc.addend) as u64;
+                    let moved = value - shift(target, value);
+                    reloc.addend = (at - shift(target, at)) as i64 - moved as i64;
+                }
+            }
+        }
+        for sym in &mut self.symbols {
+            if let SymDef::Section(section, value) = &mut sym.def {
+                *value -= shift(*section, *value);
+            }
+        }
+
+        for (idx, section) in self.sections.iter_mut().enumerate() {
+            if cuts[idx].is_empty() {
+                continue;
+            }
+
+            let mut data = Vec::with_capacity(section.data.len());
+            let mut pos = 0;
+            // What is kept of the padding is rewritten, as the deleted part
+            // may have cut a `nop`.
+            for &(pad, keep, len) in &cuts[idx] {
+                data.extend_from_slice(&section.data[pos..pad as usize]);
+                let at = data.len() as i64;
+                push_nops(&mut data, at, keep, self.rvc);
+                pos = (pad + keep + len) as usize;
+                section.size -= len;
+            }
+            data.extend_from_slice(&section.data[pos..]);
+            section.data = data;
+
+            section.relocs.retain(|reloc| reloc.kind != R_RISCV_ALIGN);
+            for reloc in &mut section.relocs {
+                reloc.offset -= shift(idx, reloc.offset as u64) as u32;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Program linked from several objects: the sections at their addresses,
+/// with all references resolved.
+#[derive(Debug)]
+pub struct Linked {
+    pub sections: Vec<LinkedSection>,
+    /// Locals of every object first, then the globals.
+    pub symbols: Vec<LinkedSymbol>,
+    pub rvc: bool,
+}
+
+#[derive(Debug)]
+pub struct LinkedSection {
+    pub name: String,
+    pub flags: SectionFlags,
+    pub addr: u32,
+    pub align: u32,
+    pub data: Vec<u8>,
+    pub size: u32,
+}
+
+#[derive(Debug)]
+pub struct LinkedSymbol {
+    pub name: String,
+    pub value: u64,
+    pub size: u64,
+    pub binding: Binding,
+    pub kind: SymbolKind,
+    /// Index of the section, `None` for absolute symbols.
+    pub section: Option<usize>,
+}
+
+/// Where the sections of the objects end up: the index of the output
+/// section and the offset in it.
+type Placement = Vec<Vec<Option<(usize, u32)>>>;
+
+/// Links the objects: allocated sections with the same name are merged in
+/// the order of the objects, then placed like the sections of a program.
+/// Sections which aren't allocated are dropped.
+pub fn link(mut objects: Vec<Object>, config: &Config) -> Result<Linked, Error> {
+    for object in &mut objects {
+        object.delete_align_nops()?;
+    }
+    let globals = resolve(&objects)?;
+
+    let mut sections: Vec<LinkedSection> = Vec::new();
+    let mut placement: Placement = Vec::new();
+    for object in &objects {
+        let mut placed = Vec::new();
+        for section in &object.sections {
+            if !section.flags.alloc {
+                placed.push(None);
+                continue;
+            }
+
+            let idx = match sections.iter().position(|out| out.name == section.name) {
+                Some(idx) => idx,
+                None => {
+                    sections.push(LinkedSection {
+                        name: section.name.clone(),
+                        flags: section.flags,
+                        addr: 0,
+                        align: 1,
+                        data: Vec::new(),
+                        size: 0,
+                    });
+                    sections.len() - 1
+                }
+            };
+
+            let out = &mut sections[idx];
+            let offset = out.size.next_multiple_of(section.align.max(1));
+            out.size = offset + section.size;
+            out.align = out.align.max(section.align);
+            out.flags.write |= section.flags.write;
+            out.flags.exec |= section.flags.exec;
+            out.flags.nobits &= section.flags.nobits;
+            // Code is padded with nops, as execution may run through.
+            let len = out.data.len() as u32;
+            if out.flags.exec {
+                push_nops(&mut out.data, len as i64, offset - len, object.rvc);
+            } else {
+                out.data.resize(offset as usize, 0);
+            }
+            out.data.extend_from_slice(&section.data);
+            out.data.resize(out.size as usize, 0);
+
+            placed.push(Some((idx, offset)));
+        }
+        placement.push(placed);
+    }
+    for section in &mut sections {
+        if section.flags.nobits {
+            section.data = Vec::new();
+        }
+    }
+
+    let addrs = config.place(
+        sections
+            .iter()
+            .map(|section| (section.flags, section.align, section.size)),
+    )?;
+    for (section, addr) in sections.iter_mut().zip(addrs) {
+        section.addr = addr;
+    }
+
+    let linker = Linker {
+        objects: &objects,
+        globals: &globals,
+        placement: &placement,
+        sections: &sections,
+    };
+    let patches = linker.relocate()?;
+    let symbols = linker.symbols();
+    for (section, offset, patch) in patches {
+        let data = &mut sections[section].data;
+        if let Some(field) = data.get_mut(offset as usize..) {
+            patch.apply(field);
+        }
+    }
+
+    Ok(Linked {
+        sections,
+        symbols,
+        rvc: objects.iter().any(|object| object.rvc),
+    })
+}
+
+/// Definitions of the global symbols: the object and the index of the
+/// symbol in it. Global definitions take precedence over weak ones.
+fn resolve(objects: &[Object]) -> Result<HashMap<&str, (usize, usize)>, Error> {
+    let mut globals: HashMap<&str, (usize, usize)> = HashMap::new();
+    for (obj, object) in objects.iter().enumerate() {
+        for (idx, sym) in object.symbols.iter().enumerate() {
+            if sym.binding == Binding::Local || sym.def == SymDef::Undefined {
+                continue;
+            }
+
+            match globals.get(sym.name.as_str()) {
+                None => {
+                    globals.insert(&sym.name, (obj, idx));
+                }
+                Some(&(prev_obj, prev)) => {
+                    let prev_binding = objects[prev_obj].symbols[prev].binding;
+                    match (prev_binding, sym.binding) {
+                        (Binding::Weak, Binding::Global) => {
+                            globals.insert(&sym.name, (obj, idx));
+                        }
+                        (Binding::Global, Binding::Global) => {
+                            return Err(AsmErrorKind::MultipleDefinition(Box::new((
+                                sym.name.clone(),
+                                objects[prev_obj].name.clone(),
+                                object.name.clone(),
+                            )))
+                            .into());
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(globals)
+}
+
+struct Linker<'l> {
+    objects: &'l [Object],
+    globals: &'l HashMap<&'l str, (usize, usize)>,
+    placement: &'l Placement,
+    sections: &'l [LinkedSection],
+}
+
+#[derive(Debug)]
+enum Patch {
+    /// Replaces the bits of `mask` in the little-endian field of `size`
+    /// bytes.
+    Bits { size: u8, mask: u64, bits: u64 },
+    /// Adds to the field, wrapping around.
+    Add { size: u8, value: i64 },
+    /// Adds to the low 6 bits of a byte, like `DW_CFA_advance_loc`.
+    Add6(i64),
+}
+
+impl Patch {
+    fn set(size: u8, value: i64) -> Self {
+        let mask = if size == 8 {
+            u64::MAX
+        } else {
+            (1 << (size * 8)) - 1
+        };
+
+        Self::Bits {
+            size,
+            mask,
+            bits: value as u64 & mask,
+        }
+    }
+
+    fn insn(mask: u32, bits: u32) -> Self {
+        Self::Bits {
+            size: 4,
+            mask: mask as u64,
+            bits: (bits & mask) as u64,
+        }
+    }
+
+    fn apply(&self, data: &mut [u8]) {
+        let size = match *self {
+            Self::Bits { size, .. } | Self::Add { size, .. } => size as usize,
+            Self::Add6(_) => 1,
+        };
+        if data.len() < size {
+            return;
+        }
+        let mut buf = [0; 8];
+        buf[..size].copy_from_slice(&data[..size]);
+        let field = u64::from_le_bytes(buf);
+
+        let field = match *self {
+            Self::Bits { mask, bits, .. } => (field & !mask) | bits,
+            Self::Add { value, .. } => field.wrapping_add(value as u64),
+            Self::Add6(value) => {
+                (field & !0x3f) | (field.wrapping_add(value as u64) & 0x3f)
+            }
+        };
+        data[..size].copy_from_slice(&field.to_le_bytes()[..size]);
+    }
+}
+
+impl Linker<'_> {
+    /// Address of a symbol, `None` if it is undefined. Undefined weak
+    /// symbols are 0.
+    fn addr(&self, obj: usize, idx: usize) -> Option<u64> {
+        let sym = &self.objects[obj].symbols[idx];
+        let (obj, sym) = match sym.def {
+            SymDef::Undefined
+                if sym.binding != Binding::Local || !sym.name.is_empty() =>
+            {
+                match self.globals.get(sym.name.as_str()) {
+                    Some(&(obj, idx)) => (obj, &self.objects[obj].symbols[idx]),
+                    None if sym.binding == Binding::Weak => return Some(0),
+                    None => return None,
+                }
+            }
+            _ => (obj, sym),
+        };
+
+        match sym.def {
+            SymDef::Undefined => None,
+            SymDef::Abs(value) => Some(value),
+            SymDef::Section(section, value) => {
+                let (out, offset) = self.placement[obj][section]?;
+                Some(self.sections[out].addr as u64 + offset as u64 + value)
+            }
+        }
+    }
+
+    fn relocate(&self) -> Result<Vec<(usize, u32, Patch)>, Error> {
+        // Values of the `auipc`s which `%pcrel_lo` relocations refer to, by
+        // address.
+        let mut pcrel_hi = HashMap::new();
+        self.each_reloc(|site, reloc, target| {
+            if reloc.kind == R_RISCV_PCREL_HI20 {
+                pcrel_hi.insert(site.addr, target - site.addr as i64);
+            }
+            Ok(())
+        })?;
+
+        let mut patches = Vec::new();
+        self.each_reloc(|site, reloc, target| {
+            let pcrel = target - site.addr as i64;
+            let overflow = || {
+                AsmErrorKind::RelocOverflow(
+                    format!(
+                        "'{}'+{:#x} in '{}'",
+                        self.sections[site.section].name, site.offset, site.file
+                    )
+                    .into(),
+                )
+            };
+            // Absolute addresses wrap around the 32-bit address space.
+            let addr32 = |value: i64| {
+                if (i32::MIN as i64..=u32::MAX as i64).contains(&value) {
+                    Ok(value as i32 as i64)
+                } else {
+                    Err(overflow())
+                }
+            };
+            let check = |value: i64, bits: u32, align: i64| {
+                let half = 1i64 << (bits - 1);
+                if (-half..half).contains(&value) && value % align == 0 {
+                    Ok(value)
+                } else {
+                    Err(overflow())
+                }
+            };
+
+            let patch = match reloc.kind {
+                R_RISCV_NONE | R_RISCV_RELAX => return Ok(()),
+                R_RISCV_32 => Patch::set(4, addr32(target)?),
+                R_RISCV_64 => Patch::set(8, target),
+                R_RISCV_32_PCREL | R_RISCV_PLT32 => Patch::set(4, check(pcrel, 32, 1)?),
+                R_RISCV_BRANCH => {
+                    let imm = check(pcrel, 13, 2)? as u32;
+                    Patch::insn(
+                        0xfe00_0f80,
+                        (bit(imm, 12) << 31)
+                            | (slice(imm, 5, 10) << 25)
+                            | (slice(imm, 1, 4) << 8)
+                            | (bit(imm, 11) << 7),
+                    )
+                }
+                R_RISCV_JAL => {
+                    let imm = check(pcrel, 21, 2)? as u32;
+                    Patch::insn(
+                        0xffff_f000,
+                        (bit(imm, 20) << 31)
+                            | (slice(imm, 1, 10) << 21)
+                            | (bit(imm, 11) << 20)
+                            | (slice(imm, 12, 19) << 12),
+                    )
+                }
+                R_RISCV_CALL | R_RISCV_CALL_PLT => {
+                    check(hi(pcrel), 20, 1)?;
+                    patches.push((
+                        site.section,
+                        site.offset + 4,
+                        Patch::insn(0xfff0_0000, (lo(pcrel) as u32) << 20),
+                    ));
+                    Patch::insn(0xffff_f000, (hi(pcrel) as u32) << 12)
+                }
+                R_RISCV_PCREL_HI20 => {
+                    Patch::insn(0xffff_f000, (check(hi(pcrel), 20, 1)? as u32) << 12)
+                }
+                R_RISCV_HI20 => {
+                    Patch::insn(0xffff_f000, (hi(addr32(target)?) as u32) << 12)
+                }
+                R_RISCV_PCREL_LO12_I | R_RISCV_PCREL_LO12_S => {
+                    let value = *pcrel_hi
+                        .get(&(target as u64))
+                        .ok_or_else(|| Error::from(AsmErrorKind::NoPcrelHi))?;
+                    lo12(reloc.kind == R_RISCV_PCREL_LO12_S, lo(value))
+                }
+                R_RISCV_LO12_I => lo12(false, lo(target)),
+                R_RISCV_LO12_S => lo12(true, lo(target)),
+                R_RISCV_RVC_BRANCH => {
+                    let imm = check(pcrel, 9, 2)? as u32;
+                    Patch::Bits {
+                        size: 2,
+                        mask: 0x1c7c,
+                        bits: ((bit(imm, 8) << 12)
+                            | (slice(imm, 3, 4) << 10)
+                            | (slice(imm, 6, 7) << 5)
+                            | (slice(imm, 1, 2) << 3)
+                            | (bit(imm, 5) << 2)) as u64,
+                    }
+                }
+                R_RISCV_RVC_JUMP => {
+                    let imm = check(pcrel, 12, 2)? as u32;
+                    Patch::Bits {
+                        size: 2,
+                        mask: 0x1ffc,
+                        bits: ((bit(imm, 11) << 12)
+                            | (bit(imm, 4) << 11)
+                            | (slice(imm, 8, 9) << 9)
+                            | (bit(imm, 10) << 8)
+                            | (bit(imm, 6) << 7)
+                            | (bit(imm, 7) << 6)
+                            | (slice(imm, 1, 3) << 3)
+                            | (bit(imm, 5) << 2)) as u64,
+                    }
+                }
+                R_RISCV_ADD8 | R_RISCV_ADD16 | R_RISCV_ADD32 | R_RISCV_ADD64 => {
+                    Patch::Add {
+                        size: 1 << (reloc.kind - R_RISCV_ADD8),
+                        value: target,
+                    }
+                }
+                R_RISCV_SUB8 | R_RISCV_SUB16 | R_RISCV_SUB32 | R_RISCV_SUB64 => {
+                    Patch::Add {
+                        size: 1 << (reloc.kind - R_RISCV_SUB8),
+                        value: target.wrapping_neg(),
+                    }
+                }
+                R_RISCV_SET6 => Patch::Bits {
+                    size: 1,
+                    mask: 0x3f,
+                    bits: target as u64 & 0x3f,
+                },
+                R_RISCV_SUB6 => Patch::Add6(target.wrapping_neg()),
+                R_RISCV_SET8 => Patch::set(1, target),
+                R_RISCV_SET16 => Patch::set(2, target),
+                R_RISCV_SET32 => Patch::set(4, target),
+                kind => {
+                    return Err(
+                        AsmErrorKind::UnsupportedReloc(kind, site.file.into()).into()
+                    )
+                }
+            };
+            patches.push((site.section, site.offset, patch));
+
+            Ok(())
+        })?;
+
+        Ok(patches)
+    }
+
+    /// Calls `f` with each relocation of the placed sections along with its
+    /// site and the value of its target.
+    fn each_reloc(
+        &self,
+        mut f: impl FnMut(RelocSite<'_>, &ObjReloc, i64) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        for (obj, object) in self.objects.iter().enumerate() {
+            for (idx, section) in object.sections.iter().enumerate() {
+                let Some((out, offset)) = self.placement[obj][idx] else {
+                    continue;
+                };
+
+                for reloc in &section.relocs {
+                    let target = match reloc.kind {
+                        R_RISCV_NONE | R_RISCV_RELAX => 0,
+                        _ => {
+                            let addr = self.addr(obj, reloc.sym).ok_or_else(|| {
+                                AsmErrorKind::UndefinedSym(Box::new((
+                                    object.symbols[reloc.sym].name.clone(),
+                                    object.name.clone(),
+                                )))
+                            })?;
+                            (addr as i64).wrapping_add(reloc.addend)
+                        }
+                    };
+                    let offset = offset + reloc.offset;
+                    let site = RelocSite {
+                        file: &object.name,
+                        section: out,
+                        offset,
+                        addr: self.sections[out].addr as u64 + offset as u64,
+                    };
+
+                    f(site, reloc, target)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Named symbols of the output: the locals of each object, then the
+    /// globals. Assembler temporaries (`.L*`) are left out.
+    fn symbols(&self) -> Vec<LinkedSymbol> {
+        let mut locals = Vec::new();
+        let mut globals = Vec::new();
+        for (obj, object) in self.objects.iter().enumerate() {
+            for (idx, sym) in object.symbols.iter().enumerate() {
+                let is_global = sym.binding != Binding::Local;
+                if sym.name.is_empty()
+                    || sym.name.starts_with(".L")
+                    || (is_global
+                        && self.globals.get(sym.name.as_str()) != Some(&(obj, idx)))
+                {
+                    continue;
+                }
+                let Some(value) = self.addr(obj, idx) else {
+                    continue;
+                };
+
+                let section = match sym.def {
+                    SymDef::Section(section, _) => {
+                        self.placement[obj][section].map(|(out, _)| out)
+                    }
+                    _ => None,
+                };
+                let linked = LinkedSymbol {
+                    name: sym.name.clone(),
+                    value,
+                    size: sym.size,
+                    binding: sym.binding,
+                    kind: sym.kind,
+                    section,
+                };
+                if is_global {
+                    globals.push(linked);
+                } else {
+                    locals.push(linked);
+                }
+            }
+        }
+
+        locals.extend(globals);
+        locals
+    }
+}
+
+#[derive(Clone, Copy)]
+struct RelocSite<'l> {
+    file: &'l str,
+    /// Index of the output section and the offset in it.
+    section: usize,
+    offset: u32,
+    addr: u64,
+}
+
+/// Low 12 bits of an I-type or S-type instruction.
+fn lo12(store: bool, value: i64) -> Patch {
+    let imm = value as u32;
+    if store {
+        Patch::insn(
+            0xfe00_0f80,
+            (slice(imm, 5, 11) << 25) | (slice(imm, 0, 4) << 7),
+        )
+    } else {
+        Patch::insn(0xfff0_0000, imm << 20)
+    }
+}
+
+impl Linked {
+    pub fn symbol(&self, name: &str) -> Option<&LinkedSymbol> {
+        self.symbols.iter().find(|sym| sym.name == name)
+    }
+
+    /// Image of the allocated sections, NoBits sections left out.
+    pub fn image(&self) -> Image {
+        let mut chunks: Vec<Chunk> = self
+            .sections
+            .iter()
+            .filter(|section| !section.data.is_empty())
+            .map(|section| Chunk {
+                addr: section.addr,
+                data: section.data.clone(),
+            })
+            .collect();
+        chunks.sort_by_key(|chunk| chunk.addr);
+
+        let entry = self.symbol("_start").map(|sym| sym.value as u32);
+
+        Image { chunks, entry }
+    }
+
+    pub fn dump_code(&self) {
+        for section in &self.sections {
+            dump_section(&section.name, section.addr, &section.data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::{read_object, ElfConfig};
+
+    const NOP: u32 = 0x0000_0013;
+
+    fn object(name: &str, src: &str) -> Object {
+        Object::from_program(&Program::parse(src).unwrap(), name).unwrap()
+    }
+
+    fn words(data: &[u8]) -> Vec<u32> {
+        data.chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect()
+    }
+
+    fn bytes(words: &[u32]) -> Vec<u8> {
+        words.iter().flat_map(|word| word.to_le_bytes()).collect()
+    }
+
+    fn section<'l>(linked: &'l Linked, name: &str) -> &'l LinkedSection {
+        linked.sections.iter().find(|s| s.name == name).unwrap()
+    }
+
+    fn symbol(name: &str, section: usize, value: u64) -> ObjSymbol {
+        ObjSymbol {
+            name: name.into(),
+            def: SymDef::Section(section, value),
+            ..ObjSymbol::null()
+        }
+    }
+
+    fn reloc(offset: u32, kind: u32, sym: usize, addend: i64) -> ObjReloc {
+        ObjReloc {
+            offset,
+            kind,
+            sym,
+            addend,
+        }
+    }
+
+    #[test]
+    fn patch() {
+        let mut field = bytes(&[0x0005_0513]);
+        lo12(false, -1).apply(&mut field);
+        assert_eq!(words(&field), [0xfff5_0513]);
+
+        // `sw a0, 0(sp)` to `sw a0, 2047(sp)`
+        let mut field = bytes(&[0x00a1_2023]);
+        lo12(true, 0x7ff).apply(&mut field);
+        assert_eq!(words(&field), [0x7ea1_2fa3]);
+
+        let mut field = bytes(&[0x1234_5678]);
+        Patch::set(2, 0x1_abcd).apply(&mut field);
+        assert_eq!(words(&field), [0x1234_abcd]);
+
+        let mut field = bytes(&[0]);
+        Patch::Add { size: 4, value: -1 }.apply(&mut field);
+        assert_eq!(words(&field), [0xffff_ffff]);
+
+        // `DW_CFA_advance_loc 1` to `DW_CFA_advance_loc 4`
+        let mut field = vec![0x41];
+        Patch::Add6(3).apply(&mut field);
+        assert_eq!(field, [0x44]);
+    }
+
+    /// `jal zero, after`, an instruction and padding up to 16 bytes written
+    /// for relaxation, then `after: jal zero, after` and a word of `.data`
+    /// referring to `after`.
+    fn aligned() -> Object {
+        Object {
+            name: "a.o".into(),
+            sections: vec![
+                ObjSection {
+                    name: ".text".into(),
+                    flags: SectionFlags::TEXT,
+                    align: 16,
+                    data: bytes(&[0x6f, 0x0015_0513, NOP, NOP, NOP, 0x6f]),
+                    size: 24,
+                    relocs: vec![
+                        reloc(0, R_RISCV_JAL, 1, 0),
+                        reloc(8, R_RISCV_ALIGN, 0, 12),
+                        reloc(20, R_RISCV_JAL, 1, 0),
+                    ],
+                },
+                ObjSection {
+                    name: ".data".into(),
+                    flags: SectionFlags::DATA,
+                    align: 4,
+                    data: bytes(&[0]),
+                    size: 4,
+                    relocs: vec![reloc(0, R_RISCV_32, 1, 0)],
+                },
+            ],
+            symbols: vec![
+                ObjSymbol::null(),
+                symbol("after", 0, 20),
+                symbol("end", 0, 24),
+            ],
+            rvc: false,
+        }
+    }
+
+    #[test]
+    fn delete_align_nops() {
+        let mut object = aligned();
+        object.delete_align_nops().unwrap();
+
+        let text = &object.sections[0];
+        assert_eq!(words(&text.data), [0x6f, 0x0015_0513, NOP, NOP, 0x6f]);
+        assert_eq!(text.size, 20);
+        let relocs: Vec<_> = text.relocs.iter().map(|r| (r.offset, r.kind)).collect();
+        assert_eq!(relocs, [(0, R_RISCV_JAL), (16, R_RISCV_JAL)]);
+        assert_eq!(object.symbols[1].def, SymDef::Section(0, 16));
+        assert_eq!(object.symbols[2].def, SymDef::Section(0, 20));
+    }
+
+    #[test]
+    fn relaxed_object() {
+        // The second padding moves by 2 bytes, so what is kept of it would
+        // end in the middle of a `nop`.
+        let src = "
+.option rvc
+.option relax
+_start:
+  addi a0, a0, 1
+  .balign 8
+  addi a0, a0, 2
+  .balign 16
+after:
+  addi a0, a0, 3
+.data
+  .word after - _start
+";
+        let program = Program::parse(src).unwrap();
+        let elf = program.elf_object(&ElfConfig::default()).unwrap();
+        let object = read_object("a.o", &elf).unwrap();
+        let linked = link(vec![object], &Config::default()).unwrap();
+
+        let text = section(&linked, ".text");
+        assert_eq!(
+            words(&text.data),
+            [0x0015_0513, NOP, 0x0025_0513, NOP, 0x0035_0513]
+        );
+        assert_eq!(words(&section(&linked, ".data").data), [16]);
+        assert_eq!(linked.symbol("after").unwrap().value, text.addr as u64 + 16);
+    }
+
+    #[test]
+    fn label_after_deleted_padding() {
+        let linked = link(vec![aligned()], &Config::default()).unwrap();
+
+        let text = section(&linked, ".text");
+        assert_eq!(
+            words(&text.data),
+            [0x0100_006f, 0x0015_0513, NOP, NOP, 0x0000_006f]
+        );
+        let data = section(&linked, ".data");
+        assert_eq!(words(&data.data), [text.addr + 16]);
+        assert_eq!(linked.symbol("after").unwrap().value, text.addr as u64 + 16);
+    }
+
+    #[test]
+    fn nop_padding() {
+        let objects = vec![
+            object("a.s", "  addi a0, a0, 1"),
+            object("b.s", ".p2align 3\n  addi a0, a0, 2"),
+        ];
+        let linked = link(objects, &Config::default()).unwrap();
+
+        assert_eq!(
+            words(&section(&linked, ".text").data),
+            [0x0015_0513, NOP, 0x0025_0513]
+        );
+    }
+
+    #[test]
+    fn undefined_weak() {
+        let src = "
+.weak w
+_start:
+  jal ra, w
+.data
+  .word w
+";
+        let config = Config {
+            base: 0x100,
+            ..Default::default()
+        };
+        let linked = link(vec![object("a.s", src)], &config).unwrap();
+
+        // `jal ra, -256` to 0
+        assert_eq!(words(&section(&linked, ".text").data), [0xf01f_f0ef]);
+        assert_eq!(words(&section(&linked, ".data").data), [0]);
+    }
+
+    #[test]
+    fn global_over_weak() {
+        let main = ".globl _start\n_start:\n  jal ra, f";
+        let weak = ".weak f\nf:\n  addi a0, a0, 1";
+        let global = ".globl f\nf:\n  addi a0, a0, 2";
+
+        for sources in [[main, weak, global], [main, global, weak]] {
+            let objects = sources
+                .iter()
+                .enumerate()
+                .map(|(idx, src)| object(&format!("{idx}.s"), src))
+                .collect();
+            let linked = link(objects, &Config::default()).unwrap();
+
+            let text = &section(&linked, ".text").data;
+            let f = linked
+                .symbols
+                .iter()
+                .find(|sym| sym.name == "f" && sym.binding == Binding::Global)
+                .unwrap()
+                .value as usize;
+            assert_eq!(words(&text[f..f + 4]), [0x0025_0513]);
+        }
+
+        let objects = vec![
+            object("a.s", main),
+            object("b.s", global),
+            object("c.s", global),
+        ];
+        let error = link(objects, &Config::default()).unwrap_err();
+        assert!(matches!(error.kind, AsmErrorKind::MultipleDefinition(_)));
+    }
+
+    #[test]
+    fn pcrel_lo_in_other_section() {
+        let src = "
+.section .text.hi, \"ax\"
+1:
+  auipc a0, %pcrel_hi(d)
+.text
+_start:
+  addi a0, a0, %pcrel_lo(1b)
+.data
+  .word 0
+d:
+  .word 1
+";
+        let linked = link(vec![object("a.s", src)], &Config::default()).unwrap();
+
+        let auipc = section(&linked, ".text.hi").addr as i64;
+        let offset = linked.symbol("d").unwrap().value as i64 - auipc;
+        assert_eq!(
+            words(&section(&linked, ".text").data),
+            [0x0005_0513 | ((lo(offset) as u32) << 20)]
+        );
+        assert_eq!(
+            words(&section(&linked, ".text.hi").data),
+            [0x0000_0517 | ((hi(offset) as u32) << 12)]
+        );
+    }
+
+    #[test]
+    fn hi_of_high_address() {
+        let main = ".globl _start\n_start:\n  lui a0, %hi(ext)\n  addi a0, a0, %lo(ext)";
+        let ext = ".globl ext\next = 0x80000800";
+        let objects = vec![object("a.s", main), object("b.s", ext)];
+        let linked = link(objects, &Config::default()).unwrap();
+
+        assert_eq!(
+            words(&section(&linked, ".text").data),
+            [0x8000_1537, 0x8005_0513]
+        );
+    }
+}
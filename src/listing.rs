@@ -0,0 +1,148 @@
+use std::fmt::Write;
+
+use crate::{
+    error::Error,
+    program::Program,
+    section::{Entry, Item},
+};
+
+const ROW_LEN: usize = 4;
+/// Rows shown for one item, longer data is cut.
+const MAX_ROWS: usize = 8;
+
+impl Program {
+    /// Listing of every processed line (including macro expansions) with
+    /// the address and contents of the items it emitted, followed by the
+    /// symbol table. Instructions are shown as words, data as bytes in
+    /// memory order. Fields filled by relocations are zero.
+    pub fn listing(&self) -> Result<String, Error> {
+        let generated = self.generate_relocatable()?;
+
+        let mut items: Vec<Vec<(usize, &Entry)>> = vec![Vec::new(); self.lines().len()];
+        for (idx, section) in self.sections().iter().enumerate() {
+            for entry in &section.items {
+                items[entry.line].push((idx, entry));
+            }
+        }
+
+        let mut out = String::new();
+        let mut file = None;
+        for (id, line) in self.lines().iter().enumerate() {
+            let source = &self.sources()[line.src];
+            if file != Some(&source.name) {
+                file = Some(&source.name);
+                writeln!(out, "{}:", source.name).unwrap();
+            }
+
+            let num = line.number;
+            let mut rows = Vec::new();
+            for &(section, entry) in &items[id] {
+                let addr = self.sections()[section].addr + entry.offset;
+                let (_, buf, _) = &generated[section];
+                let start = entry.offset as usize;
+                let bytes = buf.get(start..start + entry.item.size() as usize);
+
+                match (&entry.item, bytes) {
+                    (Item::Instr(_), Some(bytes)) => {
+                        let word = u32::from_le_bytes(bytes.try_into().unwrap());
+                        rows.push(format!("{addr:08x} {word:08x}"));
+                    }
+                    (Item::Data(_), Some(bytes)) => {
+                        for (row, bytes) in bytes.chunks(ROW_LEN).enumerate() {
+                            if row == MAX_ROWS {
+                                rows.push(format!("{:8} ...", ""));
+                                break;
+                            }
+
+                            let hex: Vec<String> =
+                                bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+                            let addr = addr + (row * ROW_LEN) as u32;
+                            rows.push(format!("{addr:08x} {}", hex.join(" ")));
+                        }
+                    }
+                    // NoBits sections have no contents.
+                    (_, None) => rows.push(format!("{addr:08x}")),
+                }
+            }
+
+            let text = self.text(id, &line.offset);
+            let mut rows = rows.into_iter();
+            writeln!(out, "{num:5} {:21} {text}", rows.next().unwrap_or_default())
+                .unwrap();
+            for row in rows {
+                writeln!(out, "{:5} {row}", "").unwrap();
+            }
+        }
+
+        let mut symbols: Vec<_> = self.symbols().collect();
+        symbols.sort_by_key(|(name, _)| *name);
+
+        out.push_str("\nSymbols:\n");
+        for (name, sym) in symbols {
+            let binding = sym.binding.name();
+            let (value, section) = match sym.value {
+                Some(value) => (
+                    format!("{:08x}", self.addr(value) as u32),
+                    value
+                        .section
+                        .map_or("*ABS*", |section| &self.sections()[section].name),
+                ),
+                None => (String::new(), "*UND*"),
+            };
+
+            writeln!(out, "  {name:20} {value:8} {section:10} {binding}").unwrap();
+        }
+
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listing() {
+        let src = "\
+.globl _start
+_start:
+  call f
+.data
+msg:
+  .string \"hello world\"
+  .zero 40
+.bss
+  .zero 4
+";
+        let program = Program::parse(src).unwrap();
+        let expected = [
+            "<input>:",
+            "    1                       .globl _start",
+            "    2                       _start:",
+            "    3 00000000 00000097       call f",
+            "      00000004 000080e7",
+            "    4                       .data",
+            "    5                       msg:",
+            "    6 00000008 68 65 6c 6c    .string \"hello world\"",
+            "      0000000c 6f 20 77 6f",
+            "      00000010 72 6c 64 00",
+            "    7 00000014 00 00 00 00    .zero 40",
+            "      00000018 00 00 00 00",
+            "      0000001c 00 00 00 00",
+            "      00000020 00 00 00 00",
+            "      00000024 00 00 00 00",
+            "      00000028 00 00 00 00",
+            "      0000002c 00 00 00 00",
+            "      00000030 00 00 00 00",
+            "               ...",
+            "    8                       .bss",
+            "    9 0000003c                .zero 4",
+            "",
+            "Symbols:",
+            "  _start               00000000 .text      global",
+            "  msg                  00000008 .data      local",
+            "",
+        ];
+        assert_eq!(program.listing().unwrap(), expected.join("\n"));
+    }
+}
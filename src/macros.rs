@@ -0,0 +1,204 @@
+use std::{ops::Range, rc::Rc};
+
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, take_while1},
+    character::complete::{char, space0},
+    combinator::{map, opt, recognize, value},
+    sequence::{delimited, preceded, tuple},
+};
+
+use crate::{
+    directive::parse_string,
+    error::{AsmErrorKind, IResult},
+    imm::{is_sym_char, parse_sym},
+    span::Span,
+};
+
+#[derive(Debug)]
+pub struct Macro {
+    pub params: Vec<Param>,
+    pub body: Rc<str>,
+    /// Source of the body and the number of its first line, for reporting
+    /// errors in expansions.
+    pub file: String,
+    pub first_line: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Param {
+    pub name: String,
+    pub kind: ParamKind,
+    pub default: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamKind {
+    Opt,
+    Req,
+    /// `name:vararg`, takes all remaining arguments.
+    Vararg,
+}
+
+/// Parses a macro name. Unlike symbols they can contain dots.
+pub fn parse_name(input: Span<'_>) -> IResult<'_, Span<'_>> {
+    take_while1(|c| is_sym_char(c) || c == '.')(input)
+}
+
+impl Param {
+    /// Parses `name[:req|:vararg][=default]`.
+    pub fn parse(input: Span<'_>) -> IResult<'_, Self> {
+        map(
+            tuple((
+                parse_sym,
+                opt(preceded(
+                    char(':'),
+                    alt((
+                        value(ParamKind::Req, tag("req")),
+                        value(ParamKind::Vararg, tag("vararg")),
+                    )),
+                )),
+                opt(preceded(
+                    delimited(space0, char('='), space0),
+                    alt((
+                        recognize(parse_string),
+                        take_while1(|c: char| c != ',' && !c.is_whitespace()),
+                    )),
+                )),
+            )),
+            |(name, kind, default)| Self {
+                name: name.to_string(),
+                kind: kind.unwrap_or(ParamKind::Opt),
+                default: default.map(|default| default.to_string()),
+            },
+        )(input)
+    }
+}
+
+impl Macro {
+    /// Binds arguments of an invocation to the params. Arguments are
+    /// positional or `name=value`, omitted ones take the default.
+    pub fn bind(&self, args: &str) -> Result<Vec<String>, AsmErrorKind> {
+        let mut values = vec![None; self.params.len()];
+        let mut next = 0;
+
+        let parts = split_args(args);
+        let end = parts.last().map(|part| part.end).unwrap_or_default();
+
+        for part in &parts {
+            let arg = args[part.clone()].trim();
+            let keyword = arg.split_once('=').and_then(|(name, value)| {
+                self.params
+                    .iter()
+                    .position(|param| param.name == name.trim_end())
+                    .map(|idx| (idx, value.trim_start()))
+            });
+
+            match keyword {
+                Some((idx, value)) => values[idx] = Some(value),
+                None => {
+                    let param = self
+                        .params
+                        .get(next)
+                        .ok_or(AsmErrorKind::TooManyMacroArgs)?;
+                    if param.kind == ParamKind::Vararg {
+                        values[next] = Some(args[part.start..end].trim());
+                        break;
+                    }
+
+                    values[next] = Some(arg);
+                    next += 1;
+                }
+            }
+        }
+
+        self.params
+            .iter()
+            .zip(values)
+            .map(
+                |(param, value)| match value.filter(|value| !value.is_empty()) {
+                    Some(value) => Ok(value.to_string()),
+                    None if param.kind == ParamKind::Req => {
+                        Err(AsmErrorKind::MissingMacroArg(param.name.clone()))
+                    }
+                    None => Ok(param.default.clone().unwrap_or_default()),
+                },
+            )
+            .collect()
+    }
+
+    /// Substitutes `\param` with the arguments, `\@` with the number of
+    /// the expansion, and removes `\()` separators.
+    pub fn expand(&self, args: &[String], count: u32) -> String {
+        let mut text = String::with_capacity(self.body.len());
+        let mut rest = &*self.body;
+
+        while let Some(pos) = rest.find('\\') {
+            text.push_str(&rest[..pos]);
+            rest = &rest[pos + 1..];
+
+            if let Some(after) = rest.strip_prefix('@') {
+                text.push_str(&count.to_string());
+                rest = after;
+            } else if let Some(after) = rest.strip_prefix("()") {
+                rest = after;
+            } else if let Some(after) = rest.strip_prefix('\\') {
+                text.push_str("\\\\");
+                rest = after;
+            } else {
+                let len = rest.find(|c| !is_sym_char(c)).unwrap_or(rest.len());
+                match self
+                    .params
+                    .iter()
+                    .position(|param| param.name == rest[..len])
+                {
+                    Some(idx) if len > 0 => {
+                        text.push_str(&args[idx]);
+                        rest = &rest[len..];
+                    }
+                    _ => text.push('\\'),
+                }
+            }
+        }
+        text.push_str(rest);
+
+        text
+    }
+}
+
+/// Splits arguments at commas outside of strings and parentheses, up to a
+/// comment.
+fn split_args(args: &str) -> Vec<Range<usize>> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut end = args.len();
+    let mut depth = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (idx, c) in args.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            _ if quoted => {}
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(start..idx);
+                start = idx + 1;
+            }
+            '#' => {
+                end = idx;
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    if !args[start..end].trim().is_empty() || !parts.is_empty() {
+        parts.push(start..end);
+    }
+
+    parts
+}
@@ -1,16 +1,298 @@
-use anyhow::anyhow;
-use riscv_asm::{error::AsmError, program::Program};
+use std::{env, fs, path::PathBuf, process};
 
-fn dump_code(input: &str) -> Result<(), AsmError<'_>> {
-    Program::parse(input)?.dump_code()
+use anyhow::{anyhow, bail, Context};
+use riscv_asm::{
+    elf::{read_object, ElfClass, ElfConfig},
+    link::{link, Object},
+    mem::{MemConfig, MemFormat},
+    program::{Config, Program},
+};
+
+const USAGE: &str = "\
+Usage: riscv_asm [options] <input>...
+
+Several inputs, or object files (.o) among them, are linked into one
+program.
+
+Options:
+  -o <file>          Output file, the hex dump is printed without it
+  -O <format>        Output format: dump, bin, ihex, srec, memh, memb, coe,
+                     mif, obj or exec (default: from the extension of the
+                     output file, .o, .elf, .hex, .srec, .mem, .coe, .mif
+                     or .bin)
+  -l <file>          Write a listing of the source with addresses and
+                     contents
+  --map <file>       Write a map of sections, memory regions and symbols
+  --region <name>:<origin>:<length>[:rw]
+                     Add a memory region, writable with ':rw'. Sizes may
+                     have a K or M suffix
+  -I <dir>           Add an include directory
+  -D <name>[=<val>]  Define an absolute symbol (default value 1)
+  --base <addr>      Address of the first section
+  --rvc              Compressed instructions are available
+  --fill <byte>      Fill of gaps between sections in flat binaries
+  --width <bytes>    Word width of memory initialization files (default: 4)
+  --depth <words>    Pad memory initialization files up to the depth
+  --no-addr          Leave out @addr records of $readmem files
+  --annotate         Add source lines to memory initialization files
+  --entry <sym>      Entry point of executables (default: _start)
+  --elf64            Write ELF64 files
+  -g                 Add line information of the source to ELF files
+  -h, --help         Print this help
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Dump,
+    Bin,
+    IntelHex,
+    Srec,
+    Mem(MemFormat),
+    Obj,
+    Exec,
+}
+
+#[derive(Debug, Default)]
+struct Args {
+    inputs: Vec<PathBuf>,
+    output: Option<PathBuf>,
+    listing: Option<PathBuf>,
+    map: Option<PathBuf>,
+    format: Option<Format>,
+    config: Config,
+    elf: ElfConfig,
+    mem: MemConfig,
+    fill: u8,
+    entry: Option<String>,
+}
+
+/// Parses a decimal or `0x` hex number, optionally with a `K` or `M`
+/// suffix.
+fn parse_num(num: &str) -> anyhow::Result<i64> {
+    let (s, neg) = match num.strip_prefix('-') {
+        Some(s) => (s, true),
+        None => (num, false),
+    };
+    let (s, scale) = match s.strip_suffix(['K', 'k']) {
+        Some(s) => (s, 1 << 10),
+        None => match s.strip_suffix(['M', 'm']) {
+            Some(s) => (s, 1 << 20),
+            None => (s, 1),
+        },
+    };
+    let value = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => i64::from_str_radix(&hex.replace('_', ""), 16),
+        None => s.replace('_', "").parse(),
+    }
+    .map_err(|_| anyhow!("invalid number '{num}'"))?;
+
+    let value = value
+        .checked_mul(scale)
+        .ok_or_else(|| anyhow!("number out of range '{num}'"))?;
+
+    Ok(if neg { -value } else { value })
+}
+
+fn parse_args() -> anyhow::Result<Args> {
+    let mut args = Args::default();
+    let mut iter = env::args().skip(1);
+
+    while let Some(arg) = iter.next() {
+        let mut value = || {
+            iter.next()
+                .ok_or_else(|| anyhow!("missing value of '{arg}'"))
+        };
+
+        match arg.as_str() {
+            "-h" | "--help" => {
+                print!("{USAGE}");
+                process::exit(0);
+            }
+            "-o" => args.output = Some(value()?.into()),
+            "-O" => {
+                args.format = Some(match value()?.as_str() {
+                    "dump" => Format::Dump,
+                    "bin" => Format::Bin,
+                    "ihex" => Format::IntelHex,
+                    "srec" => Format::Srec,
+                    "memh" => Format::Mem(MemFormat::ReadMemH),
+                    "memb" => Format::Mem(MemFormat::ReadMemB),
+                    "coe" => Format::Mem(MemFormat::Coe),
+                    "mif" => Format::Mem(MemFormat::Mif),
+                    "obj" => Format::Obj,
+                    "exec" => Format::Exec,
+                    format => bail!("unknown format '{format}'"),
+                })
+            }
+            "-l" => args.listing = Some(value()?.into()),
+            "--map" => args.map = Some(value()?.into()),
+            "--region" => {
+                let region = value()?;
+                let parts: Vec<&str> = region.split(':').collect();
+                let (name, origin, length, writable) = match parts[..] {
+                    [name, origin, length] => (name, origin, length, false),
+                    [name, origin, length, "rw"] => (name, origin, length, true),
+                    _ => bail!("invalid region '{region}'"),
+                };
+                let origin =
+                    u32::try_from(parse_num(origin)?).context("origin out of range")?;
+                let length =
+                    u32::try_from(parse_num(length)?).context("length out of range")?;
+                args.config = args.config.region(name, origin, length, writable);
+            }
+            "-I" => args.config.include_dirs.push(value()?.into()),
+            "-D" => {
+                let def = value()?;
+                let (name, value) = match def.split_once('=') {
+                    Some((name, value)) => (name.to_string(), parse_num(value)?),
+                    None => (def, 1),
+                };
+                args.config.defines.push((name, value));
+            }
+            "--base" => {
+                args.config.base = u32::try_from(parse_num(&value()?)?)
+                    .context("base address out of range")?
+            }
+            "--rvc" => args.config.rvc = true,
+            "--fill" => {
+                args.fill = u8::try_from(parse_num(&value()?)?)
+                    .context("fill byte out of range")?
+            }
+            "--width" => {
+                args.mem.width = u32::try_from(parse_num(&value()?)?)
+                    .context("word width out of range")?
+            }
+            "--depth" => {
+                args.mem.depth = Some(
+                    u32::try_from(parse_num(&value()?)?).context("depth out of range")?,
+                )
+            }
+            "--no-addr" => args.mem.addresses = false,
+            "--annotate" => args.mem.annotate = true,
+            "--entry" => args.entry = Some(value()?),
+            "--elf64" => args.elf.class = ElfClass::Elf64,
+            "-g" => args.elf.debug = true,
+            _ if arg.starts_with('-') => bail!("unknown option '{arg}'\n\n{USAGE}"),
+            _ => args.inputs.push(arg.into()),
+        }
+    }
+
+    Ok(args)
 }
 
 fn main() -> anyhow::Result<()> {
-    let program = r#"
-start:      mv a0, zero
-counter:    addi a0, a0, 1
-            beq zero, zero, counter
-    "#;
+    let mut args = parse_args()?;
+    if args.inputs.is_empty() {
+        bail!("no input\n\n{USAGE}");
+    }
+
+    let format = args.format.unwrap_or_else(|| {
+        let ext = args.output.as_ref().and_then(|output| output.extension());
+        match ext.and_then(|ext| ext.to_str()) {
+            None if args.output.is_none() => Format::Dump,
+            Some("o") => Format::Obj,
+            Some("elf") => Format::Exec,
+            Some("hex" | "ihex") => Format::IntelHex,
+            Some("srec" | "s19" | "s28" | "s37" | "mot") => Format::Srec,
+            Some("mem") => Format::Mem(MemFormat::ReadMemH),
+            Some("coe") => Format::Mem(MemFormat::Coe),
+            Some("mif") => Format::Mem(MemFormat::Mif),
+            _ => Format::Bin,
+        }
+    });
+    args.config.paged = matches!(format, Format::Exec);
+
+    let is_object = |input: &PathBuf| input.extension().is_some_and(|ext| ext == "o");
+    let bytes = if args.inputs.len() > 1 || args.inputs.iter().any(is_object) {
+        link_inputs(&args, format)?
+    } else {
+        assemble(&args, &args.inputs[0], format)?
+    };
+    let Some(bytes) = bytes else {
+        return Ok(());
+    };
+
+    let output = args
+        .output
+        .as_ref()
+        .ok_or_else(|| anyhow!("no output file for binary format"))?;
+    fs::write(output, bytes)
+        .with_context(|| format!("cannot write '{}'", output.display()))?;
+
+    Ok(())
+}
+
+/// Output of a single source, `None` if it was printed.
+fn assemble(
+    args: &Args,
+    input: &PathBuf,
+    format: Format,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let program = Program::parse_file(input, args.config.clone())?;
+    if let Some(map) = &args.map {
+        fs::write(map, program.map())
+            .with_context(|| format!("cannot write '{}'", map.display()))?;
+    }
+    if let Some(listing) = &args.listing {
+        fs::write(listing, program.listing()?)
+            .with_context(|| format!("cannot write '{}'", listing.display()))?;
+    }
+
+    Ok(Some(match format {
+        Format::Dump => {
+            program.dump_code()?;
+            return Ok(None);
+        }
+        Format::Bin => program.flat_binary(args.fill)?,
+        Format::IntelHex => program.image()?.to_ihex()?.into_bytes(),
+        Format::Srec => program.image()?.to_srec()?.into_bytes(),
+        Format::Mem(format) => {
+            let config = MemConfig {
+                fill: args.fill,
+                ..args.mem
+            };
+            program.mem_init(format, &config)?.into_bytes()
+        }
+        Format::Obj => program.elf_object(&args.elf)?,
+        Format::Exec => program.elf_executable(&args.elf, args.entry.as_deref())?,
+    }))
+}
+
+/// Output of the sources and object files linked together, `None` if it
+/// was printed.
+fn link_inputs(args: &Args, format: Format) -> anyhow::Result<Option<Vec<u8>>> {
+    if args.listing.is_some() || args.map.is_some() {
+        bail!("listings and maps are not supported when linking");
+    }
+
+    let mut objects = Vec::new();
+    for input in &args.inputs {
+        let name = input.display().to_string();
+        let object = if input.extension().is_some_and(|ext| ext == "o") {
+            let data =
+                fs::read(input).with_context(|| format!("cannot read '{name}'"))?;
+            read_object(&name, &data)?
+        } else {
+            let program = Program::parse_file(input, args.config.clone())?;
+            Object::from_program(&program, &name)?
+        };
+        objects.push(object);
+    }
+    let linked = link(objects, &args.config)?;
 
-    dump_code(program).map_err(|e| anyhow!("{}", e.to_str(program)))
+    Ok(Some(match format {
+        Format::Dump => {
+            linked.dump_code();
+            return Ok(None);
+        }
+        Format::Bin => linked.image().flat(args.fill)?,
+        Format::IntelHex => linked.image().to_ihex()?.into_bytes(),
+        Format::Srec => linked.image().to_srec()?.into_bytes(),
+        Format::Exec => linked.elf_executable(&args.elf, args.entry.as_deref())?,
+        Format::Mem(_) | Format::Obj => {
+            bail!(
+                "memory initialization files and objects are not supported when linking"
+            )
+        }
+    }))
 }
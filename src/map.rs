@@ -0,0 +1,180 @@
+use std::fmt::Write;
+
+use crate::program::Program;
+
+impl Program {
+    /// Map of the program: sections with their sizes, usage of the memory
+    /// regions and every symbol with its value, size and binding, in the
+    /// order of addresses.
+    pub fn map(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("Sections:\n");
+        writeln!(
+            out,
+            "  {:20} {:8} {:8} {:>5} Flags",
+            "Name", "Address", "Size", "Align"
+        )
+        .unwrap();
+        let mut total = 0u64;
+        for section in self.sections() {
+            let flags = section.flags;
+            let flags: String = [
+                (flags.alloc, 'a'),
+                (flags.write, 'w'),
+                (flags.exec, 'x'),
+                (flags.nobits, 'b'),
+            ]
+            .iter()
+            .filter_map(|(set, c)| set.then_some(*c))
+            .collect();
+
+            writeln!(
+                out,
+                "  {:20} {:08x} {:08x} {:5} {flags}",
+                section.name,
+                section.addr,
+                section.size(),
+                section.align
+            )
+            .unwrap();
+            total += section.size() as u64;
+        }
+        writeln!(out, "  {:20} {:8} {total:08x}", "Total", "").unwrap();
+
+        let regions = &self.config().regions;
+        if !regions.is_empty() {
+            out.push_str("\nMemory regions:\n");
+            writeln!(
+                out,
+                "  {:10} {:8} {:8} {:8} {:8} {:>6}",
+                "Name", "Origin", "Length", "Used", "Free", "Use%"
+            )
+            .unwrap();
+
+            for (idx, region) in regions.iter().enumerate() {
+                let end = self
+                    .sections()
+                    .iter()
+                    .filter(|section| {
+                        section.flags.alloc
+                            && self.config().region_for(section.flags) == Some(idx)
+                    })
+                    .map(|section| section.addr as u64 + section.size() as u64)
+                    .max()
+                    .unwrap_or(region.origin as u64);
+                let used = end - region.origin as u64;
+                let free = (region.length as u64).saturating_sub(used);
+                let usage = match region.length {
+                    0 => 0.0,
+                    length => used as f64 * 100.0 / length as f64,
+                };
+
+                writeln!(
+                    out,
+                    "  {:10} {:08x} {:08x} {used:08x} {free:08x} {usage:5.1}%",
+                    region.name, region.origin, region.length
+                )
+                .unwrap();
+            }
+        }
+
+        let mut symbols: Vec<_> = self.symbols().collect();
+        symbols.sort_by_key(|(name, sym)| {
+            (
+                sym.value.is_none(),
+                sym.value.map(|value| self.addr(value)),
+                *name,
+            )
+        });
+
+        out.push_str("\nSymbols:\n");
+        writeln!(
+            out,
+            "  {:8} {:8} {:7} {:10} Name",
+            "Address", "Size", "Binding", "Section"
+        )
+        .unwrap();
+        for (name, sym) in symbols {
+            let binding = sym.binding.name();
+            let (value, section) = match sym.value {
+                Some(value) => (
+                    format!("{:08x}", self.addr(value) as u32),
+                    value
+                        .section
+                        .map_or("*ABS*", |section| &self.sections()[section].name),
+                ),
+                None => (String::new(), "*UND*"),
+            };
+            let size = sym
+                .size
+                .map(|size| format!("{size:08x}"))
+                .unwrap_or_default();
+
+            writeln!(out, "  {value:8} {size:8} {binding:7} {section:10} {name}")
+                .unwrap();
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::AsmErrorKind, program::Config};
+
+    #[test]
+    fn map() {
+        let src = "
+.globl _start
+.weak ext
+_start:
+  jal ra, f
+f:
+  jal ra, ext
+.size f, 4
+.data
+buf:
+  .zero 6
+LEN = 6
+";
+        let config = Config::default()
+            .region("rom", 0, 0x100, false)
+            .region("ram", 0x1000, 0x10, true);
+        let program = Program::parse_with_config(src, config).unwrap();
+        let expected = [
+            "Sections:",
+            "  Name                 Address  Size     Align Flags",
+            "  .text                00000000 00000008     4 ax",
+            "  .data                00001000 00000006     1 aw",
+            "  Total                         0000000e",
+            "",
+            "Memory regions:",
+            "  Name       Origin   Length   Used     Free       Use%",
+            "  rom        00000000 00000100 00000008 000000f8   3.1%",
+            "  ram        00001000 00000010 00000006 0000000a  37.5%",
+            "",
+            "Symbols:",
+            "  Address  Size     Binding Section    Name",
+            "  00000000          global  .text      _start",
+            "  00000004 00000004 local   .text      f",
+            "  00000006          local   *ABS*      LEN",
+            "  00001000          local   .data      buf",
+            "                    weak    *UND*      ext",
+            "",
+        ];
+        assert_eq!(program.map(), expected.join("\n"));
+    }
+
+    #[test]
+    fn region_overflow() {
+        let config = Config::default().region("ram", 0x1000, 0x10, true);
+        let error =
+            Program::parse_with_config(".data\n  .zero 0x18", config).unwrap_err();
+        assert!(matches!(
+            error.kind,
+            AsmErrorKind::RegionOverflow(name, 8) if &*name == "ram"
+        ));
+    }
+}
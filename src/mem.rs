@@ -0,0 +1,327 @@
+use std::fmt::Write;
+
+use crate::{
+    error::{AsmErrorKind, Error},
+    image::Image,
+    program::Program,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemFormat {
+    ReadMemH,
+    ReadMemB,
+    /// Xilinx coefficient file.
+    Coe,
+    /// Intel (Altera) memory initialization file.
+    Mif,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MemConfig {
+    /// Bytes per word, words are little-endian.
+    pub width: u32,
+    /// Number of words of the memory, the image is padded up to it. By
+    /// default the memory is as large as the image.
+    pub depth: Option<u32>,
+    /// Value of bytes not covered by sections.
+    pub fill: u8,
+    /// Start `$readmem` blocks with `@addr` records holding the word
+    /// address, instead of one block starting at the lowest address.
+    pub addresses: bool,
+    /// Add the source line of the first item in each word as a comment.
+    pub annotate: bool,
+}
+
+impl Default for MemConfig {
+    fn default() -> Self {
+        Self {
+            width: 4,
+            depth: None,
+            fill: 0,
+            addresses: true,
+            annotate: false,
+        }
+    }
+}
+
+struct Run {
+    addr: u32,
+    data: Vec<u8>,
+}
+
+struct Word<'p> {
+    value: u64,
+    comment: Option<&'p str>,
+}
+
+impl Program {
+    /// Writes the image as a memory initialization file. Without `@addr`
+    /// records word 0 of the memory holds the lowest address of the image.
+    pub fn mem_init(
+        &self,
+        format: MemFormat,
+        config: &MemConfig,
+    ) -> Result<String, Error> {
+        let width = config.width;
+        if !matches!(width, 1 | 2 | 4 | 8) {
+            return Err(AsmErrorKind::InvalidWordWidth(width).into());
+        }
+
+        let image = self.image()?;
+        let addresses = config.addresses
+            && matches!(format, MemFormat::ReadMemH | MemFormat::ReadMemB);
+        let mut runs = runs(&image, width, config.fill, addresses)?;
+
+        let count: u32 = runs.iter().map(|run| run.data.len() as u32 / width).sum();
+        if let Some(depth) = config.depth {
+            if count > depth {
+                return Err(AsmErrorKind::DepthExceeded(depth).into());
+            }
+            if let (false, Some(run)) = (addresses, runs.last_mut()) {
+                run.data.resize(
+                    run.data.len() + ((depth - count) * width) as usize,
+                    config.fill,
+                );
+            }
+        }
+
+        let lines = self.item_lines();
+        let mut out = String::new();
+        let digits = width as usize * 2;
+        let bits = width as usize * 8;
+
+        match format {
+            MemFormat::ReadMemH | MemFormat::ReadMemB => {
+                for run in &runs {
+                    if addresses {
+                        writeln!(out, "@{:x}", run.addr / width).unwrap();
+                    }
+                    for word in self.words(run, width, config.annotate, &lines) {
+                        match format {
+                            MemFormat::ReadMemH => {
+                                write!(out, "{:0digits$x}", word.value)
+                            }
+                            _ => write!(out, "{:0bits$b}", word.value),
+                        }
+                        .unwrap();
+                        push_comment(&mut out, "//", word.comment);
+                    }
+                }
+            }
+            MemFormat::Coe => {
+                out.push_str("memory_initialization_radix=16;\n");
+                out.push_str("memory_initialization_vector=\n");
+
+                let words: Vec<Word<'_>> = runs
+                    .iter()
+                    .flat_map(|run| self.words(run, width, config.annotate, &lines))
+                    .collect();
+                for (idx, word) in words.iter().enumerate() {
+                    let sep = if idx + 1 == words.len() { ';' } else { ',' };
+                    // Comments take whole lines in COE files.
+                    if let Some(comment) = word.comment {
+                        writeln!(out, "; {comment}").unwrap();
+                    }
+                    writeln!(out, "{:0digits$x}{sep}", word.value).unwrap();
+                }
+                if words.is_empty() {
+                    out.push_str("0;\n");
+                }
+            }
+            MemFormat::Mif => {
+                let depth = config.depth.unwrap_or(count).max(1);
+                writeln!(out, "DEPTH = {depth};").unwrap();
+                writeln!(out, "WIDTH = {bits};").unwrap();
+                out.push_str("ADDRESS_RADIX = HEX;\nDATA_RADIX = HEX;\nCONTENT\nBEGIN\n");
+
+                let words = runs
+                    .iter()
+                    .flat_map(|run| self.words(run, width, config.annotate, &lines));
+                for (idx, word) in words.enumerate() {
+                    write!(out, "{idx:x} : {:0digits$x};", word.value).unwrap();
+                    push_comment(&mut out, "--", word.comment);
+                }
+                if count == 0 {
+                    writeln!(out, "[0..{:x}] : {:0digits$x};", depth - 1, 0).unwrap();
+                }
+                out.push_str("END;\n");
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// Addresses of the items in loaded sections along with their lines, in
+    /// the order of addresses.
+    fn item_lines(&self) -> Vec<(u32, usize)> {
+        let mut lines: Vec<(u32, usize)> = self
+            .sections()
+            .iter()
+            .filter(|section| section.flags.alloc && !section.flags.nobits)
+            .flat_map(|section| {
+                section
+                    .items
+                    .iter()
+                    .filter(|entry| entry.item.size() > 0)
+                    .map(|entry| (section.addr + entry.offset, entry.line))
+            })
+            .collect();
+        lines.sort_by_key(|(addr, _)| *addr);
+
+        lines
+    }
+
+    /// Splits a run into words, annotated with the line of the first item
+    /// starting in the word.
+    fn words<'p>(
+        &'p self,
+        run: &Run,
+        width: u32,
+        annotate: bool,
+        lines: &[(u32, usize)],
+    ) -> Vec<Word<'p>> {
+        run.data
+            .chunks(width as usize)
+            .enumerate()
+            .map(|(idx, bytes)| {
+                let mut value = [0; 8];
+                value[..bytes.len()].copy_from_slice(bytes);
+
+                let addr = run.addr + idx as u32 * width;
+                let first = lines.partition_point(|(start, _)| *start < addr);
+                let comment = lines
+                    .get(first)
+                    .filter(|(start, _)| annotate && *start < addr + width)
+                    .map(|(_, line)| {
+                        self.text(*line, &self.lines()[*line].offset).trim()
+                    });
+
+                Word {
+                    value: u64::from_le_bytes(value),
+                    comment,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Splits the image into runs of whole words. Chunks sharing or touching
+/// words are merged, all of them without `@addr` records.
+fn runs(image: &Image, width: u32, fill: u8, addresses: bool) -> Result<Vec<Run>, Error> {
+    let mut runs: Vec<Run> = Vec::new();
+
+    for chunk in &image.chunks {
+        let addr = chunk.addr - chunk.addr % width;
+        let end = chunk.end()?;
+        let end = end.checked_next_multiple_of(width).ok_or_else(|| {
+            let padded = (end as u64).next_multiple_of(width as u64);
+            AsmErrorKind::AddressOverflow(padded)
+        })?;
+
+        let run = match runs.last_mut() {
+            Some(run) if !addresses || addr <= run.addr + run.data.len() as u32 => run,
+            _ => {
+                runs.push(Run {
+                    addr,
+                    data: Vec::new(),
+                });
+                runs.last_mut().unwrap()
+            }
+        };
+
+        run.data
+            .resize(run.data.len().max((end - run.addr) as usize), fill);
+        let offset = (chunk.addr - run.addr) as usize;
+        run.data[offset..offset + chunk.data.len()].copy_from_slice(&chunk.data);
+    }
+
+    Ok(runs)
+}
+
+fn push_comment(out: &mut String, prefix: &str, comment: Option<&str>) {
+    if let Some(comment) = comment {
+        write!(out, " {prefix} {comment}").unwrap();
+    }
+    out.push('\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::program::Config;
+
+    const CODE: &str = "  addi a0, a0, 1\n  addi a0, a0, 2";
+
+    #[test]
+    fn readmem() {
+        let config = Config::default()
+            .region("rom", 0, 0x100, false)
+            .region("ram", 0x100, 0x100, true);
+        let program =
+            Program::parse_with_config("  addi a0, a0, 1\n.data\n  .half 0x1234", config)
+                .unwrap();
+        let mem = program.mem_init(MemFormat::ReadMemH, &MemConfig::default());
+        assert_eq!(mem.unwrap(), "@0\n00150513\n@40\n00001234\n");
+
+        let program = Program::parse(CODE).unwrap();
+        let config = MemConfig {
+            width: 2,
+            addresses: false,
+            ..Default::default()
+        };
+        let mem = program.mem_init(MemFormat::ReadMemB, &config).unwrap();
+        assert_eq!(
+            mem,
+            "0000010100010011\n0000000000010101\n0000010100010011\n0000000000100101\n"
+        );
+    }
+
+    #[test]
+    fn coe() {
+        let program = Program::parse(CODE).unwrap();
+        let config = MemConfig {
+            annotate: true,
+            ..Default::default()
+        };
+        let mem = program.mem_init(MemFormat::Coe, &config).unwrap();
+        assert_eq!(
+            mem,
+            "memory_initialization_radix=16;\nmemory_initialization_vector=\n\
+             ; addi a0, a0, 1\n00150513,\n; addi a0, a0, 2\n00250513;\n"
+        );
+    }
+
+    #[test]
+    fn mif() {
+        let program = Program::parse(CODE).unwrap();
+        let config = MemConfig {
+            depth: Some(4),
+            ..Default::default()
+        };
+        let mem = program.mem_init(MemFormat::Mif, &config).unwrap();
+        assert_eq!(
+            mem,
+            "DEPTH = 4;\nWIDTH = 32;\nADDRESS_RADIX = HEX;\nDATA_RADIX = HEX;\n\
+             CONTENT\nBEGIN\n0 : 00150513;\n1 : 00250513;\n2 : 00000000;\n\
+             3 : 00000000;\nEND;\n"
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let program = Program::parse(CODE).unwrap();
+        let config = MemConfig {
+            width: 3,
+            ..Default::default()
+        };
+        let error = program.mem_init(MemFormat::Coe, &config).unwrap_err();
+        assert!(matches!(error.kind, AsmErrorKind::InvalidWordWidth(3)));
+
+        let config = MemConfig {
+            depth: Some(1),
+            ..Default::default()
+        };
+        let error = program.mem_init(MemFormat::Mif, &config).unwrap_err();
+        assert!(matches!(error.kind, AsmErrorKind::DepthExceeded(1)));
+    }
+}
@@ -8,11 +8,17 @@ use nom::{character::complete::alpha1, combinator::map_opt};
 use phf::phf_map;
 
 impl OpCode {
-    pub fn parse(input: Span<'_>) -> IResult<Self> {
+    pub fn parse(input: Span<'_>) -> IResult<'_, Self> {
         map_opt(alpha1, |s: Span<'_>| OP_CODE.get(*s).copied())(input).map_err(|e| {
             e.map(|e: AsmError<'_>| e.with_kind(AsmErrorKind::InvalidOpCode))
         })
     }
+
+    /// Shift by an immediate amount, encoded in the lower 5 bits of the
+    /// immediate.
+    pub fn is_shift(&self) -> bool {
+        matches!(self, Self::slli | Self::srli | Self::srai)
+    }
 }
 
 macro_rules! mask {
@@ -22,6 +28,9 @@ macro_rules! mask {
     ($opcode:literal, $f3:literal) => {
         (($f3 & 0x7) << 12) | ($opcode & 0x7f)
     };
+    ($opcode:literal) => {
+        $opcode & 0x7f
+    };
 }
 
 macro_rules! op_code {
@@ -53,7 +62,7 @@ macro_rules! op_code {
         }
 
         literify! {
-            static OP_CODE: phf::Map<&'static str, OpCode> = phf_map! {
+            pub static OP_CODE: phf::Map<&'static str, OpCode> = phf_map! {
                 $(
                     ~($name) => OpCode::$name,
                 )+
@@ -78,9 +87,9 @@ op_code! {
     xori    : I, 0x13, 0x4;         // XOR Immediate
     ori     : I, 0x13, 0x6;         // OR Immediate
     andi    : I, 0x13, 0x7;         // AND Immediate
-    slli    : I, 0x13, 0x5;         // Shift Left Logical Imm
+    slli    : I, 0x13, 0x1;         // Shift Left Logical Imm
     srli    : I, 0x13, 0x5;         // Shift Right Logical Imm
-    srai    : I, 0x13, 0x5;         // Shift Right Arith Imm
+    srai    : I, 0x13, 0x5, 0x20;   // Shift Right Arith Imm
     slti    : I, 0x13, 0x2;         // Set Less Than Imm
     sltiu   : I, 0x13, 0x3;         // Set Less Than Imm (U)
     // lb      : I, 0x03, 0x0;         // Load Byte
@@ -88,5 +97,14 @@ op_code! {
     // lw      : I, 0x03, 0x2;         // Load Word
     // lbu     : I, 0x03, 0x4;         // Load Byte (U)
     // lhu     : I, 0x03, 0x5;         // Load Half (U)
+    jalr    : I, 0x67, 0x0;         // Jump And Link Reg
     beq     : B, 0x63, 0x0;         // Branch ==
+    bne     : B, 0x63, 0x1;         // Branch !=
+    blt     : B, 0x63, 0x4;         // Branch <
+    bge     : B, 0x63, 0x5;         // Branch >=
+    bltu    : B, 0x63, 0x6;         // Branch < (U)
+    bgeu    : B, 0x63, 0x7;         // Branch >= (U)
+    jal     : J, 0x6f;              // Jump And Link
+    lui     : U, 0x37;              // Load Upper Imm
+    auipc   : U, 0x17;              // Add Upper Imm to PC
 }
@@ -0,0 +1,267 @@
+use std::collections::BTreeSet;
+
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, take_while1},
+    character::complete::{char, space0},
+    combinator::{cut, map, map_opt},
+    multi::separated_list1,
+    sequence::{delimited, pair, preceded},
+};
+
+use crate::{
+    error::{AsmError, AsmErrorKind, IResult},
+    span::Span,
+};
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub arch: BTreeSet<String>,
+    pub pic: bool,
+    /// The linker may relax instruction sequences, so references within
+    /// the section are relocated as well.
+    pub relax: bool,
+}
+
+impl Options {
+    pub fn new(rvc: bool) -> Self {
+        let mut this = Self {
+            arch: BTreeSet::from(["i".to_string()]),
+            pic: false,
+            relax: false,
+        };
+        this.set_rvc(rvc);
+
+        this
+    }
+
+    pub fn rvc(&self) -> bool {
+        self.arch.contains("c")
+    }
+
+    fn set_rvc(&mut self, rvc: bool) {
+        if rvc {
+            self.arch.insert("c".into());
+        } else {
+            self.arch.remove("c");
+        }
+    }
+
+    pub fn set(&mut self, option: AsmOption) {
+        match option {
+            AsmOption::Rvc(rvc) => self.set_rvc(rvc),
+            AsmOption::Pic(pic) => self.pic = pic,
+            AsmOption::Relax(relax) => self.relax = relax,
+            AsmOption::Arch(changes) => {
+                for change in changes {
+                    match change {
+                        ArchChange::Set(exts) => self.arch = exts.into_iter().collect(),
+                        ArchChange::Add(exts) => self.arch.extend(exts),
+                        ArchChange::Remove(ext) => {
+                            self.arch.remove(&ext);
+                        }
+                    }
+                }
+            }
+            AsmOption::Push | AsmOption::Pop => {}
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum AsmOption {
+    Rvc(bool),
+    Pic(bool),
+    Relax(bool),
+    /// `arch, rv32imac` or `arch, +zbb, -c`
+    Arch(Vec<ArchChange>),
+    Push,
+    Pop,
+}
+
+#[derive(Debug, Clone)]
+pub enum ArchChange {
+    Set(Vec<String>),
+    /// `+ext`, extensions implied by it included.
+    Add(Vec<String>),
+    Remove(String),
+}
+
+impl AsmOption {
+    pub fn parse(input: Span<'_>) -> IResult<'_, Self> {
+        alt((
+            map(
+                preceded(
+                    pair(tag("arch"), sep),
+                    cut(separated_list1(sep, parse_change)),
+                ),
+                Self::Arch,
+            ),
+            map_opt(
+                take_while1(|c: char| c.is_ascii_alphanumeric()),
+                |s: Span<'_>| Self::from_name(*s),
+            ),
+        ))(input)
+        .map_err(|e| e.map(|e: AsmError<'_>| e.with_kind(AsmErrorKind::InvalidOption)))
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "rvc" => Self::Rvc(true),
+            "norvc" => Self::Rvc(false),
+            "pic" => Self::Pic(true),
+            "nopic" => Self::Pic(false),
+            "relax" => Self::Relax(true),
+            "norelax" => Self::Relax(false),
+            "push" => Self::Push,
+            "pop" => Self::Pop,
+            _ => return None,
+        })
+    }
+}
+
+fn sep(input: Span<'_>) -> IResult<'_, char> {
+    delimited(space0, char(','), space0)(input)
+}
+
+fn parse_change(input: Span<'_>) -> IResult<'_, ArchChange> {
+    let ext = || take_while1(|c: char| c.is_ascii_alphanumeric());
+
+    alt((
+        map_opt(preceded(char('+'), ext()), |s: Span<'_>| {
+            ext_name(&s).map(|ext| ArchChange::Add(implied(ext)))
+        }),
+        map_opt(preceded(char('-'), ext()), |s: Span<'_>| {
+            ext_name(&s).map(|ext| ArchChange::Remove(ext.into()))
+        }),
+        map_opt(
+            take_while1(|c: char| c.is_ascii_alphanumeric() || c == '_'),
+            |s: Span<'_>| parse_isa(&s).map(ArchChange::Set),
+        ),
+    ))(input)
+    .map_err(|e| e.map(|e: AsmError<'_>| e.with_kind(AsmErrorKind::InvalidOption)))
+}
+
+/// Extensions of an ISA string like `rv32imac` or `rv64gc_zbb`.
+fn parse_isa(isa: &str) -> Option<Vec<String>> {
+    let isa = isa.to_ascii_lowercase();
+    let rest = isa
+        .strip_prefix("rv32")
+        .or_else(|| isa.strip_prefix("rv64"))?;
+    if !rest.starts_with(['i', 'e', 'g']) {
+        return None;
+    }
+
+    let mut exts = Vec::new();
+    let mut rest = rest;
+    while let Some(c) = rest.chars().next() {
+        match c {
+            '_' => rest = &rest[1..],
+            'z' | 's' | 'x' => {
+                let end = rest.find('_').unwrap_or(rest.len());
+                exts.extend(implied(ext_name(&rest[..end])?));
+                rest = &rest[end..];
+            }
+            'a'..='y' => {
+                let end = 1 + version_len(&rest[1..]);
+                exts.extend(implied(&rest[..1]));
+                rest = &rest[end..];
+            }
+            _ => return None,
+        }
+    }
+
+    Some(exts)
+}
+
+/// Canonical order of the single letter extensions, which also orders the
+/// `z` extensions by their second letter.
+const CANONICAL_ORDER: &str = "iemafdqlcbkjtpvh";
+
+/// ISA string of the extensions in canonical order with their versions,
+/// like `rv32i2p1_m2p0_zicsr2p0`.
+pub fn isa_string(xlen: u32, exts: &BTreeSet<String>) -> String {
+    let order = |c: Option<char>| {
+        c.and_then(|c| CANONICAL_ORDER.find(c))
+            .unwrap_or(CANONICAL_ORDER.len())
+    };
+    let rank = |ext: &str| {
+        let mut chars = ext.chars();
+        match chars.next() {
+            Some('z') => (1, order(chars.next())),
+            Some('s') => (2, 0),
+            Some('x') => (3, 0),
+            c => (0, order(c)),
+        }
+    };
+
+    let mut exts: Vec<&str> = exts.iter().map(String::as_str).collect();
+    exts.sort_by_key(|ext| (rank(ext), *ext));
+    let exts: Vec<String> = exts
+        .into_iter()
+        .map(|ext| format!("{ext}{}", version(ext)))
+        .collect();
+
+    format!("rv{xlen}{}", exts.join("_"))
+}
+
+fn version(ext: &str) -> &'static str {
+    match ext {
+        "i" | "a" => "2p1",
+        "f" | "d" | "q" => "2p2",
+        "e" | "m" | "c" | "zicsr" | "zifencei" => "2p0",
+        _ => "1p0",
+    }
+}
+
+fn ext_name(ext: &str) -> Option<&str> {
+    let mut name = trim_digits(ext);
+    if name.len() < ext.len() {
+        if let Some(major) = name.strip_suffix('p').map(trim_digits) {
+            if major.len() + 1 < name.len() {
+                name = major;
+            }
+        }
+    }
+
+    let valid = name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit());
+    valid.then_some(name)
+}
+
+fn trim_digits(s: &str) -> &str {
+    s.trim_end_matches(|c: char| c.is_ascii_digit())
+}
+
+/// Length of the version at the start of `s`, like `2p1`.
+fn version_len(s: &str) -> usize {
+    let major = s.len() - s.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    if major == 0 {
+        return 0;
+    }
+
+    let rest = &s[major..];
+    match rest.strip_prefix('p') {
+        Some(minor) if minor.starts_with(|c: char| c.is_ascii_digit()) => {
+            major + 1 + minor.len()
+                - minor.trim_start_matches(|c: char| c.is_ascii_digit()).len()
+        }
+        _ => major,
+    }
+}
+
+/// The extension along with the ones it implies, `g` standing for
+/// `imafd_zicsr_zifencei`.
+fn implied(ext: &str) -> Vec<String> {
+    let exts: &[&str] = match ext {
+        "g" => &["i", "m", "a", "f", "d", "zicsr", "zifencei"],
+        "f" => &["f", "zicsr"],
+        "d" => &["d", "f", "zicsr"],
+        "q" => &["q", "d", "f", "zicsr"],
+        ext => return vec![ext.to_string()],
+    };
+
+    exts.iter().map(|ext| ext.to_string()).collect()
+}
@@ -1,163 +1,2122 @@
-use nom_span::Spanned;
-use std::collections::HashMap;
+use std::{
+    cell::Cell,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs, iter,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
 
 use nom::{
     branch::alt,
-    bytes::complete::{take_until, take_while},
-    character::complete::{anychar, char, multispace0, space0, space1},
-    combinator::{cut, eof, map, not, opt, rest},
-    sequence::{pair, preceded, terminated},
+    character::complete::{char, digit1, space0, space1},
+    combinator::{all_consuming, cut, eof, map, map_res, not, opt, rest},
+    sequence::{delimited, pair, preceded, terminated, tuple},
     Finish, Slice,
 };
 
 use crate::{
-    error::{AsmError, AsmErrorKind, IResult},
-    imm::parse_sym,
+    attribute::{AttrArg, AttrValue},
+    cfi::{CfiInsn, Frame, FrameInfo},
+    data::Data,
+    directive::{AlignKind, Condition, Directive},
+    dwarf::{LineInfo, LineRow},
+    error::{AsmErrorKind, Error, IResult, Location},
+    imm::{parse_sym, Dir, Expr, Imm, LocalRef, Modifier, Target, Value},
     instr::Instr,
+    macros::{parse_name, Macro, Param, ParamKind},
+    op_code::OP_CODE,
+    option::{AsmOption, Options},
+    pseudo::PSEUDO,
+    reg::REGS,
+    reloc::{Fixup, Reloc, RelocKind},
+    section::{Item, Section, SectionFlags},
+    source::{Origin, Source, SourceLine},
     span::{Offset, Span},
+    symbol::{Binding, Symbol},
 };
 
-#[derive(Debug)]
-pub struct Program<'i> {
-    input: &'i str,
-    code: Vec<Instr>,
-    sym: HashMap<String, i32>,
+/// Assembler settings which don't come from the source.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// Compressed instructions are available, so alignment padding in code
+    /// can use `c.nop`.
+    pub rvc: bool,
+    /// Address of the first section, e.g. `0x8000_0000` for code running
+    /// from RAM.
+    pub base: u32,
+    /// Directories searched by `.include` and `.incbin` after the directory
+    /// of the including file.
+    pub include_dirs: Vec<PathBuf>,
+    /// Absolute symbols defined before parsing, like `-D BOARD=2`.
+    pub defines: Vec<(String, i64)>,
+    /// Memory the sections are placed in. Without regions sections follow
+    /// each other from `base`.
+    pub regions: Vec<Region>,
+    /// Sections are loaded by pages, so ones with other permissions than
+    /// the previous section start a new page.
+    pub paged: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Region {
+    pub name: String,
+    pub origin: u32,
+    pub length: u32,
+    /// Writable sections are placed in the first writable region, the
+    /// others in the first read-only one.
+    pub writable: bool,
 }
 
-trait Addresable: Iterator + Sized {
-    fn with_address(self) -> impl Iterator<Item = (u32, Self::Item)> {
-        self.enumerate()
-            .map(|(addr, code)| ((addr << 2) as u32, code))
+impl Config {
+    pub fn define(mut self, name: impl Into<String>, value: i64) -> Self {
+        self.defines.push((name.into(), value));
+        self
+    }
+
+    pub fn region(
+        mut self,
+        name: impl Into<String>,
+        origin: u32,
+        length: u32,
+        writable: bool,
+    ) -> Self {
+        self.regions.push(Region {
+            name: name.into(),
+            origin,
+            length,
+            writable,
+        });
+        self
+    }
+
+    /// Index of the region holding sections with the flags, the first
+    /// region if none matches.
+    pub fn region_for(&self, flags: SectionFlags) -> Option<usize> {
+        self.regions
+            .iter()
+            .position(|region| region.writable == flags.write)
+            .or((!self.regions.is_empty()).then_some(0))
+    }
+
+    /// Addresses of sections given by their flags, alignment and size.
+    /// Allocatable ones follow each other, in their memory regions if there
+    /// are any, the others are at 0.
+    pub fn place(
+        &self,
+        sections: impl IntoIterator<Item = (SectionFlags, u32, u32)>,
+    ) -> Result<Vec<u32>, Error> {
+        let mut addr = self.base;
+        // Permissions of the last section in each region, then outside.
+        let mut perms = vec![None; self.regions.len() + 1];
+        let mut ends: Vec<u64> = self
+            .regions
+            .iter()
+            .map(|region| region.origin as u64)
+            .collect();
+
+        let mut addrs = Vec::new();
+        for (flags, align, size) in sections {
+            if !flags.alloc {
+                addrs.push(0);
+                continue;
+            }
+
+            let region = self.region_for(flags);
+            let last = perms[region.unwrap_or(self.regions.len())]
+                .replace((flags.write, flags.exec));
+            let align = match last {
+                Some(last) if self.paged && last != (flags.write, flags.exec) => {
+                    align.max(PAGE)
+                }
+                _ => align,
+            };
+
+            match region {
+                Some(idx) => {
+                    let start = ends[idx].next_multiple_of(align as u64);
+                    addrs.push(start as u32);
+                    ends[idx] = start + size as u64;
+                }
+                None => {
+                    let overflow = || {
+                        let end =
+                            (addr as u64).next_multiple_of(align as u64) + size as u64;
+                        AsmErrorKind::AddressOverflow(end)
+                    };
+                    let start =
+                        addr.checked_next_multiple_of(align).ok_or_else(overflow)?;
+                    addrs.push(start);
+                    addr = start.checked_add(size).ok_or_else(overflow)?;
+                }
+            }
+        }
+
+        for (region, end) in self.regions.iter().zip(ends) {
+            let limit = region.origin as u64 + region.length as u64;
+            if end > limit {
+                return Err(AsmErrorKind::RegionOverflow(
+                    region.name.as_str().into(),
+                    u32::try_from(end - limit).unwrap_or(u32::MAX),
+                )
+                .into());
+            }
+        }
+
+        Ok(addrs)
     }
 }
 
-impl<I: Iterator> Addresable for I {}
+#[derive(Debug)]
+pub struct Program {
+    config: Config,
+    sources: Vec<Source>,
+    lines: Vec<SourceLine>,
+    sections: Vec<Section>,
+    curr: usize,
+    sym: HashMap<String, Symbol>,
+    local: HashMap<u32, Vec<(usize, Value)>>,
+    /// Expressions of the symbols defined by `=`, in the order of their
+    /// lines, evaluated where the symbols are used.
+    assigns: HashMap<String, Vec<(Site, Imm)>>,
+    /// Depth of the assignments being evaluated, to catch circular ones.
+    assign_depth: Cell<usize>,
+    macros: HashMap<String, Rc<Macro>>,
+    /// Number of macro expansions so far, substituted for `\@`.
+    expansions: u32,
+    /// Size of the text generated by expansions so far.
+    expanded: usize,
+    /// Block whose lines are being collected.
+    block: Option<Block>,
+    /// `.exitm` was met, lines up to the end of the macro are skipped.
+    exit: bool,
+    /// Open conditionals, innermost last.
+    conds: Vec<Cond>,
+    line_info: LineInfo,
+    frame_info: FrameInfo,
+    attributes: BTreeMap<u32, AttrValue>,
+    options: Options,
+    option_stack: Vec<Options>,
+    arch: BTreeSet<String>,
+}
+
+/// Size of the pages of loaded executables, which have a single set of
+/// permissions.
+pub const PAGE: u32 = 0x1000;
+
+/// Maximal depth of nested macro expansions and includes.
+const MAX_DEPTH: usize = 100;
+
+/// Maximal size of all the text generated by macros and repetitions.
+const MAX_EXPANSION: usize = 1 << 24;
+
+/// Size counted for each expansion besides its text, bounding the number
+/// of empty ones.
+const EXPANSION_COST: usize = 64;
+
+/// Contents of a section along with the relocations to apply to them.
+pub type Relocatable<'p> = (&'p Section, Vec<u8>, Vec<Reloc>);
+
+/// Where an expression is evaluated: the location counter and the line the
+/// expression comes from.
+#[derive(Debug, Clone, Copy)]
+pub struct Site {
+    pub here: Value,
+    pub line: usize,
+}
+
+impl Program {
+    pub fn resolve(&self, site: Site, offset: &Offset) -> Result<Value, Error> {
+        let name = self.text(site.line, offset);
+        if let Some(defs) = self.assigns.get(name) {
+            return self.resolve_assign(site, offset, defs);
+        }
+
+        self.sym
+            .get(name)
+            .and_then(|sym| sym.value)
+            .ok_or_else(|| self.error(site.line, offset, AsmErrorKind::UnknownSym))
+    }
 
-impl<'s> Program<'s> {
-    pub fn resolve(&self, offset: &Offset) -> Result<i32, AsmError<'s>> {
-        let start = offset.offset;
-        let end = start + offset.len;
-        let sym = &self.input[start..end];
+    /// Value of a symbol defined by assignments: the last one before the
+    /// site, or the first one for references ahead of them.
+    fn resolve_assign(
+        &self,
+        site: Site,
+        offset: &Offset,
+        defs: &[(Site, Imm)],
+    ) -> Result<Value, Error> {
+        let idx = defs.partition_point(|(def, _)| def.line < site.line);
+        let (def, imm) = &defs[idx.saturating_sub(1)];
+
+        let depth = self.assign_depth.get();
+        if depth > MAX_DEPTH {
+            return Err(self.error(site.line, offset, AsmErrorKind::CircularSym));
+        }
+        self.assign_depth.set(depth + 1);
+        let value = imm.eval(self, *def);
+        self.assign_depth.set(depth);
+
+        value
+    }
+
+    pub fn resolve_local(&self, site: Site, local: &LocalRef) -> Result<Value, Error> {
+        let defs = self
+            .local
+            .get(&local.num)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+
+        // Definitions are recorded in the order lines are processed, so the
+        // ones preceding the reference (or on the same line) come first.
+        let idx = defs.partition_point(|(line, _)| *line <= site.line);
+        let def = match local.dir {
+            Dir::Backward => idx.checked_sub(1).map(|idx| &defs[idx]),
+            Dir::Forward => defs.get(idx),
+        };
 
-        self.sym.get(sym).copied().ok_or_else(|| AsmError {
-            span: Spanned::new(self.input, true).slice(start..end),
-            kind: AsmErrorKind::UnknownSym,
+        def.map(|(_, value)| *value).ok_or_else(|| {
+            self.error(site.line, &local.offset, AsmErrorKind::UnknownLocal)
         })
     }
 
-    pub fn generate(&self) -> Result<Vec<u32>, AsmError<'s>> {
-        self.code
+    pub fn addr(&self, value: Value) -> i64 {
+        let base = value
+            .section
+            .map(|section| self.sections[section].addr)
+            .unwrap_or_default();
+
+        (base as i64).wrapping_add(value.offset)
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn sections(&self) -> &[Section] {
+        &self.sections
+    }
+
+    pub fn symbols(&self) -> impl Iterator<Item = (&str, &Symbol)> {
+        self.sym.iter().map(|(name, sym)| (name.as_str(), sym))
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.sym.get(name)
+    }
+
+    /// Finds the `auipc` at `label` which a `%pcrel_lo(label)` at the site
+    /// refers to, along with its line.
+    pub fn pcrel_hi(
+        &self,
+        label: Value,
+        site: Site,
+        offset: &Offset,
+    ) -> Result<(&Instr, usize), Error> {
+        let error = || self.error(site.line, offset, AsmErrorKind::NoPcrelHi);
+        let section = &self.sections[label.section.ok_or_else(error)?];
+        let idx = section
+            .items
+            .binary_search_by_key(&label.offset, |entry| entry.offset as i64)
+            .map_err(|_| error())?;
+
+        let entry = &section.items[idx];
+        match &entry.item {
+            Item::Instr(instr)
+                if instr.imm().and_then(Imm::modifier).is_some_and(
+                    |(modifier, ..)| {
+                        matches!(modifier, Modifier::PcrelHi | Modifier::Call)
+                    },
+                ) =>
+            {
+                Ok((instr, entry.line))
+            }
+            _ => Err(error()),
+        }
+    }
+
+    pub fn sources(&self) -> &[Source] {
+        &self.sources
+    }
+
+    pub fn lines(&self) -> &[SourceLine] {
+        &self.lines
+    }
+
+    pub fn line_info(&self) -> &LineInfo {
+        &self.line_info
+    }
+
+    pub fn frame_info(&self) -> &FrameInfo {
+        &self.frame_info
+    }
+
+    pub fn attributes(&self) -> &BTreeMap<u32, AttrValue> {
+        &self.attributes
+    }
+
+    /// Compressed instructions are available in some of the code, by
+    /// configuration or `.option`.
+    pub fn rvc(&self) -> bool {
+        self.arch.contains("c")
+    }
+
+    pub fn arch(&self) -> &BTreeSet<String> {
+        &self.arch
+    }
+
+    pub fn error(&self, line: usize, offset: &Offset, kind: AsmErrorKind) -> Error {
+        self.error_at(self.lines[line].src, offset.offset, kind)
+    }
+
+    pub(crate) fn line_error(&self, line: usize, kind: AsmErrorKind) -> Error {
+        self.error(line, &self.stmt(line), kind)
+    }
+
+    /// Offset of the line without leading whitespace.
+    fn stmt(&self, line: usize) -> Offset {
+        let offset = self.lines[line].offset;
+        let text = self.text(line, &offset);
+        let indent = text.len() - text.trim_start().len();
+
+        Offset {
+            offset: offset.offset + indent,
+            len: offset.len - indent,
+        }
+    }
+
+    fn error_at(&self, src: usize, offset: usize, kind: AsmErrorKind) -> Error {
+        let mut error = Error {
+            kind,
+            loc: Some(self.sources[src].location(offset)),
+            notes: Vec::new(),
+        };
+
+        let mut parent = self.sources[src].parent;
+        while let Some((origin, line)) = parent {
+            error = error.with_note(origin.note(), self.location(line, &self.stmt(line)));
+            parent = self.sources[self.lines[line].src].parent;
+        }
+
+        error
+    }
+
+    fn location(&self, line: usize, offset: &Offset) -> Location {
+        self.sources[self.lines[line].src].location(offset.offset)
+    }
+
+    pub fn text(&self, line: usize, offset: &Offset) -> &str {
+        let text = &self.sources[self.lines[line].src].text;
+        &text[offset.offset..offset.offset + offset.len]
+    }
+
+    /// Generates contents of every section, in the order of their
+    /// definition. NoBits sections have no contents.
+    pub fn generate(&self) -> Result<Vec<(&Section, Vec<u8>)>, Error> {
+        self.sections
             .iter()
-            .with_address()
-            .map(|(addr, instr)| instr.code(self, addr))
+            .enumerate()
+            .map(|(idx, section)| Ok((section, self.emit(idx, None)?)))
             .collect()
     }
 
-    pub fn dump_code(&self) -> Result<(), AsmError<'s>> {
-        let code = self.generate()?;
+    /// Generates contents of every section for an object file: references
+    /// to other sections and to undefined symbols are left zero and
+    /// recorded as relocations instead.
+    pub fn generate_relocatable(&self) -> Result<Vec<Relocatable<'_>>, Error> {
+        self.sections
+            .iter()
+            .enumerate()
+            .map(|(idx, section)| {
+                let mut relocs = Vec::new();
+                let buf = self.emit(idx, Some(&mut relocs))?;
+                Ok((section, buf, relocs))
+            })
+            .collect()
+    }
 
-        for (addr, instr) in code.into_iter().with_address() {
-            println!(
-                "{:08x}: {:02x} {:02x} {:02x} {:02x}",
-                addr,
-                (instr >> 24) & 0xff,
-                (instr >> 16) & 0xff,
-                (instr >> 8) & 0xff,
-                instr & 0xff
-            );
+    fn emit(
+        &self,
+        idx: usize,
+        mut relocs: Option<&mut Vec<Reloc>>,
+    ) -> Result<Vec<u8>, Error> {
+        let section = &self.sections[idx];
+        let mut buf = Vec::with_capacity(section.size() as usize);
+        if section.flags.nobits {
+            return Ok(buf);
+        }
+
+        for entry in &section.items {
+            let site = Site {
+                here: Value::rel(idx, entry.offset),
+                line: entry.line,
+            };
+            match &entry.item {
+                Item::Instr(instr) => {
+                    let code = match relocs.as_deref_mut() {
+                        Some(relocs) => match instr.fixup(self, site)? {
+                            Fixup::Resolved => instr.code(self, site)?,
+                            Fixup::Reloc(kind, target) => {
+                                relocs.push(Reloc {
+                                    offset: entry.offset,
+                                    kind,
+                                    target,
+                                });
+                                if instr.relax && kind.is_relaxable() {
+                                    relocs.push(Reloc {
+                                        offset: entry.offset,
+                                        kind: RelocKind::Relax,
+                                        target: Target {
+                                            sym: None,
+                                            value: Value::abs(0),
+                                        },
+                                    });
+                                }
+                                instr.encode(0)
+                            }
+                            Fixup::Covered => instr.encode(0),
+                        },
+                        None => instr.code(self, site)?,
+                    };
+                    buf.extend(code.to_le_bytes());
+                }
+                Item::Data(data) => {
+                    data.emit(self, site, &mut buf, relocs.as_deref_mut())?
+                }
+            }
+        }
+
+        Ok(buf)
+    }
+
+    /// Prints the contents of the sections as 32-bit words, most significant
+    /// byte first. [`Program::flat_binary`] gives the bytes in memory order.
+    pub fn dump_code(&self) -> Result<(), Error> {
+        for (section, code) in self.generate()? {
+            dump_section(&section.name, section.addr, &code);
         }
 
         Ok(())
     }
 }
 
+/// Prints the contents of a section at `addr` as 32-bit words, nothing if
+/// it is empty.
+pub(crate) fn dump_section(name: &str, addr: u32, code: &[u8]) {
+    if code.is_empty() {
+        return;
+    }
+
+    println!("{name}:");
+    for (offset, word) in (0..).step_by(4).zip(code.chunks(4)) {
+        let bytes = word
+            .iter()
+            .rev()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<Vec<_>>();
+
+        println!("{:08x}: {}", addr + offset, bytes.join(" "));
+    }
+}
+
+/// Lines collected up to the end of a block, e.g. a macro body.
+#[derive(Debug)]
+struct Block {
+    kind: BlockKind,
+    line: usize,
+    /// Nesting level of blocks of the same kind.
+    depth: usize,
+}
+
+#[derive(Debug)]
+enum BlockKind {
+    Macro(String, Vec<Param>),
+    Rept(u32),
+    /// Body repeated with the param substituted with each of the values.
+    Irp(String, Vec<String>),
+}
+
+impl BlockKind {
+    /// Directives opening a nested block which shares the end directive.
+    fn begins(&self, name: &str) -> bool {
+        match self {
+            Self::Macro(..) => name == "macro",
+            Self::Rept(_) | Self::Irp(..) => matches!(name, "rept" | "irp" | "irpc"),
+        }
+    }
+
+    fn end(&self) -> &'static str {
+        match self {
+            Self::Macro(..) => "endm",
+            Self::Rept(_) | Self::Irp(..) => "endr",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Cond {
+    line: usize,
+    state: CondState,
+    has_else: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CondState {
+    /// Lines of the current branch are assembled.
+    Active,
+    /// No branch taken yet, a following `.elseif` or `.else` may be.
+    Pending,
+    /// A branch was taken already or the whole block is skipped.
+    Done,
+}
+
+fn directive_name(line: &str) -> Option<&str> {
+    let name = line.trim_start().strip_prefix('.')?;
+    let len = name
+        .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .unwrap_or(name.len());
+
+    Some(&name[..len])
+}
+
 #[derive(Debug)]
 enum Line {
-    Instr(Instr),
-    Label(String, Option<Instr>),
+    Instr(Vec<Instr>),
+    Directive(Directive),
+    Label(Label, Option<Box<Line>>),
+    Assign(Offset, Imm),
+}
+
+#[derive(Debug)]
+enum Label {
+    Sym(Offset),
+    Local(u32),
 }
 
 impl Line {
-    fn parse(input: Span<'_>) -> IResult<Self> {
-        map(
-            pair(
-                alt((Self::parse_instr, Self::parse_label)),
-                opt(preceded(space1, Self::parse_comment)),
-            ),
-            |(this, _)| this,
+    fn parse(input: Span<'_>) -> IResult<'_, Option<Self>> {
+        terminated(
+            opt(alt((Self::parse_assign, Self::parse_stmt))),
+            opt(Self::parse_comment),
         )(input)
     }
 
-    fn parse_instr(input: Span<'_>) -> IResult<Self> {
+    fn parse_stmt(input: Span<'_>) -> IResult<'_, Self> {
+        alt((Self::parse_directive, Self::parse_instr, Self::parse_label))(input)
+    }
+
+    fn parse_instr(input: Span<'_>) -> IResult<'_, Self> {
         map(Instr::parse, Self::Instr)(input)
     }
 
-    fn parse_label(input: Span<'_>) -> IResult<Self> {
+    fn parse_directive(input: Span<'_>) -> IResult<'_, Self> {
+        map(Directive::parse, Self::Directive)(input)
+    }
+
+    fn parse_assign(input: Span<'_>) -> IResult<'_, Self> {
+        map(
+            pair(
+                terminated(parse_sym, delimited(space0, char('='), space0)),
+                cut(Imm::parse),
+            ),
+            |(sym, imm)| Self::Assign(sym.into(), imm),
+        )(input)
+    }
+
+    fn parse_label(input: Span<'_>) -> IResult<'_, Self> {
         map(
             pair(
-                terminated(parse_sym, char(':')),
+                terminated(
+                    alt((Self::parse_sym_label, Self::parse_local_label)),
+                    char(':'),
+                ),
                 opt(preceded(
                     space1,
-                    preceded(not(char('#')), cut(Instr::parse)),
+                    preceded(not(char('#')), cut(Self::parse_stmt)),
                 )),
             ),
-            |(label, instr)| Self::Label(label.to_string(), instr),
+            |(label, line)| Self::Label(label, line.map(Box::new)),
         )(input)
     }
 
-    fn parse_comment(input: Span<'_>) -> IResult<()> {
-        map(preceded(char('#'), anychar), |_| ())(input)
+    fn parse_sym_label(input: Span<'_>) -> IResult<'_, Label> {
+        map(parse_sym, |label| Label::Sym(label.into()))(input)
+    }
+
+    fn parse_local_label(input: Span<'_>) -> IResult<'_, Label> {
+        map_res(digit1, |label: Span<'_>| label.parse().map(Label::Local))(input)
+    }
+
+    /// Parses a possible macro invocation: optional label, name and the
+    /// arguments.
+    fn parse_call(input: Span<'_>) -> IResult<'_, (Option<Label>, Span<'_>, Span<'_>)> {
+        tuple((
+            preceded(
+                space0,
+                opt(terminated(
+                    alt((Self::parse_sym_label, Self::parse_local_label)),
+                    pair(char(':'), space0),
+                )),
+            ),
+            terminated(parse_name, alt((space1, eof))),
+            rest,
+        ))(input)
+    }
+
+    fn parse_comment(input: Span<'_>) -> IResult<'_, ()> {
+        map(preceded(pair(space0, char('#')), rest), |_| ())(input)
     }
 }
 
-impl<'s> Program<'s> {
-    pub fn parse(input: &'s str) -> Result<Self, AsmError<'s>> {
+impl Program {
+    pub fn parse(input: &str) -> Result<Self, Error> {
+        Self::parse_with_config(input, Config::default())
+    }
+
+    pub fn parse_with_config(input: &str, config: Config) -> Result<Self, Error> {
+        Self::parse_source(Source::new("<input>".into(), input), config)
+    }
+
+    pub fn parse_file(path: impl AsRef<Path>, config: Config) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|e| AsmErrorKind::Io(path.display().to_string(), e))?;
+
+        Self::parse_source(Source::file(path.to_path_buf(), &text, None), config)
+    }
+
+    fn parse_source(source: Source, config: Config) -> Result<Self, Error> {
         let mut program = Self {
-            input,
-            code: Default::default(),
+            options: Options::new(config.rvc),
+            arch: Options::new(config.rvc).arch,
+            config,
+            sources: Default::default(),
+            lines: Default::default(),
+            sections: vec![Section::new(".text".into(), SectionFlags::TEXT)],
+            curr: 0,
             sym: Default::default(),
+            local: Default::default(),
+            assigns: Default::default(),
+            assign_depth: Cell::new(0),
+            macros: Default::default(),
+            expansions: 0,
+            expanded: 0,
+            block: None,
+            exit: false,
+            conds: Vec::new(),
+            line_info: Default::default(),
+            frame_info: Default::default(),
+            attributes: Default::default(),
+            option_stack: Vec::new(),
         };
 
-        let input = Span::new(input, true);
-        let _ = program.parse_code(input).finish()?;
+        if !program.config.defines.is_empty() {
+            for (name, _) in &program.config.defines {
+                if all_consuming(parse_sym)(Span::new(name, true)).is_err() {
+                    return Err(AsmErrorKind::InvalidDefine(name.clone()).into());
+                }
+            }
+            let defines = program
+                .config
+                .defines
+                .iter()
+                .map(|(name, value)| format!("{name} = {value}\n"))
+                .collect::<String>();
+
+            let src = program.add_source(Source::new("<defines>".into(), &defines));
+            program.parse_code(src)?;
+        }
+
+        let src = program.add_source(source);
+        program.parse_code(src)?;
+        if let Some(frame) = &program.frame_info.open {
+            return Err(
+                program.line_error(frame.line, AsmErrorKind::Unterminated("cfi_endproc"))
+            );
+        }
+        program.resolve_assigns()?;
+        program.check_symbols()?;
+        program.layout()?;
 
         Ok(program)
     }
 
-    fn parse_code<'i>(&mut self, input: Span<'i>) -> IResult<'i, ()> {
-        let (mut input, _) = multispace0(input)?;
-        loop {
-            if input.is_empty() {
-                return Ok((input, ()));
+    fn add_source(&mut self, source: Source) -> usize {
+        self.sources.push(source);
+        self.sources.len() - 1
+    }
+
+    fn parse_code(&mut self, src: usize) -> Result<(), Error> {
+        let text = self.sources[src].text.clone();
+        let mut input = Span::new(&*text, true);
+
+        while !input.is_empty() && !self.exit {
+            let end = input.find('\n').unwrap_or(input.len());
+            let line = input.slice(..end);
+            let line = line.slice(..line.trim_end_matches('\r').len());
+            input = input.slice((end + 1).min(input.len())..);
+
+            self.parse_line(src, line)?;
+        }
+
+        if let Some(block) = self.block.take() {
+            return Err(
+                self.line_error(block.line, AsmErrorKind::Unterminated(block.kind.end()))
+            );
+        }
+
+        // Conditionals can't span sources, unless left by `.exitm`.
+        if let Some(idx) = self
+            .conds
+            .iter()
+            .position(|cond| self.lines[cond.line].src == src)
+        {
+            if !self.exit {
+                let line = self.conds[idx].line;
+                return Err(self.line_error(line, AsmErrorKind::Unterminated("endif")));
+            }
+            self.conds.truncate(idx);
+        }
+
+        if self.origin(src) == Some(Origin::Macro) {
+            self.exit = false;
+        }
+
+        Ok(())
+    }
+
+    fn parse_line(&mut self, src: usize, line: Span<'_>) -> Result<(), Error> {
+        let id = self.lines.len();
+        self.lines.push(SourceLine {
+            src,
+            offset: line.into(),
+            number: self.sources[src].first_line + line.line() - 1,
+        });
+
+        if let Some(block) = self.block.take() {
+            return self.collect(block, id);
+        }
+
+        // Only conditionals are looked at in skipped lines, to track nesting
+        // and find the branch to assemble.
+        if !self.is_active() {
+            let name = directive_name(self.text(id, &self.lines[id].offset));
+            match name {
+                Some("if" | "ifdef" | "ifndef" | "ifnotdef") => {
+                    self.conds.push(Cond {
+                        line: id,
+                        state: CondState::Done,
+                        has_else: false,
+                    });
+                    return Ok(());
+                }
+                Some("else" | "elseif" | "endif") => {}
+                _ => return Ok(()),
+            }
+        }
+
+        if let Ok((_, (label, name, args))) = Line::parse_call(line) {
+            if let Some(mac) = self.macros.get(*name).cloned() {
+                if let Some(label) = label {
+                    self.define(label, self.here(), id)?;
+                }
+
+                return self.call(&mac, &args, id);
+            }
+        }
+
+        let (_, parsed) = delimited(space0, Line::parse, pair(space0, eof))(line)
+            .finish()
+            .map_err(|e| self.error_at(src, e.span.byte_offset(), e.kind))?;
+
+        if let Some(parsed) = parsed {
+            self.exec(parsed, id)?;
+        }
+
+        Ok(())
+    }
+
+    fn is_active(&self) -> bool {
+        self.conds
+            .last()
+            .is_none_or(|cond| cond.state == CondState::Active)
+    }
+
+    /// Innermost conditional, which must be opened in the source of the
+    /// line. Branches can't follow `.else`.
+    fn cond(&mut self, id: usize, branch: bool) -> Result<&mut Cond, Error> {
+        let src = self.lines[id].src;
+        match self.conds.last() {
+            Some(cond)
+                if self.lines[cond.line].src == src && !(branch && cond.has_else) =>
+            {
+                Ok(self.conds.last_mut().unwrap())
+            }
+            _ => Err(self.line_error(id, AsmErrorKind::Misplaced)),
+        }
+    }
+
+    fn eval_cond(&self, id: usize, cond: &Condition) -> Result<bool, Error> {
+        match cond {
+            Condition::Expr(expr) => Ok(self.eval_abs(id, expr)? != 0),
+            Condition::Defined(sym, defined) => {
+                let is_defined = self
+                    .sym
+                    .get(self.text(id, sym))
+                    .is_some_and(Symbol::is_defined);
+
+                Ok(is_defined == *defined)
+            }
+        }
+    }
+
+    fn open(&mut self, kind: BlockKind, id: usize) {
+        self.block = Some(Block {
+            kind,
+            line: id,
+            depth: 1,
+        });
+    }
+
+    /// Adds the line to the block being collected, closing it at the
+    /// matching end directive.
+    fn collect(&mut self, mut block: Block, id: usize) -> Result<(), Error> {
+        let name = directive_name(self.text(id, &self.lines[id].offset));
+        if name.is_some_and(|name| block.kind.begins(name)) {
+            block.depth += 1;
+        } else if name == Some(block.kind.end()) {
+            block.depth -= 1;
+            if block.depth == 0 {
+                return self.close(block, id);
+            }
+        }
+
+        self.block = Some(block);
+
+        Ok(())
+    }
+
+    fn close(&mut self, block: Block, end: usize) -> Result<(), Error> {
+        let src = self.lines[block.line].src;
+        let body = if end > block.line + 1 {
+            let first = self.lines[block.line + 1].offset;
+            let last = self.lines[end - 1].offset;
+            &self.sources[src].text[first.offset..last.offset + last.len]
+        } else {
+            ""
+        };
+        let first_line = self
+            .location(block.line, &self.lines[block.line].offset)
+            .line
+            + 1;
+
+        let mac = |params| Macro {
+            params,
+            body: body.into(),
+            file: self.sources[src].name.clone(),
+            first_line,
+        };
+
+        let (mac, iterations): (_, Box<dyn Iterator<Item = Vec<String>>>) = match block
+            .kind
+        {
+            BlockKind::Macro(name, params) => {
+                if self.macros.contains_key(&name) {
+                    return Err(self.line_error(block.line, AsmErrorKind::DuplicateMacro));
+                }
+
+                let mac = mac(params);
+                self.macros.insert(name, Rc::new(mac));
+                return Ok(());
+            }
+            BlockKind::Rept(count) => {
+                let size = (count as usize).saturating_mul(body.len() + EXPANSION_COST);
+                if size > MAX_EXPANSION - self.expanded {
+                    return Err(self.line_error(block.line, AsmErrorKind::ExpansionSize));
+                }
+
+                (mac(Vec::new()), Box::new((0..count).map(|_| Vec::new())))
+            }
+            BlockKind::Irp(name, values) => {
+                let param = Param {
+                    name,
+                    kind: ParamKind::Opt,
+                    default: None,
+                };
+                let values = if values.is_empty() {
+                    vec![vec![String::new()]]
+                } else {
+                    values.into_iter().map(|value| vec![value]).collect()
+                };
+
+                (mac(vec![param]), Box::new(values.into_iter()))
+            }
+        };
+
+        for args in iterations {
+            if self.exit {
+                break;
+            }
+
+            let text = mac.expand(&args, self.expansions);
+            self.expansion(&mac, &text, Origin::Repeat, block.line)?;
+        }
+
+        Ok(())
+    }
+
+    fn call(&mut self, mac: &Macro, args: &str, id: usize) -> Result<(), Error> {
+        let args = mac.bind(args).map_err(|kind| self.line_error(id, kind))?;
+        let text = mac.expand(&args, self.expansions);
+        self.expansions += 1;
+
+        self.expansion(mac, &text, Origin::Macro, id)
+    }
+
+    fn expansion(
+        &mut self,
+        mac: &Macro,
+        text: &str,
+        origin: Origin,
+        id: usize,
+    ) -> Result<(), Error> {
+        if self.parents(id).count() > MAX_DEPTH {
+            return Err(self.line_error(id, AsmErrorKind::MacroDepth));
+        }
+        self.expanded += text.len() + EXPANSION_COST;
+        if self.expanded > MAX_EXPANSION {
+            return Err(self.line_error(id, AsmErrorKind::ExpansionSize));
+        }
+
+        let src = self.add_source(Source::expansion(
+            mac.file.clone(),
+            mac.first_line,
+            text,
+            origin,
+            id,
+        ));
+        self.parse_code(src)
+    }
+
+    fn exec(&mut self, line: Line, id: usize) -> Result<(), Error> {
+        match line {
+            Line::Instr(instrs) => {
+                for mut instr in instrs {
+                    instr.relax = self.options.relax;
+                    instr.pic = self.options.pic;
+                    self.push(instr.into(), id)?;
+                }
             }
+            Line::Directive(directive) => self.directive(directive, id)?,
+            Line::Label(label, line) => {
+                self.define(label, self.here(), id)?;
 
-            let (input_, line) = alt((take_until("\n"), rest))(input)?;
+                if let Some(line) = line {
+                    self.exec(*line, id)?;
+                }
+            }
+            Line::Assign(sym, imm) => self.assign(sym, imm, id)?,
+        }
 
-            let (line, _) = space0(line)?;
-            if !line.is_empty() {
-                let (line, parsed) = Line::parse(line)?;
-                match parsed {
-                    Line::Instr(instr) => {
-                        self.code.push(instr);
+        Ok(())
+    }
+
+    fn directive(&mut self, directive: Directive, id: usize) -> Result<(), Error> {
+        match directive {
+            Directive::Section(name, flags) => self.switch_section(name, flags),
+            Directive::Int(size, values) => {
+                self.push(Data::Int(size, values).into(), id)?
+            }
+            Directive::Ascii(bytes) => self.push(Data::Bytes(bytes).into(), id)?,
+            Directive::Zero(count) => {
+                let count = self.eval_count(id, &count)?;
+                self.push(
+                    Data::Fill {
+                        count,
+                        size: 1,
+                        value: 0,
+                    }
+                    .into(),
+                    id,
+                )?;
+            }
+            Directive::Space(count, fill) => {
+                let count = self.eval_count(id, &count)?;
+                let value = match fill {
+                    Some(fill) => self.eval_abs(id, &fill)?,
+                    None => 0,
+                };
+                self.push(
+                    Data::Fill {
+                        count,
+                        size: 1,
+                        value,
+                    }
+                    .into(),
+                    id,
+                )?;
+            }
+            Directive::Fill(repeat, size, value) => {
+                let count = self.eval_count(id, &repeat)?;
+                let size = match size {
+                    Some(size) => match self.eval_count(id, &size)? {
+                        size @ 0..=8 => size,
+                        _ => {
+                            return Err(self.error(
+                                id,
+                                &size.offset,
+                                AsmErrorKind::ImmOutOfRange,
+                            ))
+                        }
+                    },
+                    None => 1,
+                };
+                let value = match value {
+                    Some(value) => self.eval_abs(id, &value)?,
+                    None => 0,
+                };
+                self.push(Data::Fill { count, size, value }.into(), id)?;
+            }
+            Directive::Align(kind, align, fill, max) => {
+                let align = match (kind, self.eval_count(id, &align)?) {
+                    (AlignKind::Pow2, align @ 0..=31) => 1 << align,
+                    (AlignKind::Bytes, align) if align.is_power_of_two() => align,
+                    _ => {
+                        return Err(self.error(
+                            id,
+                            &align.offset,
+                            AsmErrorKind::ImmOutOfRange,
+                        ))
                     }
-                    Line::Label(label, instr) => {
-                        self.sym.insert(label, self.curr_addr() as i32);
+                };
+
+                self.align(align, fill, max, id)?;
+            }
+            Directive::Org(target, fill) => {
+                let value = target.imm.eval(self, self.site(id))?;
+                if value.section.is_some_and(|section| section != self.curr) {
+                    return Err(self.error(
+                        id,
+                        &target.offset,
+                        AsmErrorKind::InvalidExpr,
+                    ));
+                }
+
+                let size = self.sections[self.curr].size() as i64;
+                let count = u32::try_from(value.offset - size).map_err(|_| {
+                    self.error(id, &target.offset, AsmErrorKind::OrgBackwards)
+                })?;
+                let value = match fill {
+                    Some(fill) => self.eval_abs(id, &fill)?,
+                    None => 0,
+                };
 
-                        if let Some(instr) = instr {
-                            self.code.push(instr);
+                if count > 0 {
+                    self.push(
+                        Data::Fill {
+                            count,
+                            size: 1,
+                            value,
                         }
+                        .into(),
+                        id,
+                    )?;
+                }
+            }
+            Directive::Macro(name, params) => {
+                self.open(BlockKind::Macro(name, params), id)
+            }
+            Directive::Rept(count) => {
+                let count = self.eval_count(id, &count)?;
+                self.open(BlockKind::Rept(count), id);
+            }
+            Directive::Irp(name, values) => self.open(BlockKind::Irp(name, values), id),
+            Directive::Endr => return Err(self.line_error(id, AsmErrorKind::Misplaced)),
+            Directive::If(cond) => {
+                let state = if self.eval_cond(id, &cond)? {
+                    CondState::Active
+                } else {
+                    CondState::Pending
+                };
+                self.conds.push(Cond {
+                    line: id,
+                    state,
+                    has_else: false,
+                });
+            }
+            Directive::ElseIf(cond) => {
+                let state = self.cond(id, true)?.state;
+                self.cond(id, true)?.state = match state {
+                    CondState::Pending if self.eval_cond(id, &cond)? => CondState::Active,
+                    CondState::Pending => CondState::Pending,
+                    CondState::Active | CondState::Done => CondState::Done,
+                };
+            }
+            Directive::Else => {
+                let cond = self.cond(id, true)?;
+                cond.has_else = true;
+                cond.state = match cond.state {
+                    CondState::Pending => CondState::Active,
+                    CondState::Active | CondState::Done => CondState::Done,
+                };
+            }
+            Directive::Endif => {
+                self.cond(id, false)?;
+                self.conds.pop();
+            }
+            Directive::Exitm => {
+                let in_macro = self
+                    .parents(id)
+                    .any(|src| self.origin(src) == Some(Origin::Macro));
+                if !in_macro {
+                    return Err(self.line_error(id, AsmErrorKind::Misplaced));
+                }
+
+                self.exit = true;
+            }
+            Directive::Endm => return Err(self.line_error(id, AsmErrorKind::Misplaced)),
+            Directive::Bind(binding, syms) => {
+                for sym in syms {
+                    self.declare(id, &sym)?.binding = binding;
+                }
+            }
+            Directive::Visibility(visibility, syms) => {
+                for sym in syms {
+                    self.declare(id, &sym)?.visibility = visibility;
+                }
+            }
+            Directive::Type(sym, kind) => self.declare(id, &sym)?.kind = kind,
+            Directive::Size(sym, size) => {
+                if !self.declare(id, &sym)?.is_defined() {
+                    return Err(self.error(id, &sym, AsmErrorKind::UnknownSym));
+                }
+
+                let size = u64::try_from(self.eval_abs(id, &size)?).map_err(|_| {
+                    self.error(id, &size.offset, AsmErrorKind::ImmOutOfRange)
+                })?;
+                self.declare(id, &sym)?.size = Some(size);
+            }
+            Directive::Set(sym, imm) => self.assign(sym, imm, id)?,
+            Directive::File(None, name) => self.line_info.name = Some(name),
+            Directive::File(Some(num), path) => {
+                self.line_info.files.insert(num, path);
+            }
+            Directive::Loc(loc) => {
+                if !self.line_info.files.contains_key(&loc.file) {
+                    return Err(self.line_error(id, AsmErrorKind::UnknownFile(loc.file)));
+                }
+                self.line_info.loc = Some(loc);
+            }
+            Directive::CfiStartProc(simple) => {
+                if self.frame_info.open.is_some() {
+                    return Err(
+                        self.line_error(id, AsmErrorKind::Unterminated("cfi_endproc"))
+                    );
+                }
+                let start = self.sections[self.curr].size();
+                self.frame_info.open = Some(Frame::new(self.curr, start, simple, id));
+            }
+            Directive::CfiEndProc => {
+                let Some(mut frame) = self.frame_info.open.take() else {
+                    return Err(self.line_error(id, AsmErrorKind::Misplaced));
+                };
+                frame.end = self.sections[frame.section].size();
+                self.frame_info.frames.push(frame);
+            }
+            Directive::CfiSections(sections) => self.frame_info.sections = sections,
+            Directive::Cfi(insn) => self.cfi(insn, id)?,
+            Directive::Attribute(tag, arg) => {
+                let value = match arg {
+                    AttrArg::Int(value) => {
+                        AttrValue::Int(self.eval_count(id, &value)? as u64)
                     }
+                    AttrArg::Str(value) => AttrValue::Str(value),
+                };
+                self.attributes.insert(tag, value);
+            }
+            Directive::Option(AsmOption::Push) => {
+                self.option_stack.push(self.options.clone());
+            }
+            Directive::Option(AsmOption::Pop) => {
+                self.options = self
+                    .option_stack
+                    .pop()
+                    .ok_or_else(|| self.line_error(id, AsmErrorKind::Misplaced))?;
+            }
+            Directive::Option(option) => {
+                self.options.set(option);
+                self.arch.extend(self.options.arch.iter().cloned());
+            }
+            Directive::Include(name) => {
+                let path = self.find_file(&name, id)?;
+                let text = fs::read_to_string(&path).map_err(|e| {
+                    self.line_error(id, AsmErrorKind::Io(path.display().to_string(), e))
+                })?;
+
+                let src = self.add_source(Source::file(path, &text, Some(id)));
+                self.parse_code(src)?;
+            }
+            Directive::Incbin(name, skip, count) => {
+                let path = self.find_file(&name, id)?;
+                let bytes = fs::read(&path).map_err(|e| {
+                    self.line_error(id, AsmErrorKind::Io(path.display().to_string(), e))
+                })?;
+
+                let skip = match skip {
+                    Some(skip) => self.eval_bound(id, &skip, bytes.len())?,
+                    None => 0,
+                };
+                let count = match count {
+                    Some(count) => self.eval_bound(id, &count, bytes.len() - skip)?,
+                    None => bytes.len() - skip,
+                };
+
+                self.push(Data::Bytes(bytes[skip..skip + count].to_vec()).into(), id)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds a call frame instruction to the open frame, applying from the
+    /// current end of its section.
+    fn cfi(&mut self, insn: CfiInsn, id: usize) -> Result<(), Error> {
+        let Some(mut frame) = self.frame_info.open.take() else {
+            return Err(self.line_error(id, AsmErrorKind::Misplaced));
+        };
+
+        let offset = self.sections[frame.section].size();
+        let result = frame.push(
+            offset,
+            insn,
+            |expr| self.eval_abs(id, expr),
+            |expr, kind| match expr {
+                Some(expr) => self.error(id, &expr.offset, kind),
+                None => self.line_error(id, kind),
+            },
+        );
+        self.frame_info.open = Some(frame);
+
+        result
+    }
+
+    fn align(
+        &mut self,
+        align: u32,
+        fill: Option<Expr>,
+        max: Option<Expr>,
+        id: usize,
+    ) -> Result<(), Error> {
+        let max = max.map(|max| self.eval_count(id, &max)).transpose()?;
+        let fill = fill.map(|fill| self.eval_abs(id, &fill)).transpose()?;
+
+        let section = &mut self.sections[self.curr];
+        section.align = section.align.max(align);
+
+        let size = section.size();
+        let Some(padded) = size.checked_next_multiple_of(align) else {
+            let end = (size as u64).next_multiple_of(align as u64);
+            return Err(self.line_error(id, AsmErrorKind::AddressOverflow(end)));
+        };
+        let code = fill.is_none() && section.flags.exec && !section.flags.nobits;
+        let (rvc, relax) = (self.options.rvc(), code && self.options.relax);
+        // Once the linker has relaxed the code before, any padding up to the
+        // alignment less an instruction may be needed.
+        let pad = if relax {
+            align.saturating_sub(if rvc { 2 } else { 4 })
+        } else {
+            padded - size
+        };
+        if pad == 0 || max.is_some_and(|max| pad > max) {
+            return Ok(());
+        }
+
+        let data = match fill {
+            None if code => Data::Nops {
+                size: pad,
+                rvc,
+                relax,
+            },
+            fill => Data::Fill {
+                count: pad,
+                size: 1,
+                value: fill.unwrap_or_default(),
+            },
+        };
+
+        self.push(data.into(), id)
+    }
+
+    fn push(&mut self, item: Item, id: usize) -> Result<(), Error> {
+        let section = &mut self.sections[self.curr];
+        let is_zero = matches!(&item, Item::Data(data) if data.is_zero());
+        if section.flags.nobits && !is_zero {
+            return Err(self.line_error(id, AsmErrorKind::NoBitsData));
+        }
+
+        section.relaxed |= match &item {
+            Item::Instr(instr) => instr.relax,
+            Item::Data(Data::Nops { relax, .. }) => *relax,
+            Item::Data(_) => false,
+        };
+        if let (Item::Instr(_), Some(loc)) = (&item, self.line_info.loc.take()) {
+            self.line_info.rows.push(LineRow {
+                section: self.curr,
+                offset: section.size(),
+                loc,
+            });
+        }
+        section
+            .push(id, item)
+            .map_err(|kind| self.line_error(id, kind))
+    }
+
+    /// Evaluates an expression which must be absolute where it is used, e.g.
+    /// the size of a `.zero` region.
+    fn eval_abs(&self, id: usize, expr: &Expr) -> Result<i64, Error> {
+        let value = expr.imm.eval(self, self.site(id))?;
+        if !value.is_abs() {
+            return Err(self.error(id, &expr.offset, AsmErrorKind::NotAbsolute));
+        }
+
+        Ok(value.offset)
+    }
+
+    fn eval_count(&self, id: usize, expr: &Expr) -> Result<u32, Error> {
+        u32::try_from(self.eval_abs(id, expr)?)
+            .map_err(|_| self.error(id, &expr.offset, AsmErrorKind::ImmOutOfRange))
+    }
+
+    fn eval_bound(&self, id: usize, expr: &Expr, max: usize) -> Result<usize, Error> {
+        let value = self.eval_count(id, expr)? as usize;
+        if value > max {
+            return Err(self.error(id, &expr.offset, AsmErrorKind::ImmOutOfRange));
+        }
+
+        Ok(value)
+    }
+
+    /// Looks for a file named by `.include` or `.incbin`: next to the
+    /// including file, then in the include directories.
+    fn find_file(&self, name: &str, id: usize) -> Result<PathBuf, Error> {
+        let dir = self.parents(id).find_map(|src| {
+            self.sources[src]
+                .path
+                .as_ref()
+                .map(|path| path.parent().unwrap_or(Path::new("")).to_path_buf())
+        });
+
+        let path = dir
+            .iter()
+            .chain(&self.config.include_dirs)
+            .map(|dir| dir.join(name))
+            .chain(iter::once(PathBuf::from(name)))
+            .find(|path| path.is_file())
+            .ok_or_else(|| {
+                self.line_error(id, AsmErrorKind::FileNotFound(name.to_string()))
+            })?;
+
+        if let Ok(canonical) = path.canonicalize() {
+            let is_cycle = self.parents(id).any(|src| {
+                self.sources[src]
+                    .path
+                    .as_ref()
+                    .and_then(|path| path.canonicalize().ok())
+                    .is_some_and(|parent| parent == canonical)
+            });
+            if is_cycle {
+                return Err(self.line_error(id, AsmErrorKind::IncludeCycle));
+            }
+        }
+
+        Ok(path)
+    }
+
+    fn origin(&self, src: usize) -> Option<Origin> {
+        self.sources[src].parent.map(|(origin, _)| origin)
+    }
+
+    /// Sources of the line and of the lines which included it, innermost
+    /// first.
+    fn parents(&self, id: usize) -> impl Iterator<Item = usize> + '_ {
+        iter::successors(Some(self.lines[id].src), |src| {
+            self.sources[*src]
+                .parent
+                .map(|(_, line)| self.lines[line].src)
+        })
+    }
+
+    fn switch_section(&mut self, name: String, flags: SectionFlags) {
+        self.curr = match self.sections.iter().position(|s| s.name == name) {
+            Some(idx) => idx,
+            None => {
+                self.sections.push(Section::new(name, flags));
+                self.sections.len() - 1
+            }
+        };
+    }
+
+    fn layout(&mut self) -> Result<(), Error> {
+        let addrs = self.config.place(
+            self.sections
+                .iter()
+                .map(|section| (section.flags, section.align, section.size())),
+        )?;
+        for (section, addr) in self.sections.iter_mut().zip(addrs) {
+            section.addr = addr;
+        }
+
+        Ok(())
+    }
+
+    fn define(&mut self, label: Label, value: Value, id: usize) -> Result<(), Error> {
+        match label {
+            Label::Sym(offset) => {
+                let prev = *self.declare(id, &offset)?;
+                let name = self.text(id, &offset).to_string();
+                if prev.is_defined() || self.assigns.contains_key(&name) {
+                    return Err(self.duplicate(id, &offset, &prev));
                 }
 
-                let (line, _) = space0(line)?;
-                eof(line)?;
+                self.sym.insert(
+                    name,
+                    Symbol {
+                        value: Some(value),
+                        line: id,
+                        offset,
+                        ..prev
+                    },
+                );
+            }
+            Label::Local(num) => {
+                self.local.entry(num).or_default().push((id, value));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Defines a symbol by `=` or `.set`, which may define it again. The
+    /// expression is evaluated where the symbol is used, so it may refer
+    /// to symbols defined later.
+    fn assign(&mut self, offset: Offset, imm: Imm, id: usize) -> Result<(), Error> {
+        let prev = *self.declare(id, &offset)?;
+        let name = self.text(id, &offset).to_string();
+        let assigned = self.assigns.contains_key(&name);
+        if prev.is_defined() && !assigned {
+            return Err(self.duplicate(id, &offset, &prev));
+        }
+
+        // Values known by now are set right away, for directives evaluated
+        // while parsing, like `.if`.
+        let site = self.site(id);
+        let value = imm.eval(self, site).ok().or(prev.value);
+        self.assigns
+            .entry(name.clone())
+            .or_default()
+            .push((site, imm));
+        let (line, offset) = if assigned {
+            (prev.line, prev.offset)
+        } else {
+            (id, offset)
+        };
+        self.sym.insert(
+            name,
+            Symbol {
+                value,
+                line,
+                offset,
+                ..prev
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Sets the symbols defined by assignments to their last values, once
+    /// the symbols they refer to are defined.
+    fn resolve_assigns(&mut self) -> Result<(), Error> {
+        let mut last: Vec<(&String, &(Site, Imm))> = self
+            .assigns
+            .iter()
+            .filter_map(|(name, defs)| Some((name, defs.last()?)))
+            .collect();
+        last.sort_by_key(|(_, (site, _))| site.line);
+
+        let values = last
+            .into_iter()
+            .map(|(name, (site, imm))| Ok((name.clone(), imm.eval(self, *site)?)))
+            .collect::<Result<Vec<_>, Error>>()?;
+        for (name, value) in values {
+            if let Some(sym) = self.sym.get_mut(&name) {
+                sym.value = Some(value);
             }
+        }
+
+        Ok(())
+    }
+
+    fn duplicate(&self, id: usize, offset: &Offset, prev: &Symbol) -> Error {
+        self.error(id, offset, AsmErrorKind::DuplicateSym)
+            .with_note("Previously defined", self.location(prev.line, &prev.offset))
+    }
+
+    /// Returns the symbol named at `offset`, adding it as undefined if it
+    /// wasn't mentioned before.
+    fn declare(&mut self, id: usize, offset: &Offset) -> Result<&mut Symbol, Error> {
+        let name = self.text(id, offset);
+
+        if REGS.contains_key(name) {
+            return Err(self.error(id, offset, AsmErrorKind::SymIsReg));
+        }
+        if OP_CODE.contains_key(name) || PSEUDO.contains_key(name) {
+            return Err(self.error(id, offset, AsmErrorKind::SymIsOpCode));
+        }
+
+        Ok(self
+            .sym
+            .entry(name.to_string())
+            .or_insert_with(|| Symbol::new(id, *offset)))
+    }
+
+    /// Checks symbols once all lines are parsed. Only global and weak
+    /// symbols may be left undefined, referring to other objects.
+    fn check_symbols(&self) -> Result<(), Error> {
+        let undefined = self
+            .sym
+            .values()
+            .filter(|sym| !sym.is_defined() && sym.binding == Binding::Local)
+            .min_by_key(|sym| sym.line);
+
+        match undefined {
+            Some(sym) => Err(self.error(sym.line, &sym.offset, AsmErrorKind::UnknownSym)),
+            None => Ok(()),
+        }
+    }
+
+    fn here(&self) -> Value {
+        Value::rel(self.curr, self.sections[self.curr].size())
+    }
+
+    fn site(&self, id: usize) -> Site {
+        Site {
+            here: self.here(),
+            line: id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::symbol::{SymbolKind, Visibility};
+
+    fn text(src: &str) -> Vec<u8> {
+        let program = Program::parse(src).unwrap();
+        let mut sections = program.generate().unwrap();
+        sections.remove(0).1
+    }
+
+    #[test]
+    fn reassign() {
+        let src = "
+x = 1
+  addi a0, a0, x
+x = 2
+  addi a0, a0, x
+.set x, x + 1
+  addi a0, a0, x
+";
+        let program = Program::parse(src).unwrap();
+        assert_eq!(
+            text(src),
+            [0x13, 0x05, 0x15, 0x00, 0x13, 0x05, 0x25, 0x00, 0x13, 0x05, 0x35, 0x00]
+        );
+        assert_eq!(program.sym["x"].value, Some(Value::abs(3)));
+    }
+
+    #[test]
+    fn assign_forward() {
+        let src = "
+start:
+  addi a0, a0, size
+size = end - start
+end:
+";
+        assert_eq!(text(src), [0x13, 0x05, 0x45, 0x00]);
+    }
+
+    #[test]
+    fn assign_errors() {
+        for (src, kind) in [
+            ("x:\nx = 1", AsmErrorKind::DuplicateSym),
+            ("x = 1\nx:", AsmErrorKind::DuplicateSym),
+            ("a = b\nb = a", AsmErrorKind::CircularSym),
+        ] {
+            let error = Program::parse(src).unwrap_err();
+            assert_eq!(
+                std::mem::discriminant(&error.kind),
+                std::mem::discriminant(&kind),
+                "{src}"
+            );
+        }
+    }
+
+    #[test]
+    fn address_overflow() {
+        let config = Config {
+            base: 0xffff_fff8,
+            ..Default::default()
+        };
+        let error =
+            Program::parse_with_config("  .word 1, 2, 3", config.clone()).unwrap_err();
+        assert!(matches!(
+            error.kind,
+            AsmErrorKind::AddressOverflow(0x1_0000_0004)
+        ));
+
+        let program =
+            Program::parse_with_config("  .word 1\n  .byte 2, 3, 4", config).unwrap();
+        assert_eq!(program.sections()[0].addr, 0xffff_fff8);
+    }
 
-            input = take_while(|c| c == '\n')(input_)?.0;
+    #[test]
+    fn section_overflow() {
+        let cases = [
+            ("  .fill 0x80000000, 8, 0", 0x4_0000_0000),
+            ("  .zero 0xffffffff\n  .zero 1", 0x1_0000_0000),
+            ("  .zero 0x80000001\n  .balign 0x80000000", 0x1_0000_0000),
+        ];
+        for (src, end) in cases {
+            let error = Program::parse(src).unwrap_err();
+            assert!(
+                matches!(error.kind, AsmErrorKind::AddressOverflow(e) if e == end),
+                "{src}: {:?}",
+                error.kind
+            );
+        }
+    }
+
+    #[test]
+    fn defines() {
+        let config = Config::default().define("BOARD", 2).define("DEBUG", 1);
+        let src = "
+.if BOARD == 2
+  addi a0, a0, BOARD + DEBUG
+.endif
+";
+        let program = Program::parse_with_config(src, config).unwrap();
+        let mut sections = program.generate().unwrap();
+        assert_eq!(sections.remove(0).1, [0x13, 0x05, 0x35, 0x00]);
+
+        for name in ["1x", "a b", "x=1", "", "a0"] {
+            let config = Config::default().define(name, 1);
+            let error = Program::parse_with_config("", config).unwrap_err();
+            let invalid = matches!(error.kind, AsmErrorKind::InvalidDefine(_));
+            // Register names are valid symbols, but can't be defined.
+            assert_eq!(invalid, name != "a0", "{name}");
+        }
+    }
+
+    #[test]
+    fn local_labels() {
+        let src = "
+1:
+  beq a0, a1, 1f
+  jal zero, 1b
+1:
+  jal zero, 1b
+2:
+  jal zero, 2b
+";
+        assert_eq!(
+            text(src),
+            [
+                0x63, 0x04, 0xb5, 0x00, 0x6f, 0xf0, 0xdf, 0xff, 0x6f, 0x00, 0x00, 0x00,
+                0x6f, 0x00, 0x00, 0x00,
+            ]
+        );
+
+        for src in ["  jal zero, 1f\n1:\n  jal zero, 2f", "  jal zero, 1b\n1:"] {
+            let program = Program::parse(src).unwrap();
+            let error = program.generate().unwrap_err();
+            assert!(matches!(error.kind, AsmErrorKind::UnknownLocal), "{src}");
+        }
+    }
+
+    #[test]
+    fn label_errors() {
+        let error = Program::parse("loop:\n  addi a0, a0, 1\nloop:").unwrap_err();
+        assert!(matches!(error.kind, AsmErrorKind::DuplicateSym));
+        assert_eq!(error.loc.unwrap().line, 3);
+        assert_eq!(error.notes.len(), 1);
+        assert_eq!(error.notes[0].1.line, 1);
+
+        for (src, kind) in [
+            ("a0:", AsmErrorKind::SymIsReg),
+            ("add:", AsmErrorKind::SymIsOpCode),
+        ] {
+            let error = Program::parse(src).unwrap_err();
+            assert_eq!(
+                std::mem::discriminant(&error.kind),
+                std::mem::discriminant(&kind),
+                "{src}"
+            );
+        }
+    }
+
+    #[test]
+    fn section_switching() {
+        let src = "
+  addi a0, a0, 1
+.data
+x:
+  .word 1
+.text
+y:
+  addi a0, a0, 2
+.section .rodata.str, \"a\"
+  .byte 3
+.section .noinit, \"aw\", @nobits
+  .zero 8
+.bss
+z:
+  .zero 4
+";
+        let program = Program::parse(src).unwrap();
+        let sections: Vec<_> = program
+            .sections()
+            .iter()
+            .map(|section| (section.name.as_str(), section.flags, section.size()))
+            .collect();
+        let noinit = SectionFlags {
+            alloc: true,
+            write: true,
+            exec: false,
+            nobits: true,
+        };
+        assert_eq!(
+            sections,
+            [
+                (".text", SectionFlags::TEXT, 8),
+                (".data", SectionFlags::DATA, 4),
+                (".rodata.str", SectionFlags::RODATA, 1),
+                (".noinit", noinit, 8),
+                (".bss", SectionFlags::BSS, 4),
+            ]
+        );
+
+        let value = |name| program.symbol(name).unwrap().value.unwrap();
+        assert_eq!(value("x"), Value::rel(1, 0));
+        assert_eq!(value("y"), Value::rel(0, 4));
+        assert_eq!(value("z"), Value::rel(4, 0));
+
+        let error = Program::parse(".bss\n  .word 1").unwrap_err();
+        assert!(matches!(error.kind, AsmErrorKind::NoBitsData));
+        let error = Program::parse(".section .x, \"q\"").unwrap_err();
+        assert!(matches!(error.kind, AsmErrorKind::InvalidSectionFlags));
+    }
+
+    #[test]
+    fn alignment() {
+        let src = "
+  addi a0, a0, 1
+  .balign 16
+  addi a0, a0, 2
+.data
+  .byte 1
+  .p2align 2
+  .byte 2
+  .balign 4, 0xff
+  .byte 3
+  .balign 8, 0, 2
+  .byte 4
+  .align 3
+  .byte 5
+";
+        let program = Program::parse(src).unwrap();
+        let sections = program.generate().unwrap();
+        assert_eq!(
+            sections[0].1,
+            [
+                0x13, 0x05, 0x15, 0x00, 0x13, 0x00, 0x00, 0x00, 0x13, 0x00, 0x00, 0x00,
+                0x13, 0x00, 0x00, 0x00, 0x13, 0x05, 0x25, 0x00,
+            ]
+        );
+        // The padding to 8 would be longer than the allowed 2 bytes.
+        assert_eq!(
+            sections[1].1,
+            [1, 0, 0, 0, 2, 0xff, 0xff, 0xff, 3, 4, 0, 0, 0, 0, 0, 0, 5]
+        );
+        assert_eq!(program.sections()[0].align, 16);
+
+        let config = Config {
+            rvc: true,
+            ..Default::default()
+        };
+        let program =
+            Program::parse_with_config("  .half 1\n  .balign 8", config).unwrap();
+        assert_eq!(
+            program.generate().unwrap()[0].1,
+            [0x01, 0x00, 0x01, 0x00, 0x13, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn include_files() {
+        let dir = std::env::temp_dir()
+            .join(format!("riscv_asm_include_{}", std::process::id()));
+        let inc = dir.join("inc");
+        fs::create_dir_all(&inc).unwrap();
+        fs::write(dir.join("a.s"), ".include \"b.s\"\n").unwrap();
+        fs::write(dir.join("b.s"), "  addi a0, a0, 1\n.include \"a.s\"\n").unwrap();
+        fs::write(inc.join("defs.s"), "X = 2\n").unwrap();
+        fs::write(inc.join("data.bin"), [1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        let config = Config {
+            include_dirs: vec![inc],
+            ..Default::default()
+        };
+
+        let main = dir.join("main.s");
+        let parse = |src: &str| {
+            fs::write(&main, src).unwrap();
+            Program::parse_file(&main, config.clone())
+        };
+
+        let program =
+            parse(".include \"defs.s\"\n  .byte X\n  .incbin \"data.bin\", 2, 3\n")
+                .unwrap();
+        let mut sections = program.generate().unwrap();
+        assert_eq!(sections.remove(0).1, [2, 3, 4, 5]);
+
+        let error = parse(".include \"a.s\"\n").unwrap_err();
+        assert!(matches!(error.kind, AsmErrorKind::IncludeCycle));
+        assert!(error.loc.unwrap().file.ends_with("b.s"));
+
+        for src in ["  .incbin \"data.bin\", 9", "  .incbin \"data.bin\", 6, 3"] {
+            let error = parse(src).unwrap_err();
+            assert!(matches!(error.kind, AsmErrorKind::ImmOutOfRange), "{src}");
+        }
+        let error = parse("  .incbin \"none.bin\"").unwrap_err();
+        assert!(matches!(error.kind, AsmErrorKind::FileNotFound(_)));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn macros() {
+        let src = r"
+.macro inc reg, by=1
+  addi \reg, \reg, \by
+.endm
+.macro words first, rest:vararg
+  .byte \first
+  .byte \rest
+.endm
+.macro here
+l\@:
+  .byte l\@ - 12
+.endm
+.macro once n
+  .byte \n
+  .if \n == 1
+  .exitm
+  .endif
+  .byte 0xff
+.endm
+  inc a0
+  inc a1, 2
+  inc by=3, reg=a2
+.data
+  words 1, 2, 3
+  here
+  here
+  once 1
+  once 2
+";
+        let program = Program::parse(src).unwrap();
+        let sections = program.generate().unwrap();
+        assert_eq!(
+            sections[0].1,
+            [0x13, 0x05, 0x15, 0x00, 0x93, 0x85, 0x25, 0x00, 0x13, 0x06, 0x36, 0x00]
+        );
+        assert_eq!(sections[1].1, [1, 2, 3, 3, 4, 1, 2, 0xff]);
+        assert!(program.symbol("l4").is_some() && program.symbol("l5").is_some());
+    }
+
+    #[test]
+    fn macro_errors() {
+        for (src, kind) in [
+            (
+                ".macro m a:req\n.endm\n  m",
+                AsmErrorKind::MissingMacroArg(String::new()),
+            ),
+            (
+                ".macro m a\n.endm\n  m 1, 2",
+                AsmErrorKind::TooManyMacroArgs,
+            ),
+            (
+                ".macro m\n.endm\n.macro m\n.endm",
+                AsmErrorKind::DuplicateMacro,
+            ),
+            (".macro m\n  m\n.endm\n  m", AsmErrorKind::MacroDepth),
+            (".macro m\n", AsmErrorKind::Unterminated("endm")),
+            (".endm", AsmErrorKind::Misplaced),
+        ] {
+            let error = Program::parse(src).unwrap_err();
+            assert_eq!(
+                std::mem::discriminant(&error.kind),
+                std::mem::discriminant(&kind),
+                "{src}"
+            );
+        }
+
+        // Errors in expansions point into the body, noting the invocation.
+        let error = Program::parse(".macro m\n  addi a0, a0\n.endm\n  m").unwrap_err();
+        assert_eq!(error.loc.unwrap().line, 2);
+        assert_eq!(error.notes.last().unwrap().1.line, 4);
+    }
+
+    #[test]
+    fn repetitions() {
+        let src = r"
+.rept 2
+  .byte 1
+  .irpc c, 23
+  .byte \c
+  .endr
+.endr
+.irp reg, a0, a1
+  addi \reg, \reg, 1
+.endr
+.rept 0
+  .byte 0xff
+.endr
+";
+        let program = Program::parse(src).unwrap();
+        assert_eq!(
+            program.generate().unwrap()[0].1,
+            [1, 2, 3, 1, 2, 3, 0x13, 0x05, 0x15, 0x00, 0x93, 0x85, 0x15, 0x00]
+        );
+
+        for (src, kind) in [
+            (".rept 2\n  .byte 1\n", AsmErrorKind::Unterminated("endr")),
+            (".endr", AsmErrorKind::Misplaced),
+            (".rept x\n.endr", AsmErrorKind::UnknownSym),
+        ] {
+            let error = Program::parse(src).unwrap_err();
+            assert_eq!(
+                std::mem::discriminant(&error.kind),
+                std::mem::discriminant(&kind),
+                "{src}"
+            );
+        }
+    }
+
+    #[test]
+    fn conditionals() {
+        let src = "
+X = 2
+.if X == 1
+  .byte 1
+.elseif X == 2
+  .byte 2
+  .ifdef Y
+  .byte 0xff
+  .else
+  .byte 3
+  .endif
+.elseif X == 2
+  .byte 0xff
+.else
+  .byte 0xff
+.endif
+.ifndef Y
+  .byte 4
+.endif
+.if 0
+  .if x x x
+  .bogus
+  .endif
+.endif
+";
+        let program = Program::parse(src).unwrap();
+        assert_eq!(program.generate().unwrap()[0].1, [2, 3, 4]);
+
+        for (src, kind) in [
+            (".else", AsmErrorKind::Misplaced),
+            (".elseif 1", AsmErrorKind::Misplaced),
+            (".endif", AsmErrorKind::Misplaced),
+            (".if 1\n.else\n.else\n.endif", AsmErrorKind::Misplaced),
+            (".if 1\n.else\n.elseif 1\n.endif", AsmErrorKind::Misplaced),
+            (".if 1\n", AsmErrorKind::Unterminated("endif")),
+            (".if later\n.endif\nlater:", AsmErrorKind::UnknownSym),
+        ] {
+            let error = Program::parse(src).unwrap_err();
+            assert_eq!(
+                std::mem::discriminant(&error.kind),
+                std::mem::discriminant(&kind),
+                "{src}"
+            );
+        }
+    }
+
+    #[test]
+    fn symbol_directives() {
+        let src = "
+.globl f
+.weak w
+.hidden f
+.type f, @function
+.type obj, \"object\"
+f:
+  jal ra, w
+.size f, . - f
+.data
+obj:
+  .word 1
+.size obj, 4
+";
+        let program = Program::parse(src).unwrap();
+        let f = program.symbol("f").unwrap();
+        assert_eq!(
+            (f.binding, f.kind, f.size, f.visibility),
+            (
+                Binding::Global,
+                SymbolKind::Func,
+                Some(4),
+                Visibility::Hidden
+            )
+        );
+        let obj = program.symbol("obj").unwrap();
+        assert_eq!(
+            (obj.binding, obj.kind, obj.size),
+            (Binding::Local, SymbolKind::Object, Some(4))
+        );
+        let w = program.symbol("w").unwrap();
+        assert_eq!((w.binding, w.is_defined()), (Binding::Weak, false));
+
+        for (src, kind) in [
+            (".size x, 4\nx:", AsmErrorKind::UnknownSym),
+            (".local u\n  jal ra, u", AsmErrorKind::UnknownSym),
+            (".type f, @bogus", AsmErrorKind::InvalidSymbolType),
+            (".globl a0", AsmErrorKind::SymIsReg),
+        ] {
+            let error = Program::parse(src).unwrap_err();
+            assert_eq!(
+                std::mem::discriminant(&error.kind),
+                std::mem::discriminant(&kind),
+                "{src}"
+            );
         }
     }
 
-    fn curr_addr(&self) -> u32 {
-        (self.code.len() as u32) << 2
+    #[test]
+    fn strings() {
+        let src = r#"
+  .ascii "ab", "c"
+  .string "d\n"
+  .asciz "\x65"
+"#;
+        assert_eq!(text(src), b"abcd\n\0e\0");
     }
 }
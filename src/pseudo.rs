@@ -9,7 +9,7 @@ use crate::{
 };
 
 impl Pseudo {
-    pub fn parse(input: Span<'_>) -> IResult<Self> {
+    pub fn parse(input: Span<'_>) -> IResult<'_, Self> {
         map_opt(alpha0, |s: Span<'_>| PSEUDO.get(*s).copied())(input).map_err(|e| {
             e.map(|e: AsmError<'_>| e.with_kind(AsmErrorKind::InvalidPseudo))
         })
@@ -47,5 +47,8 @@ macro_rules! pseudo {
 }
 
 pseudo! {
-    mv => addi
+    mv => addi,
+    j => jal,
+    call => auipc,
+    la => auipc
 }
@@ -25,11 +25,14 @@ impl Debug for Reg {
 }
 
 impl Reg {
+    pub const ZERO: Self = Self(0);
+    pub const RA: Self = Self(1);
+
     pub fn idx(&self) -> u32 {
         self.0
     }
 
-    pub fn parse(input: Span<'_>) -> IResult<Self> {
+    pub fn parse(input: Span<'_>) -> IResult<'_, Self> {
         map_opt(alphanumeric1, |s: Span<'_>| {
             REGS.get(*s).copied().map(Into::into)
         })(input)
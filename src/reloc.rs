@@ -0,0 +1,96 @@
+use crate::imm::Target;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocKind {
+    Abs32,
+    Abs64,
+    Branch,
+    Jal,
+    /// `auipc` + `jalr` pair of `call`.
+    Call,
+    CallPlt,
+    PcrelHi20,
+    PcrelLo12I,
+    PcrelLo12S,
+    Hi20,
+    Lo12I,
+    Lo12S,
+    /// 32-bit offset from the field, like the addresses of `.eh_frame`.
+    Pcrel32,
+    /// Marks the instruction of the previous relocation as relaxable.
+    Relax,
+    /// Alignment padding of code, of which the linker keeps what is needed
+    /// once it has relaxed the code before.
+    Align,
+    /// Adds the target to the field of the given size in bytes, paired with
+    /// [`RelocKind::Sub`] for a difference of locations in relaxed code.
+    Add(u8),
+    Sub(u8),
+    /// Sets the field, paired with [`RelocKind::Sub`], or [`RelocKind::Sub6`]
+    /// for the low 6 bits of a byte, like `DW_CFA_advance_loc`.
+    Set(u8),
+    Set6,
+    Sub6,
+}
+
+impl RelocKind {
+    /// `R_RISCV_*` number.
+    pub fn elf_type(&self) -> u32 {
+        match self {
+            Self::Abs32 => 1,
+            Self::Abs64 => 2,
+            Self::Branch => 16,
+            Self::Jal => 17,
+            Self::Call => 18,
+            Self::CallPlt => 19,
+            Self::PcrelHi20 => 23,
+            Self::PcrelLo12I => 24,
+            Self::PcrelLo12S => 25,
+            Self::Hi20 => 26,
+            Self::Lo12I => 27,
+            Self::Lo12S => 28,
+            Self::Align => 43,
+            Self::Relax => 51,
+            Self::Sub6 => 52,
+            Self::Set6 => 53,
+            Self::Pcrel32 => 57,
+            Self::Add(size) => 33 + size.trailing_zeros(),
+            Self::Sub(size) => 37 + size.trailing_zeros(),
+            Self::Set(size) => 54 + size.trailing_zeros(),
+        }
+    }
+
+    /// The linker may rewrite instructions with the relocation, if they
+    /// are marked with [`RelocKind::Relax`].
+    pub fn is_relaxable(&self) -> bool {
+        matches!(
+            self,
+            Self::Call
+                | Self::CallPlt
+                | Self::PcrelHi20
+                | Self::PcrelLo12I
+                | Self::PcrelLo12S
+                | Self::Hi20
+                | Self::Lo12I
+                | Self::Lo12S
+        )
+    }
+}
+
+/// How a field referring to an address is filled in an object file.
+#[derive(Debug)]
+pub enum Fixup {
+    /// Value is known, e.g. an offset within the section.
+    Resolved,
+    /// Field is zero, the linker fills it from the relocation.
+    Reloc(RelocKind, Target),
+    /// Field is zero, filled by the relocation of another instruction.
+    Covered,
+}
+
+#[derive(Debug, Clone)]
+pub struct Reloc {
+    pub offset: u32,
+    pub kind: RelocKind,
+    pub target: Target,
+}
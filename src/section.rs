@@ -0,0 +1,196 @@
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, take_while},
+    character::complete::{char, one_of, space0},
+    combinator::{map_res, opt, value},
+    sequence::{delimited, preceded},
+};
+
+use crate::{
+    data::Data,
+    error::{AsmError, AsmErrorKind, IResult},
+    instr::Instr,
+    span::Span,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SectionFlags {
+    pub alloc: bool,
+    pub write: bool,
+    pub exec: bool,
+    pub nobits: bool,
+}
+
+impl SectionFlags {
+    pub const TEXT: Self = Self {
+        alloc: true,
+        write: false,
+        exec: true,
+        nobits: false,
+    };
+    pub const DATA: Self = Self {
+        alloc: true,
+        write: true,
+        exec: false,
+        nobits: false,
+    };
+    pub const RODATA: Self = Self {
+        alloc: true,
+        write: false,
+        exec: false,
+        nobits: false,
+    };
+    pub const BSS: Self = Self {
+        alloc: true,
+        write: true,
+        exec: false,
+        nobits: true,
+    };
+
+    /// Default flags of a section, derived from its name the same way as
+    /// GNU as does (e.g. `.text.init` gets the flags of `.text`).
+    pub fn for_name(name: &str) -> Self {
+        let is = |prefix: &str| {
+            name == prefix
+                || name
+                    .strip_prefix(prefix)
+                    .is_some_and(|rest| rest.starts_with('.'))
+        };
+
+        if is(".text") {
+            Self::TEXT
+        } else if is(".data") || is(".sdata") {
+            Self::DATA
+        } else if is(".rodata") || is(".srodata") {
+            Self::RODATA
+        } else if is(".bss") || is(".sbss") {
+            Self::BSS
+        } else {
+            Self::default()
+        }
+    }
+
+    /// Parses the flags and optional type of the `.section` directive, e.g.
+    /// `"aw", @nobits`. Without explicit type `nobits` is taken from `name`.
+    pub fn parse<'i>(name: &str, input: Span<'i>) -> IResult<'i, Self> {
+        let (input, mut flags) = delimited(
+            char('"'),
+            map_res(take_while(|c| c != '"'), |s: Span<'_>| {
+                s.chars().try_fold(Self::default(), |mut flags, c| {
+                    match c {
+                        'a' => flags.alloc = true,
+                        'w' => flags.write = true,
+                        'x' => flags.exec = true,
+                        _ => return Err(()),
+                    }
+                    Ok(flags)
+                })
+            }),
+            char('"'),
+        )(input)
+        .map_err(|e| {
+            e.map(|e: AsmError<'_>| e.with_kind(AsmErrorKind::InvalidSectionFlags))
+        })?;
+
+        let (input, nobits) = opt(preceded(
+            delimited(space0, char(','), space0),
+            preceded(
+                one_of("@%"),
+                alt((value(false, tag("progbits")), value(true, tag("nobits")))),
+            ),
+        ))(input)?;
+        flags.nobits = nobits.unwrap_or(Self::for_name(name).nobits);
+
+        Ok((input, flags))
+    }
+}
+
+#[derive(Debug)]
+pub enum Item {
+    Instr(Instr),
+    Data(Data),
+}
+
+impl Item {
+    pub fn size(&self) -> u64 {
+        match self {
+            Self::Instr(_) => 4,
+            Self::Data(data) => data.size(),
+        }
+    }
+}
+
+impl From<Instr> for Item {
+    fn from(instr: Instr) -> Self {
+        Self::Instr(instr)
+    }
+}
+
+impl From<Data> for Item {
+    fn from(data: Data) -> Self {
+        Self::Data(data)
+    }
+}
+
+#[derive(Debug)]
+pub struct Entry {
+    pub offset: u32,
+    pub line: usize,
+    pub item: Item,
+}
+
+#[derive(Debug)]
+pub struct Section {
+    pub name: String,
+    pub flags: SectionFlags,
+    /// Start address, assigned once all sections are parsed.
+    pub addr: u32,
+    /// Alignment of the start address, the largest one requested by
+    /// alignment directives.
+    pub align: u32,
+    pub items: Vec<Entry>,
+    /// The linker may relax code of the section, moving its locations.
+    pub relaxed: bool,
+    size: u32,
+}
+
+impl Section {
+    pub fn new(name: String, flags: SectionFlags) -> Self {
+        Self {
+            name,
+            flags,
+            addr: 0,
+            align: if flags.exec { 4 } else { 1 },
+            items: Default::default(),
+            relaxed: false,
+            size: 0,
+        }
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Appends an item, which must end within the address space.
+    pub fn push(&mut self, line: usize, item: Item) -> Result<(), AsmErrorKind> {
+        let offset = self.size;
+        let end = offset as u64 + item.size();
+        self.size = u32::try_from(end).map_err(|_| AsmErrorKind::AddressOverflow(end))?;
+        self.items.push(Entry { offset, line, item });
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_for_name() {
+        assert_eq!(SectionFlags::for_name(".text.init"), SectionFlags::TEXT);
+        assert_eq!(SectionFlags::for_name(".sdata"), SectionFlags::DATA);
+        assert_eq!(SectionFlags::for_name(".bss.stack"), SectionFlags::BSS);
+        assert_eq!(SectionFlags::for_name(".texts"), SectionFlags::default());
+    }
+}
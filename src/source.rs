@@ -0,0 +1,99 @@
+use std::{path::PathBuf, rc::Rc};
+
+use crate::{error::Location, span::Offset};
+
+#[derive(Debug)]
+pub struct Source {
+    pub name: String,
+    pub path: Option<PathBuf>,
+    pub text: Rc<str>,
+    /// Number of the first line, for text taken from another source like
+    /// a macro body.
+    pub first_line: usize,
+    /// Line which brought the source in and how.
+    pub parent: Option<(Origin, usize)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    Include,
+    Macro,
+    Repeat,
+}
+
+impl Origin {
+    pub fn note(&self) -> &'static str {
+        match self {
+            Self::Include => "Included",
+            Self::Macro => "Expanded",
+            Self::Repeat => "Repeated",
+        }
+    }
+}
+
+impl Source {
+    pub fn new(name: String, text: &str) -> Self {
+        Self {
+            name,
+            path: None,
+            text: text.into(),
+            first_line: 1,
+            parent: None,
+        }
+    }
+
+    pub fn file(path: PathBuf, text: &str, parent: Option<usize>) -> Self {
+        Self {
+            name: path.display().to_string(),
+            path: Some(path),
+            text: text.into(),
+            first_line: 1,
+            parent: parent.map(|line| (Origin::Include, line)),
+        }
+    }
+
+    /// Text generated from lines of another source, e.g. an expanded macro.
+    pub fn expansion(
+        name: String,
+        first_line: usize,
+        text: &str,
+        origin: Origin,
+        parent: usize,
+    ) -> Self {
+        Self {
+            name,
+            path: None,
+            text: text.into(),
+            first_line,
+            parent: Some((origin, parent)),
+        }
+    }
+
+    pub fn location(&self, offset: usize) -> Location {
+        let before = &self.text[..offset];
+        let line_begin = before.rfind('\n').map(|pos| pos + 1).unwrap_or(0);
+
+        Location {
+            file: self.name.clone(),
+            line: self.first_line + before.matches('\n').count(),
+            col: before[line_begin..].chars().count() + 1,
+            text: self.text[line_begin..]
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .trim_end()
+                .to_string(),
+        }
+    }
+}
+
+/// Line of a source. Lines are numbered in the order the assembler
+/// processes them, so lines of an included file follow the `.include`.
+#[derive(Debug, Clone, Copy)]
+pub struct SourceLine {
+    pub src: usize,
+    pub offset: Offset,
+    /// Number of the line in its file, lines of expansions are numbered as
+    /// in the body they come from.
+    pub number: usize,
+}
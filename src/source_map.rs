@@ -0,0 +1,146 @@
+use std::ops::Range;
+
+use crate::program::Program;
+
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    /// Entries in the order of addresses.
+    entries: Vec<MapEntry>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MapEntry {
+    pub addrs: Range<u32>,
+    pub span: SourceSpan,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceSpan {
+    /// Index into [`Program::lines`], leading to the lines which included
+    /// or expanded it.
+    pub line: usize,
+    pub file: String,
+    /// Line number and column (both 1-based) of the statement, lines of
+    /// expansions refer to the macro or repeat body.
+    pub number: usize,
+    pub col: usize,
+    /// Length of the statement in bytes.
+    pub len: usize,
+}
+
+impl SourceMap {
+    pub fn entries(&self) -> &[MapEntry] {
+        &self.entries
+    }
+
+    pub fn lookup(&self, addr: u32) -> Option<&MapEntry> {
+        let idx = self
+            .entries
+            .partition_point(|entry| entry.addrs.end <= addr);
+
+        self.entries
+            .get(idx)
+            .filter(|entry| entry.addrs.contains(&addr))
+    }
+
+    /// Address ranges emitted by a line of a file, adjacent ranges merged.
+    pub fn addrs(&self, file: &str, number: usize) -> Vec<Range<u32>> {
+        let mut ranges: Vec<Range<u32>> = Vec::new();
+
+        for entry in &self.entries {
+            if entry.span.number != number || entry.span.file != file {
+                continue;
+            }
+
+            match ranges.last_mut() {
+                Some(range) if range.end == entry.addrs.start => {
+                    range.end = entry.addrs.end
+                }
+                _ => ranges.push(entry.addrs.clone()),
+            }
+        }
+
+        ranges
+    }
+}
+
+impl Program {
+    pub fn source_map(&self) -> SourceMap {
+        let mut entries: Vec<MapEntry> = self
+            .sections()
+            .iter()
+            .filter(|section| section.flags.alloc)
+            .flat_map(|section| {
+                section
+                    .items
+                    .iter()
+                    .filter(|entry| entry.item.size() > 0)
+                    .map(|entry| {
+                        let start = section.addr + entry.offset;
+                        MapEntry {
+                            addrs: start..start + entry.item.size() as u32,
+                            span: self.span(entry.line),
+                        }
+                    })
+            })
+            .collect();
+        entries.sort_by_key(|entry| entry.addrs.start);
+
+        SourceMap { entries }
+    }
+
+    /// Span of the statement on the line, without indentation.
+    pub(crate) fn span(&self, id: usize) -> SourceSpan {
+        let line = &self.lines()[id];
+        let text = self.text(id, &line.offset);
+        let stmt = text.trim();
+        let indent = &text[..text.len() - text.trim_start().len()];
+
+        SourceSpan {
+            line: id,
+            file: self.sources()[line.src].name.clone(),
+            number: line.number,
+            col: indent.chars().count() + 1,
+            len: stmt.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup() {
+        let src = "\
+.macro two
+  addi a0, a0, 1
+  addi a0, a0, 2
+.endm
+_start:
+  call f
+  two
+  two
+f:
+.data
+  .word 1
+";
+        let map = Program::parse(src).unwrap().source_map();
+
+        let span = &map.lookup(4).unwrap().span;
+        assert_eq!((span.number, span.col, span.len), (6, 3, 6));
+        assert_eq!(map.lookup(4).unwrap().addrs, 4..8);
+        // Expanded lines refer to the body of the macro.
+        assert_eq!(map.lookup(8).unwrap().span.number, 2);
+        assert_eq!(map.lookup(12).unwrap().span.number, 3);
+        assert_eq!(map.lookup(24).unwrap().span.number, 11);
+        assert!(map.lookup(28).is_none());
+
+        // The instructions of `call` are merged.
+        assert_eq!(map.addrs("<input>", 6).len(), 1);
+        assert_eq!(map.addrs("<input>", 6)[0], 0..8);
+        assert_eq!(map.addrs("<input>", 2), [8..12, 16..20]);
+        assert!(map.addrs("<input>", 7).is_empty());
+        assert!(map.addrs("other.s", 6).is_empty());
+    }
+}
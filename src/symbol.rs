@@ -0,0 +1,136 @@
+use nom::{
+    branch::alt,
+    bytes::complete::tag,
+    character::complete::{char, one_of},
+    combinator::value,
+    sequence::{delimited, preceded},
+};
+
+use crate::{
+    error::{AsmError, AsmErrorKind, IResult},
+    imm::Value,
+    span::{Offset, Span},
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol {
+    /// `None` for symbols which are only declared, e.g. by `.globl`.
+    pub value: Option<Value>,
+    /// Line of the definition (or of the first declaration) and the name's
+    /// offset in it.
+    pub line: usize,
+    pub offset: Offset,
+    pub binding: Binding,
+    pub kind: SymbolKind,
+    pub size: Option<u64>,
+    pub visibility: Visibility,
+}
+
+impl Symbol {
+    pub fn new(line: usize, offset: Offset) -> Self {
+        Self {
+            value: None,
+            line,
+            offset,
+            binding: Default::default(),
+            kind: Default::default(),
+            size: None,
+            visibility: Default::default(),
+        }
+    }
+
+    /// Index of the section the symbol belongs to, `None` for absolute and
+    /// undefined ones.
+    pub fn section(&self) -> Option<usize> {
+        self.value.and_then(|value| value.section)
+    }
+
+    pub fn is_defined(&self) -> bool {
+        self.value.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Binding {
+    #[default]
+    Local,
+    Global,
+    Weak,
+}
+
+impl Binding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Local => "local",
+            Self::Global => "global",
+            Self::Weak => "weak",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SymbolKind {
+    #[default]
+    NoType,
+    Func,
+    Object,
+}
+
+impl SymbolKind {
+    /// Parses the type of the `.type` directive: `@function`, `%object`,
+    /// `"function"` or `STT_FUNC`.
+    pub fn parse(input: Span<'_>) -> IResult<'_, Self> {
+        let kind = || {
+            alt((
+                value(Self::Func, tag("function")),
+                value(Self::Object, tag("object")),
+                value(Self::NoType, tag("notype")),
+            ))
+        };
+
+        alt((
+            preceded(one_of("@%"), kind()),
+            delimited(char('"'), kind(), char('"')),
+            preceded(
+                tag("STT_"),
+                alt((
+                    value(Self::Func, tag("FUNC")),
+                    value(Self::Object, tag("OBJECT")),
+                    value(Self::NoType, tag("NOTYPE")),
+                )),
+            ),
+        ))(input)
+        .map_err(|e| {
+            e.map(|e: AsmError<'_>| e.with_kind(AsmErrorKind::InvalidSymbolType))
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Visibility {
+    #[default]
+    Default,
+    Internal,
+    Hidden,
+    Protected,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_kind() {
+        for (src, kind) in [
+            ("@function", SymbolKind::Func),
+            ("%object", SymbolKind::Object),
+            ("\"notype\"", SymbolKind::NoType),
+            ("STT_FUNC", SymbolKind::Func),
+        ] {
+            let (rest, parsed) = SymbolKind::parse(Span::new(src, true)).unwrap();
+            assert!(rest.is_empty(), "{src}");
+            assert_eq!(parsed, kind, "{src}");
+        }
+        assert!(SymbolKind::parse(Span::new("function", true)).is_err());
+    }
+}